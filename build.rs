@@ -0,0 +1,116 @@
+//! 构建脚本
+//!
+//! - 把 `user/programs/` 下的每个用户程序编译成静态链接的 ELF, 输出到 `$OUT_DIR/user/`,
+//!   并生成程序表 `$OUT_DIR/user_programs.rs`, 由 `process::programs` 以 `include_bytes!` 嵌入内核
+
+use std::env;
+use std::fmt::Write as _;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+fn main() {
+    println!("cargo:rerun-if-changed=build.rs");
+    build_user_programs();
+}
+
+/// 用户程序是单文件的 `no_std` crate, 以宿主的 x86_64 目标编译(只用到 `core`),
+/// 再由工具链自带的 `rust-lld` 按 `user/link.ld` 静态链接到用户地址空间.
+/// 程序可以通过 `rt::` 使用 `user/rt/` 中的运行时, 运行时先编译成 rlib
+fn build_user_programs() {
+    let user_dir = Path::new("user");
+    let programs_dir = user_dir.join("programs");
+    let runtime = user_dir.join("rt/lib.rs");
+    let link_script = user_dir.join("link.ld");
+    println!("cargo:rerun-if-changed={}", programs_dir.display());
+    println!("cargo:rerun-if-changed={}", runtime.display());
+    println!("cargo:rerun-if-changed={}", link_script.display());
+
+    let out_dir = PathBuf::from(env::var_os("OUT_DIR").unwrap()).join("user");
+    fs::create_dir_all(&out_dir).expect("failed to create user program directory");
+
+    let rustc = env::var_os("RUSTC").unwrap_or_else(|| "rustc".into());
+    let host = env::var("HOST").unwrap();
+    let sysroot = Command::new(&rustc)
+        .args(["--print", "sysroot"])
+        .output()
+        .expect("failed to run rustc");
+    let sysroot = String::from_utf8(sysroot.stdout).unwrap();
+    let linker = Path::new(sysroot.trim())
+        .join("lib/rustlib")
+        .join(&host)
+        .join("bin/rust-lld");
+
+    let status = Command::new(&rustc)
+        .env_remove("CARGO_ENCODED_RUSTFLAGS")
+        .args([
+            "--edition",
+            "2021",
+            "--crate-type",
+            "rlib",
+            "--crate-name",
+            "rt",
+        ])
+        .args(["--target", &host])
+        .args(["-C", "panic=abort", "-C", "opt-level=2"])
+        .arg("--out-dir")
+        .arg(&out_dir)
+        .arg(&runtime)
+        .status()
+        .expect("failed to run rustc");
+    assert!(status.success(), "failed to build the user runtime");
+    let runtime_rlib = out_dir.join("librt.rlib");
+
+    let mut sources: Vec<PathBuf> = fs::read_dir(&programs_dir)
+        .expect("failed to read user/programs")
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "rs"))
+        .collect();
+    sources.sort();
+
+    let mut table = String::from("pub static USER_PROGRAMS: &[(&str, &[u8])] = &[\n");
+    for source in sources {
+        println!("cargo:rerun-if-changed={}", source.display());
+        let name = source.file_stem().unwrap().to_str().unwrap();
+        let status = Command::new(&rustc)
+            // 不继承内核构建的编译参数
+            .env_remove("CARGO_ENCODED_RUSTFLAGS")
+            .args([
+                "--edition",
+                "2021",
+                "--crate-type",
+                "bin",
+                "--crate-name",
+                name,
+            ])
+            .args(["--target", &host])
+            .args(["-C", "panic=abort", "-C", "opt-level=2"])
+            .args(["-C", "strip=debuginfo"])
+            .arg("--extern")
+            .arg(format!("rt={}", runtime_rlib.display()))
+            .arg("-C")
+            .arg(format!("linker={}", linker.display()))
+            .args(["-C", "linker-flavor=ld.lld"])
+            .arg("-C")
+            .arg(format!("link-arg=-T{}", link_script.display()))
+            // 以位置无关的方式编译, 由链接器静态地解析到固定地址
+            .args(["-C", "link-arg=-no-pie", "-C", "link-arg=-static"])
+            .arg("-o")
+            .arg(out_dir.join(name))
+            .arg(&source)
+            .status()
+            .expect("failed to run rustc");
+        assert!(status.success(), "failed to build user program {}", name);
+        writeln!(
+            table,
+            "    ({:?}, include_bytes!({:?})),",
+            name,
+            out_dir.join(name).display().to_string()
+        )
+        .unwrap();
+    }
+    table.push_str("];\n");
+
+    let table_path = out_dir.parent().unwrap().join("user_programs.rs");
+    fs::write(table_path, table).expect("failed to write user program table");
+}
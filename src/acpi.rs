@@ -0,0 +1,127 @@
+//! ACPI 表解析
+//!
+//! - 只解析启动其他 CPU 需要的部分: 从 RSDP 找到 RSDT/XSDT, 再从中找到 MADT
+//! - MADT 列出了每个 CPU 的 local APIC ID 和 local APIC 寄存器的物理地址
+//! - 所有表都通过物理内存映射读取, 不需要额外的映射
+
+use crate::memory;
+use alloc::vec::Vec;
+use x86_64::PhysAddr;
+
+/// RSDP 所在的 BIOS 只读区域
+const BIOS_AREA_START: u64 = 0xE_0000;
+const BIOS_AREA_END: u64 = 0x10_0000;
+/// BIOS 数据区中保存 EBDA 段地址的位置
+const EBDA_SEGMENT_PTR: u64 = 0x40E;
+/// 只搜索 EBDA 的前 1 KiB
+const EBDA_SEARCH_LEN: u64 = 1024;
+
+const RSDP_SIGNATURE: &[u8; 8] = b"RSD PTR ";
+const MADT_SIGNATURE: &[u8; 4] = b"APIC";
+
+/// 所有系统描述表共有的表头长度
+const SDT_HEADER_LEN: u64 = 36;
+
+/// MADT 条目类型
+const MADT_LOCAL_APIC: u8 = 0;
+const MADT_LOCAL_APIC_ADDRESS_OVERRIDE: u8 = 5;
+/// local APIC 条目的标志位: 已启用. 只设置了 Online Capable 的条目是可以热插拔但目前不存在的 CPU
+const LOCAL_APIC_ENABLED: u32 = 1 << 0;
+
+/// 从 MADT 中得到的处理器信息
+#[derive(Debug, Clone)]
+pub struct MadtInfo {
+    /// local APIC 寄存器的物理地址
+    pub local_apic_address: PhysAddr,
+    /// 所有可用 CPU 的 local APIC ID, 按 MADT 中的顺序排列
+    pub apic_ids: Vec<u8>,
+}
+
+/// 查找并解析 MADT, 找不到或校验失败时返回 `None`
+pub fn madt() -> Option<MadtInfo> {
+    let rsdp = find_rsdp()?;
+    let madt = find_table(rsdp, MADT_SIGNATURE)?;
+    Some(parse_madt(madt))
+}
+
+fn read<T: Copy>(addr: PhysAddr) -> T {
+    unsafe { memory::phys_to_virt(addr).as_ptr::<T>().read_unaligned() }
+}
+
+fn checksum_ok(addr: PhysAddr, len: u64) -> bool {
+    (0..len).fold(0u8, |sum, i| sum.wrapping_add(read::<u8>(addr + i))) == 0
+}
+
+/// 在 EBDA 和 BIOS 区域中按 16 字节对齐搜索 RSDP
+fn find_rsdp() -> Option<PhysAddr> {
+    let ebda = u64::from(read::<u16>(PhysAddr::new(EBDA_SEGMENT_PTR))) << 4;
+    let ebda_range = (ebda..ebda + EBDA_SEARCH_LEN).step_by(16);
+    let bios_range = (BIOS_AREA_START..BIOS_AREA_END).step_by(16);
+
+    ebda_range
+        .filter(|_| ebda != 0)
+        .chain(bios_range)
+        .map(PhysAddr::new)
+        .find(|&addr| read::<[u8; 8]>(addr) == *RSDP_SIGNATURE && checksum_ok(addr, 20))
+}
+
+/// 在 RSDT(ACPI 1.0) 或 XSDT(ACPI 2.0+) 中查找签名为 `signature` 的表
+fn find_table(rsdp: PhysAddr, signature: &[u8; 4]) -> Option<PhysAddr> {
+    let revision = read::<u8>(rsdp + 15u64);
+    let (root, entry_size) = if revision >= 2 {
+        (PhysAddr::new(read::<u64>(rsdp + 24u64)), 8)
+    } else {
+        (PhysAddr::new(u64::from(read::<u32>(rsdp + 16u64))), 4)
+    };
+    let len = u64::from(read::<u32>(root + 4u64));
+    if !checksum_ok(root, len) {
+        return None;
+    }
+
+    (SDT_HEADER_LEN..len)
+        .step_by(entry_size)
+        .map(|offset| match entry_size {
+            8 => PhysAddr::new(read::<u64>(root + offset)),
+            _ => PhysAddr::new(u64::from(read::<u32>(root + offset))),
+        })
+        .find(|&table| {
+            read::<[u8; 4]>(table) == *signature
+                && checksum_ok(table, u64::from(read::<u32>(table + 4u64)))
+        })
+}
+
+fn parse_madt(madt: PhysAddr) -> MadtInfo {
+    let len = u64::from(read::<u32>(madt + 4u64));
+    let mut local_apic_address = PhysAddr::new(u64::from(read::<u32>(madt + SDT_HEADER_LEN)));
+    let mut apic_ids = Vec::new();
+
+    // 表头之后是 local APIC 地址(4 字节)和标志(4 字节), 然后是变长的条目
+    let mut offset = SDT_HEADER_LEN + 8;
+    while offset + 2 <= len {
+        let entry = madt + offset;
+        let entry_type = read::<u8>(entry);
+        let entry_len = u64::from(read::<u8>(entry + 1u64));
+        if entry_len < 2 {
+            break;
+        }
+        match entry_type {
+            MADT_LOCAL_APIC => {
+                let apic_id = read::<u8>(entry + 3u64);
+                let flags = read::<u32>(entry + 4u64);
+                if flags & LOCAL_APIC_ENABLED != 0 {
+                    apic_ids.push(apic_id);
+                }
+            }
+            MADT_LOCAL_APIC_ADDRESS_OVERRIDE => {
+                local_apic_address = PhysAddr::new(read::<u64>(entry + 4u64));
+            }
+            _ => {}
+        }
+        offset += entry_len;
+    }
+
+    MadtInfo {
+        local_apic_address,
+        apic_ids,
+    }
+}
@@ -0,0 +1,266 @@
+//! 用户地址空间
+//!
+//! - 每个地址空间有自己的 4 级页表. 内核部分的 4 级页表项从内核页表复制, 下级页表共享,
+//!   所以内核映射在所有地址空间中都相同, 切换页表后内核代码可以照常运行
+//! - 用户程序只能使用 [`USER_START`]..[`USER_END`], 这段范围对应的 4 级页表项在内核页表中必须为空
+//! - 复制之后内核页表中新增的 4 级页表项不会出现在已有的地址空间中; 内核的堆, 栈和 MMIO 区域
+//!   都在初始化时建立, 不受影响
+//! - [`AddressSpace::unmap`] 把页面所在的帧还给帧分配器; 丢弃地址空间时释放用户范围内的
+//!   所有页面和页表, 以及 4 级页表本身. 丢弃时它不能是任何 CPU 正在使用的页表
+
+use crate::memory;
+use core::ops::Range;
+use x86_64::registers::control::{Cr3, Cr3Flags};
+use x86_64::structures::paging::mapper::{MapToError, TranslateResult};
+use x86_64::structures::paging::page_table::PageTableEntry;
+use x86_64::structures::paging::{
+    FrameAllocator, FrameDeallocator, Mapper, OffsetPageTable, Page, PageTable, PageTableFlags,
+    PhysFrame, Size4KiB, Translate,
+};
+use x86_64::VirtAddr;
+
+/// 用户地址范围 `[USER_START, USER_END)`, 对应 4 级页表项 16..128
+pub const USER_START: u64 = 0x0000_0800_0000_0000;
+pub const USER_END: u64 = 0x0000_4000_0000_0000;
+
+/// 用户地址范围对应的 4 级页表下标
+const USER_L4_ENTRIES: Range<usize> = 16..128;
+
+/// 读写用户内存时遇到的未映射地址
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NotMapped(pub VirtAddr);
+
+/// `[start, start + len)` 是否完全位于用户地址范围内
+pub fn is_user_range(start: u64, len: u64) -> bool {
+    start >= USER_START && start.checked_add(len).is_some_and(|end| end <= USER_END)
+}
+
+pub struct AddressSpace {
+    page_table: PhysFrame,
+}
+
+impl AddressSpace {
+    /// 创建只包含内核映射的地址空间
+    pub fn new() -> Result<Self, MapToError<Size4KiB>> {
+        let frame =
+            memory::with_kernel_memory(|_, frame_allocator| frame_allocator.allocate_frame())
+                .ok_or(MapToError::FrameAllocationFailed)?;
+        let kernel = unsafe { table_at(memory::kernel_page_table()) };
+        let table = unsafe { table_at(frame) };
+        table.zero();
+        for (index, entry) in kernel.iter().enumerate() {
+            if USER_L4_ENTRIES.contains(&index) {
+                assert!(entry.is_unused(), "kernel mapping in user address range");
+            } else {
+                table[index] = entry.clone();
+            }
+        }
+        Ok(AddressSpace { page_table: frame })
+    }
+
+    /// 4 级页表所在的帧, 即切换到这个地址空间时写入 CR3 的值
+    pub fn page_table(&self) -> PhysFrame {
+        self.page_table
+    }
+
+    fn mapper(&self) -> OffsetPageTable<'_> {
+        let offset = memory::phys_to_virt(x86_64::PhysAddr::new(0));
+        unsafe { OffsetPageTable::new(table_at(self.page_table), offset) }
+    }
+
+    /// 为 `[start, start + len)` 覆盖的所有页分配清零的帧, 以 `flags` 映射为用户可访问
+    ///
+    /// - 已经映射的页保留原来的内容, 权限取两者的并集; ELF 中相邻的段可能共用同一页
+    pub fn map(
+        &mut self,
+        start: VirtAddr,
+        len: u64,
+        flags: PageTableFlags,
+    ) -> Result<(), MapToError<Size4KiB>> {
+        assert!(
+            is_user_range(start.as_u64(), len),
+            "mapping outside the user address range"
+        );
+        if len == 0 {
+            return Ok(());
+        }
+        let flags = flags | PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+        // 中间各级页表总是可写且用户可访问, 权限由最后一级页表项决定
+        let table_flags =
+            PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE;
+        let pages = Page::<Size4KiB>::range_inclusive(
+            Page::containing_address(start),
+            Page::containing_address(start + (len - 1)),
+        );
+
+        let mut mapper = self.mapper();
+        memory::with_kernel_memory(|_, frame_allocator| {
+            for page in pages {
+                if let TranslateResult::Mapped { flags: old, .. } =
+                    mapper.translate(page.start_address())
+                {
+                    let mut merged = old | flags;
+                    if !(old & flags).contains(PageTableFlags::NO_EXECUTE) {
+                        merged.remove(PageTableFlags::NO_EXECUTE);
+                    }
+                    unsafe {
+                        mapper
+                            .update_flags(page, merged)
+                            .expect("page vanished")
+                            .flush()
+                    };
+                    continue;
+                }
+                let frame = frame_allocator
+                    .allocate_frame()
+                    .ok_or(MapToError::FrameAllocationFailed)?;
+                unsafe {
+                    memory::phys_to_virt(frame.start_address())
+                        .as_mut_ptr::<u8>()
+                        .write_bytes(0, Page::<Size4KiB>::SIZE as usize);
+                    mapper
+                        .map_to_with_table_flags(page, frame, flags, table_flags, frame_allocator)?
+                        .ignore();
+                }
+            }
+            Ok(())
+        })
+    }
+
+    /// 解除 `[start, start + len)` 覆盖的所有页的映射并释放它们的帧, 跳过没有映射的页
+    ///
+    /// - 用户页面的帧都由 [`map`](Self::map) 分配, 只属于这个地址空间
+    pub fn unmap(&mut self, start: VirtAddr, len: u64) {
+        assert!(
+            is_user_range(start.as_u64(), len),
+            "unmapping outside the user address range"
+        );
+        if len == 0 {
+            return;
+        }
+        let pages = Page::<Size4KiB>::range_inclusive(
+            Page::containing_address(start),
+            Page::containing_address(start + (len - 1)),
+        );
+        let mut mapper = self.mapper();
+        memory::with_kernel_memory(|_, frame_allocator| {
+            for page in pages {
+                // 只有当前地址空间的 TLB 项需要刷新, 刷新其他地址空间的页没有副作用
+                if let Ok((frame, flush)) = mapper.unmap(page) {
+                    flush.flush();
+                    unsafe { frame_allocator.deallocate_frame(frame) };
+                }
+            }
+        });
+    }
+
+    /// `[start, start + len)` 覆盖的页是否都没有映射
+    pub fn is_unmapped(&self, start: VirtAddr, len: u64) -> bool {
+        let mapper = self.mapper();
+        let mut page = start.align_down(Page::<Size4KiB>::SIZE);
+        while page < start + len {
+            if !matches!(mapper.translate(page), TranslateResult::NotMapped) {
+                return false;
+            }
+            page += Page::<Size4KiB>::SIZE;
+        }
+        true
+    }
+
+    /// 返回 `addr` 所在页的页表项标志, 未映射时返回 `None`
+    pub fn page_flags(&self, addr: VirtAddr) -> Option<PageTableFlags> {
+        match self.mapper().translate(addr) {
+            TranslateResult::Mapped { flags, .. } => Some(flags),
+            _ => None,
+        }
+    }
+
+    /// 通过物理内存映射把 `data` 写到这个地址空间的 `addr` 处, 不需要切换页表, 也不检查写权限
+    pub fn write(&mut self, addr: VirtAddr, data: &[u8]) -> Result<(), NotMapped> {
+        self.for_each_chunk(addr, data.len(), |ptr, range| unsafe {
+            core::ptr::copy_nonoverlapping(data[range.clone()].as_ptr(), ptr, range.len());
+        })
+    }
+
+    /// 从这个地址空间的 `addr` 处读取 `buf.len()` 字节
+    pub fn read(&self, addr: VirtAddr, buf: &mut [u8]) -> Result<(), NotMapped> {
+        self.for_each_chunk(addr, buf.len(), |ptr, range| unsafe {
+            core::ptr::copy_nonoverlapping(ptr, buf[range.clone()].as_mut_ptr(), range.len());
+        })
+    }
+
+    /// 把 `[addr, addr + len)` 按页拆开, 对每一段调用 `f(物理内存映射中的地址, 在整段中的下标范围)`
+    fn for_each_chunk(
+        &self,
+        addr: VirtAddr,
+        len: usize,
+        mut f: impl FnMut(*mut u8, Range<usize>),
+    ) -> Result<(), NotMapped> {
+        let mapper = self.mapper();
+        let mut done = 0;
+        while done < len {
+            let current = addr + done as u64;
+            let phys = mapper.translate_addr(current).ok_or(NotMapped(current))?;
+            let page_left = Page::<Size4KiB>::SIZE - u64::from(current.page_offset());
+            let chunk = (len - done).min(page_left as usize);
+            f(memory::phys_to_virt(phys).as_mut_ptr(), done..done + chunk);
+            done += chunk;
+        }
+        Ok(())
+    }
+
+    /// 切换到这个地址空间
+    ///
+    /// # Safety
+    /// 调用者必须保证之后仍在使用的用户地址都属于这个地址空间
+    pub unsafe fn activate(&self) {
+        let (current, _) = Cr3::read();
+        if current != self.page_table {
+            Cr3::write(self.page_table, Cr3Flags::empty());
+        }
+    }
+}
+
+/// 释放用户范围内的页面和各级页表, 内核部分的页表与内核页表共享, 不释放
+///
+/// - 执行进程的线程结束并切换离开之后才能丢弃它的地址空间, 见 [`process`](crate::process)
+impl Drop for AddressSpace {
+    fn drop(&mut self) {
+        debug_assert_ne!(
+            Cr3::read().0,
+            self.page_table,
+            "dropping the active address space"
+        );
+        let table = unsafe { table_at(self.page_table) };
+        memory::with_kernel_memory(|_, frame_allocator| unsafe {
+            for index in USER_L4_ENTRIES {
+                free_entry(&table[index], 3, frame_allocator);
+            }
+            frame_allocator.deallocate_frame(self.page_table);
+        });
+    }
+}
+
+/// 释放页表项 `entry` 指向的 `level` 级页表及其下的所有页面, `level` 为 0 时指向的是页面
+///
+/// - 用户范围内只有 [`AddressSpace::map`] 建立的 4 KiB 页, 没有大页
+unsafe fn free_entry(
+    entry: &PageTableEntry,
+    level: u8,
+    frame_allocator: &mut impl FrameDeallocator<Size4KiB>,
+) {
+    let Ok(frame) = entry.frame() else {
+        return;
+    };
+    if level > 0 {
+        for child in table_at(frame).iter() {
+            free_entry(child, level - 1, frame_allocator);
+        }
+    }
+    frame_allocator.deallocate_frame(frame);
+}
+
+/// 通过物理内存映射访问位于 `frame` 的页表
+unsafe fn table_at(frame: PhysFrame) -> &'static mut PageTable {
+    &mut *memory::phys_to_virt(frame.start_address()).as_mut_ptr()
+}
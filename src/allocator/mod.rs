@@ -1,5 +1,6 @@
 #![allow(unused_imports)]
 
+use crate::sync::{IrqSafeMutex, IrqSafeMutexGuard};
 use bump::BumpAllocator;
 use dummy::Dummy;
 use fixed_size_block::FixedSizeBlockAllocator;
@@ -52,18 +53,19 @@ pub fn init_heap(
     Ok(())
 }
 
+/// 分配器的锁, 中断处理中也可能分配内存, 所以持锁期间关中断
 pub struct Locked<A> {
-    inner: spin::Mutex<A>,
+    inner: IrqSafeMutex<A>,
 }
 
 impl<A> Locked<A> {
     pub const fn new(inner: A) -> Self {
         Locked {
-            inner: spin::Mutex::new(inner),
+            inner: IrqSafeMutex::new(inner),
         }
     }
 
-    pub fn lock(&self) -> spin::MutexGuard<A> {
+    pub fn lock(&self) -> IrqSafeMutexGuard<A> {
         self.inner.lock()
     }
 }
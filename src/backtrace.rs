@@ -0,0 +1,241 @@
+//! 基于帧指针的栈回溯
+//!
+//! 目标描述文件开启了 `"frame-pointer": "always"`, 每个函数的序言都是 `push rbp; mov rbp, rsp`,
+//! 所以 `[rbp]` 是调用者的 rbp, `[rbp + 8]` 是返回地址, 沿着这条链就能回溯调用栈.
+//!
+//! - 只在帧指针所在的栈(当前 CPU 的 IST 栈, 或 [`PerCpu::stack`](crate::percpu::PerCpu::stack)
+//!   记录的当前执行流的栈)内回溯, 损坏的帧指针不会让回溯读到栈外的内存; 栈未知时只打印异常地址
+//! - 返回地址通过链接时嵌入的符号表解析为函数名. 内核由 `tools/kernel-ld.sh` 链接,
+//!   它在第一次链接后用 `llvm-nm` 取出代码段符号, 作为 `_binary_ksyms_start` 到
+//!   `_binary_ksyms_end` 之间的文本重新链接进内核, 每行是 `起始地址 大小 名字`
+
+use crate::memory::StackBounds;
+use crate::{gdt, percpu};
+use core::arch::asm;
+use core::fmt;
+use core::ptr::addr_of;
+use x86_64::VirtAddr;
+
+extern "C" {
+    static _binary_ksyms_start: u8;
+    static _binary_ksyms_end: u8;
+    /// 链接器定义的代码段末尾
+    static etext: u8;
+}
+
+/// 最多回溯的栈帧数
+const MAX_FRAMES: usize = 32;
+
+/// 一条待回溯的调用链, 通过 [`fmt::Display`] 打印
+#[derive(Debug, Clone, Copy)]
+pub struct Backtrace {
+    /// 发生异常时的指令地址, 作为第一帧打印
+    rip: Option<u64>,
+    rbp: u64,
+    /// `rbp` 所在的栈
+    stack: Option<StackBounds>,
+}
+
+impl Backtrace {
+    /// 从当前函数的帧指针开始回溯
+    #[inline(always)]
+    pub fn capture() -> Self {
+        let rbp: u64;
+        unsafe {
+            asm!("mov {}, rbp", out(reg) rbp, options(nomem, nostack, preserves_flags));
+        }
+        Backtrace {
+            rip: None,
+            rbp,
+            stack: stack_containing(rbp),
+        }
+    }
+
+    /// 从被打断的现场开始回溯, 用于异常处理
+    pub fn from_frame(rip: u64, rbp: u64) -> Self {
+        Backtrace {
+            rip: Some(rip),
+            rbp,
+            stack: stack_containing(rbp),
+        }
+    }
+
+    /// 沿帧指针链返回各级返回地址
+    pub fn return_addresses(&self) -> ReturnAddresses {
+        ReturnAddresses {
+            rbp: self.rbp,
+            stack: self.stack,
+            depth: 0,
+        }
+    }
+}
+
+/// 找到 `rbp` 所在的栈: 当前 CPU 的某个 IST 栈, 或者当前执行流的栈
+fn stack_containing(rbp: u64) -> Option<StackBounds> {
+    let addr = VirtAddr::try_new(rbp).ok()?;
+    let percpu = percpu::try_current()?;
+    (0..)
+        .map_while(gdt::ist_stack)
+        .chain(percpu.stack())
+        .find(|stack| stack.contains(addr))
+}
+
+impl fmt::Display for Backtrace {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "stack backtrace:")?;
+        let mut index = 0;
+        if let Some(rip) = self.rip {
+            write!(f, "\n{:>4}: ", index)?;
+            write_symbol(f, rip, rip)?;
+            index += 1;
+        }
+        for addr in self.return_addresses() {
+            write!(f, "\n{:>4}: ", index)?;
+            // 返回地址指向 call 的下一条指令, 减一才落在调用者函数内部
+            write_symbol(f, addr, addr - 1)?;
+            index += 1;
+        }
+        Ok(())
+    }
+}
+
+fn write_symbol(f: &mut fmt::Formatter, addr: u64, lookup: u64) -> fmt::Result {
+    match resolve(lookup) {
+        Some((name, start)) => write!(f, "{:#018x} - {}+{:#x}", addr, name, addr - start),
+        None => write!(f, "{:#018x} - <unknown>", addr),
+    }
+}
+
+/// 返回地址迭代器
+pub struct ReturnAddresses {
+    rbp: u64,
+    stack: Option<StackBounds>,
+    depth: usize,
+}
+
+impl Iterator for ReturnAddresses {
+    type Item = u64;
+
+    fn next(&mut self) -> Option<u64> {
+        let stack = self.stack?;
+        let rbp = self.rbp;
+        // 整个帧(调用者的 rbp 和返回地址)都要在栈内, 且按 8 字节对齐
+        if self.depth >= MAX_FRAMES
+            || rbp % 8 != 0
+            || rbp < stack.start().as_u64()
+            || rbp.saturating_add(16) > stack.end().as_u64()
+        {
+            return None;
+        }
+
+        let (caller_rbp, return_address) = unsafe {
+            let frame = rbp as *const u64;
+            (frame.read_volatile(), frame.add(1).read_volatile())
+        };
+        if return_address == 0 {
+            return None;
+        }
+
+        // 调用者的栈帧一定在更高的地址上, 否则说明帧链已到尽头或被破坏
+        self.rbp = if caller_rbp > rbp { caller_rbp } else { 0 };
+        self.depth += 1;
+        Some(return_address)
+    }
+}
+
+/// 查找包含 `addr` 的函数, 返回函数名和起始地址
+///
+/// - 地址超出函数的大小, 或者在代码段之外时返回 `None`
+pub fn resolve(addr: u64) -> Option<(&'static str, u64)> {
+    let text_end = addr_of!(etext) as u64;
+    lookup(symbol_table(), text_end, addr)
+}
+
+/// 嵌入内核的符号表文本
+fn symbol_table() -> &'static str {
+    let bytes = unsafe {
+        let start = addr_of!(_binary_ksyms_start);
+        let end = addr_of!(_binary_ksyms_end);
+        core::slice::from_raw_parts(start, end as usize - start as usize)
+    };
+    core::str::from_utf8(bytes).unwrap_or("")
+}
+
+/// 在按地址升序排列的符号表 `table` 中查找 `addr`
+fn lookup(table: &str, text_end: u64, addr: u64) -> Option<(&str, u64)> {
+    let mut found = None;
+    let mut next_start = text_end;
+    for (start, size, name) in table.lines().filter_map(parse_symbol) {
+        if start > addr {
+            next_start = start;
+            break;
+        }
+        found = Some((start, size, name));
+    }
+    let (start, size, name) = found?;
+    // 汇编中定义的符号没有大小, 认为它延伸到下一个符号
+    let end = if size > 0 { start + size } else { next_start };
+    (addr < end.min(text_end)).then_some((name, start))
+}
+
+/// 解析一行 `起始地址 大小 名字`, 地址和大小都是十六进制
+fn parse_symbol(line: &str) -> Option<(u64, u64, &str)> {
+    let (start, rest) = line.split_once(' ')?;
+    let (size, name) = rest.split_once(' ')?;
+    let start = u64::from_str_radix(start, 16).ok()?;
+    let size = u64::from_str_radix(size, 16).ok()?;
+    Some((start, size, name))
+}
+
+#[test_case]
+fn test_lookup_respects_symbol_size() {
+    let table = "1000 20 first\n1040 0 label\n1080 10 second\n";
+    assert_eq!(lookup(table, 0x2000, 0xfff), None);
+    assert_eq!(lookup(table, 0x2000, 0x1010), Some(("first", 0x1000)));
+    // 两个函数之间的填充不属于任何函数
+    assert_eq!(lookup(table, 0x2000, 0x1030), None);
+    // 没有大小的符号延伸到下一个符号
+    assert_eq!(lookup(table, 0x2000, 0x107f), Some(("label", 0x1040)));
+    assert_eq!(lookup(table, 0x2000, 0x1088), Some(("second", 0x1080)));
+    assert_eq!(lookup(table, 0x2000, 0x1090), None);
+    // 不超过代码段末尾
+    assert_eq!(lookup(table, 0x1060, 0x1070), None);
+}
+
+#[test_case]
+fn test_resolve_kernel_function() {
+    let addr = resolve as usize as u64;
+    let (name, start) = resolve(addr + 1).expect("resolve is not in the symbol table");
+    assert!(name.ends_with("backtrace::resolve"), "{}", name);
+    assert_eq!(start, addr);
+    assert_eq!(resolve(0), None);
+}
+
+#[test_case]
+fn test_walk_stays_on_stack() {
+    // 三个帧, 最外层的帧指向栈外
+    let mut frames = [0u64; 8];
+    let base = frames.as_ptr() as u64;
+    frames[0] = base + 16;
+    frames[1] = 0x1111;
+    frames[2] = base + 48;
+    frames[3] = 0x2222;
+    frames[6] = base + 0x1000;
+    frames[7] = 0x3333;
+    let stack = StackBounds::new(VirtAddr::new(base), VirtAddr::new(base + 64));
+    let walk = |rbp| ReturnAddresses {
+        rbp,
+        stack: Some(stack),
+        depth: 0,
+    };
+
+    let addresses: alloc::vec::Vec<u64> = walk(base).collect();
+    assert_eq!(addresses, [0x1111, 0x2222, 0x3333]);
+    // 未对齐或不在栈内的帧指针不会被解引用
+    assert_eq!(walk(base + 4).next(), None);
+    assert_eq!(walk(base + 56).next(), None);
+    assert_eq!(walk(base - 16).next(), None);
+    // 指向更低地址的帧链就此结束
+    frames[2] = base;
+    assert_eq!(walk(base).count(), 2);
+}
@@ -0,0 +1,223 @@
+//! CPU 识别和 SIMD 启用
+//!
+//! - [`info`] 返回 BSP 上用 CPUID 读出的厂商, 型号和特性, 只检测一次; 各 CPU 的特性假定相同
+//! - [`init`] 启用 SSE (CR0/CR4.OSFXSR), 支持 XSAVE 时再启用 CR4.OSXSAVE 并在 XCR0 中打开
+//!   x87/SSE/AVX 状态, 每个 CPU 都要调用
+//! - 内核自身以软浮点编译, 不使用 SIMD 寄存器; 启用它们是为了用户程序, 线程切换时由
+//!   `thread::fpu` 懒保存
+
+use core::arch::x86_64::{CpuidResult, __cpuid, __cpuid_count};
+use lazy_static::lazy_static;
+use x86_64::registers::control::{Cr0, Cr0Flags, Cr4, Cr4Flags};
+use x86_64::registers::xcontrol::{XCr0, XCr0Flags};
+
+/// 与 SIMD 和系统编程相关的 CPU 特性
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Features {
+    pub fpu: bool,
+    pub tsc: bool,
+    pub apic: bool,
+    pub x2apic: bool,
+    pub fxsr: bool,
+    pub sse: bool,
+    pub sse2: bool,
+    pub sse3: bool,
+    pub ssse3: bool,
+    pub sse4_1: bool,
+    pub sse4_2: bool,
+    pub xsave: bool,
+    pub avx: bool,
+    pub avx2: bool,
+    pub rdrand: bool,
+    pub rdseed: bool,
+    /// 页表项的 NO_EXECUTE 位
+    pub nx: bool,
+    /// 1 GiB 大页
+    pub page_1gb: bool,
+    pub rdtscp: bool,
+    /// TSC 以固定频率计数, 不受变频和休眠影响
+    pub invariant_tsc: bool,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct CpuInfo {
+    vendor: [u8; 12],
+    brand: [u8; 48],
+    pub family: u32,
+    pub model: u32,
+    pub stepping: u32,
+    /// 最大的基本/扩展 CPUID 功能号
+    pub max_leaf: u32,
+    pub max_extended_leaf: u32,
+    pub features: Features,
+    /// CPU 支持在 XCR0 中启用的状态组件, 不支持 XSAVE 时为空
+    pub xsave_components: XCr0Flags,
+}
+
+impl CpuInfo {
+    fn detect() -> Self {
+        let leaf0 = cpuid(0);
+        let max_leaf = leaf0.eax;
+        let mut vendor = [0; 12];
+        vendor[0..4].copy_from_slice(&leaf0.ebx.to_le_bytes());
+        vendor[4..8].copy_from_slice(&leaf0.edx.to_le_bytes());
+        vendor[8..12].copy_from_slice(&leaf0.ecx.to_le_bytes());
+
+        let leaf1 = cpuid(1);
+        let bit = |reg: u32, n: u32| reg & (1 << n) != 0;
+        let stepping = leaf1.eax & 0xf;
+        let mut model = (leaf1.eax >> 4) & 0xf;
+        let mut family = (leaf1.eax >> 8) & 0xf;
+        if family == 0xf {
+            family += (leaf1.eax >> 20) & 0xff;
+        }
+        if family == 0x6 || family >= 0xf {
+            model += ((leaf1.eax >> 16) & 0xf) << 4;
+        }
+
+        let mut features = Features {
+            fpu: bit(leaf1.edx, 0),
+            tsc: bit(leaf1.edx, 4),
+            apic: bit(leaf1.edx, 9),
+            fxsr: bit(leaf1.edx, 24),
+            sse: bit(leaf1.edx, 25),
+            sse2: bit(leaf1.edx, 26),
+            sse3: bit(leaf1.ecx, 0),
+            ssse3: bit(leaf1.ecx, 9),
+            sse4_1: bit(leaf1.ecx, 19),
+            sse4_2: bit(leaf1.ecx, 20),
+            x2apic: bit(leaf1.ecx, 21),
+            xsave: bit(leaf1.ecx, 26),
+            avx: bit(leaf1.ecx, 28),
+            rdrand: bit(leaf1.ecx, 30),
+            ..Features::default()
+        };
+        if max_leaf >= 7 {
+            let leaf7 = cpuid_count(7, 0);
+            features.avx2 = bit(leaf7.ebx, 5);
+            features.rdseed = bit(leaf7.ebx, 18);
+        }
+
+        let mut xsave_components = XCr0Flags::empty();
+        if features.xsave && max_leaf >= 0xd {
+            let leaf_d = cpuid_count(0xd, 0);
+            let bits = u64::from(leaf_d.eax) | u64::from(leaf_d.edx) << 32;
+            xsave_components = XCr0Flags::from_bits_truncate(bits);
+        }
+
+        let max_extended_leaf = cpuid(0x8000_0000).eax;
+        if max_extended_leaf >= 0x8000_0001 {
+            let ext1 = cpuid(0x8000_0001);
+            features.nx = bit(ext1.edx, 20);
+            features.page_1gb = bit(ext1.edx, 26);
+            features.rdtscp = bit(ext1.edx, 27);
+        }
+        let mut brand = [0; 48];
+        if max_extended_leaf >= 0x8000_0004 {
+            for (i, leaf) in (0x8000_0002..=0x8000_0004).enumerate() {
+                let r = cpuid(leaf);
+                for (j, reg) in [r.eax, r.ebx, r.ecx, r.edx].into_iter().enumerate() {
+                    let offset = i * 16 + j * 4;
+                    brand[offset..offset + 4].copy_from_slice(&reg.to_le_bytes());
+                }
+            }
+        }
+        if max_extended_leaf >= 0x8000_0007 {
+            features.invariant_tsc = bit(cpuid(0x8000_0007).edx, 8);
+        }
+
+        CpuInfo {
+            vendor,
+            brand,
+            family,
+            model,
+            stepping,
+            max_leaf,
+            max_extended_leaf,
+            features,
+            xsave_components,
+        }
+    }
+
+    /// 厂商字符串, 例如 `GenuineIntel`, `AuthenticAMD`
+    pub fn vendor(&self) -> &str {
+        core::str::from_utf8(&self.vendor).unwrap_or("")
+    }
+
+    /// 处理器型号字符串, CPU 不提供时为空
+    pub fn brand(&self) -> &str {
+        let len = self.brand.iter().position(|&b| b == 0).unwrap_or(48);
+        core::str::from_utf8(&self.brand[..len])
+            .unwrap_or("")
+            .trim()
+    }
+}
+
+fn cpuid(leaf: u32) -> CpuidResult {
+    unsafe { __cpuid(leaf) }
+}
+
+fn cpuid_count(leaf: u32, sub_leaf: u32) -> CpuidResult {
+    unsafe { __cpuid_count(leaf, sub_leaf) }
+}
+
+lazy_static! {
+    static ref CPU_INFO: CpuInfo = CpuInfo::detect();
+}
+
+/// CPU 的识别信息和特性
+pub fn info() -> &'static CpuInfo {
+    &CPU_INFO
+}
+
+/// 启用当前 CPU 的 SSE, 以及 CPU 支持时的 XSAVE 和 AVX
+pub fn init() {
+    let info = info();
+    let features = info.features;
+    assert!(
+        features.fxsr && features.sse2,
+        "CPU does not support FXSAVE and SSE2"
+    );
+
+    unsafe {
+        Cr0::update(|flags| {
+            flags.remove(Cr0Flags::EMULATE_COPROCESSOR);
+            flags.insert(Cr0Flags::MONITOR_COPROCESSOR);
+        });
+        Cr4::update(|flags| flags.insert(Cr4Flags::OSFXSR | Cr4Flags::OSXMMEXCPT_ENABLE));
+
+        if features.xsave {
+            Cr4::update(|flags| flags.insert(Cr4Flags::OSXSAVE));
+            let mut components = XCr0Flags::X87 | XCr0Flags::SSE;
+            if features.avx && info.xsave_components.contains(XCr0Flags::AVX) {
+                components |= XCr0Flags::AVX;
+            }
+            XCr0::write(components);
+        }
+    }
+}
+
+/// 当前 CPU 是否已启用 XSAVE
+pub fn xsave_enabled() -> bool {
+    Cr4::read().contains(Cr4Flags::OSXSAVE)
+}
+
+/// 按当前 XCR0 启用的组件, `XSAVE` 保存区需要的字节数; 只能在启用 XSAVE 之后调用
+pub fn xsave_area_size() -> usize {
+    assert!(xsave_enabled(), "XSAVE is not enabled");
+    cpuid_count(0xd, 0).ebx as usize
+}
+
+#[test_case]
+fn test_cpu_info() {
+    let info = info();
+    assert!(!info.vendor().is_empty());
+    // x86_64 要求 SSE2, 引导程序已经依赖 NX
+    assert!(info.features.sse2 && info.features.fxsr);
+    assert!(info.features.nx);
+    assert!(Cr4::read().contains(Cr4Flags::OSFXSR));
+    assert_eq!(xsave_enabled(), info.features.xsave);
+    if xsave_enabled() {
+        assert!(XCr0::read().contains(XCr0Flags::X87 | XCr0Flags::SSE));
+    }
+}
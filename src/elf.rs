@@ -0,0 +1,175 @@
+//! ELF64 可执行文件解析
+//!
+//! - 只支持 x86_64 小端的静态链接可执行文件(`ET_EXEC`), 不处理动态链接和重定位
+//! - [`Elf::parse`] 检查文件头和程序头表, 保证之后读取的程序头和段内容都在文件范围内
+
+/// 段类型: 需要加载到内存中的段
+pub const PT_LOAD: u32 = 1;
+
+/// 段权限
+pub const PF_X: u32 = 1 << 0;
+pub const PF_W: u32 = 1 << 1;
+pub const PF_R: u32 = 1 << 2;
+
+const ELF_MAGIC: [u8; 4] = [0x7f, b'E', b'L', b'F'];
+const ELFCLASS64: u8 = 2;
+const ELFDATA2LSB: u8 = 1;
+const EV_CURRENT: u8 = 1;
+const ET_EXEC: u16 = 2;
+const EM_X86_64: u16 = 62;
+
+const ELF_HEADER_SIZE: usize = 64;
+const PROGRAM_HEADER_SIZE: usize = 56;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ElfError {
+    /// 文件比文件头还短
+    Truncated,
+    /// 不是 ELF 文件
+    BadMagic,
+    /// 不是 64 位小端的 ELF
+    UnsupportedFormat,
+    /// 不是静态链接的可执行文件
+    NotExecutable,
+    /// 不是 x86_64 程序
+    UnsupportedMachine,
+    /// 程序头表的大小或位置不正确
+    BadProgramHeaders,
+    /// 段的文件内容超出文件范围, 或文件大小大于内存大小
+    BadSegment,
+}
+
+/// 一个程序头
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ProgramHeader {
+    pub kind: u32,
+    pub flags: u32,
+    pub offset: u64,
+    pub vaddr: u64,
+    pub file_size: u64,
+    pub mem_size: u64,
+    pub align: u64,
+}
+
+impl ProgramHeader {
+    pub fn is_load(&self) -> bool {
+        self.kind == PT_LOAD
+    }
+
+    pub fn is_writable(&self) -> bool {
+        self.flags & PF_W != 0
+    }
+
+    pub fn is_executable(&self) -> bool {
+        self.flags & PF_X != 0
+    }
+
+    /// 段在内存中的地址范围 `[vaddr, vaddr + mem_size)`, 溢出时返回 `None`
+    pub fn mem_range(&self) -> Option<core::ops::Range<u64>> {
+        Some(self.vaddr..self.vaddr.checked_add(self.mem_size)?)
+    }
+}
+
+/// 校验过的 ELF 文件
+#[derive(Debug, Clone, Copy)]
+pub struct Elf<'a> {
+    data: &'a [u8],
+    entry: u64,
+    ph_offset: usize,
+    ph_count: usize,
+}
+
+fn read_u16(data: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes(data[offset..offset + 2].try_into().unwrap())
+}
+
+fn read_u32(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+}
+
+fn read_u64(data: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(data[offset..offset + 8].try_into().unwrap())
+}
+
+impl<'a> Elf<'a> {
+    pub fn parse(data: &'a [u8]) -> Result<Self, ElfError> {
+        if data.len() < ELF_HEADER_SIZE {
+            return Err(ElfError::Truncated);
+        }
+        if data[0..4] != ELF_MAGIC {
+            return Err(ElfError::BadMagic);
+        }
+        if data[4] != ELFCLASS64 || data[5] != ELFDATA2LSB || data[6] != EV_CURRENT {
+            return Err(ElfError::UnsupportedFormat);
+        }
+        if read_u16(data, 16) != ET_EXEC {
+            return Err(ElfError::NotExecutable);
+        }
+        if read_u16(data, 18) != EM_X86_64 {
+            return Err(ElfError::UnsupportedMachine);
+        }
+
+        let ph_offset =
+            usize::try_from(read_u64(data, 32)).map_err(|_| ElfError::BadProgramHeaders)?;
+        let ph_entry_size = usize::from(read_u16(data, 54));
+        let ph_count = usize::from(read_u16(data, 56));
+        let ph_end = ph_count
+            .checked_mul(PROGRAM_HEADER_SIZE)
+            .and_then(|size| size.checked_add(ph_offset));
+        if ph_entry_size != PROGRAM_HEADER_SIZE || ph_end.map_or(true, |end| end > data.len()) {
+            return Err(ElfError::BadProgramHeaders);
+        }
+
+        let elf = Elf {
+            data,
+            entry: read_u64(data, 24),
+            ph_offset,
+            ph_count,
+        };
+        for header in elf.program_headers() {
+            let file_end = header.offset.checked_add(header.file_size);
+            if file_end.map_or(true, |end| end > data.len() as u64)
+                || header.file_size > header.mem_size
+            {
+                return Err(ElfError::BadSegment);
+            }
+        }
+        Ok(elf)
+    }
+
+    /// 程序入口地址
+    pub fn entry(&self) -> u64 {
+        self.entry
+    }
+
+    /// 程序头表在文件中的偏移
+    pub fn program_header_offset(&self) -> u64 {
+        self.ph_offset as u64
+    }
+
+    pub fn program_header_count(&self) -> usize {
+        self.ph_count
+    }
+
+    pub fn program_headers(&self) -> impl Iterator<Item = ProgramHeader> + 'a {
+        let (data, ph_offset) = (self.data, self.ph_offset);
+        (0..self.ph_count).map(move |i| {
+            let base = ph_offset + i * PROGRAM_HEADER_SIZE;
+            ProgramHeader {
+                kind: read_u32(data, base),
+                flags: read_u32(data, base + 4),
+                offset: read_u64(data, base + 8),
+                vaddr: read_u64(data, base + 16),
+                file_size: read_u64(data, base + 32),
+                mem_size: read_u64(data, base + 40),
+                align: read_u64(data, base + 48),
+            }
+        })
+    }
+
+    /// 段在文件中的内容, 长度为 `file_size`
+    pub fn segment_data(&self, header: &ProgramHeader) -> &'a [u8] {
+        let start = header.offset as usize;
+        &self.data[start..start + header.file_size as usize]
+    }
+}
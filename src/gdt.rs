@@ -1,53 +1,134 @@
-use lazy_static::lazy_static;
+use crate::memory::{self, StackBounds};
+use crate::percpu;
+use crate::smp::{self, MAX_CPUS};
+use conquer_once::spin::OnceCell;
+use core::cell::UnsafeCell;
+use core::sync::atomic::Ordering;
 use x86_64::structures::gdt::{Descriptor, GlobalDescriptorTable, SegmentSelector};
 use x86_64::structures::tss::TaskStateSegment;
 use x86_64::VirtAddr;
 
 pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
+pub const NMI_IST_INDEX: u16 = 1;
+pub const MACHINE_CHECK_IST_INDEX: u16 = 2;
 
-lazy_static! {
-    static ref TSS: TaskStateSegment = {
-        let mut tss = TaskStateSegment::new();
-        tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = {
-            const STACK_SIZE: usize = 4096 * 5;
-            static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
+/// 每个 IST 栈的页数
+const IST_STACK_PAGES: u64 = 5;
 
-            #[allow(warnings)]
-            let stack_start = VirtAddr::from_ptr(unsafe { &STACK });
+/// 每个 CPU 各自的 TSS
+///
+/// - TSS 中的 IST 栈只能由一个 CPU 使用, 而且加载 TSS 时 CPU 会把它的描述符标记为忙,
+///   同一个 TSS 不能被两个 CPU 加载, 所以每个 CPU 都需要自己的 TSS 和包含它的 GDT
+static TSS: [OnceCell<Tss>; MAX_CPUS] = [const { OnceCell::uninit() }; MAX_CPUS];
 
-            stack_start + STACK_SIZE
-        };
-        tss
-    };
+/// 加载后仍需修改 `privilege_stack_table[0]` 的 TSS
+///
+/// - 只有所属的 CPU 在关中断时修改, CPU 只在从用户态陷入内核时读取
+struct Tss(UnsafeCell<TaskStateSegment>);
+
+unsafe impl Sync for Tss {}
+
+static CPU_TABLES: [OnceCell<CpuTables>; MAX_CPUS] = [const { OnceCell::uninit() }; MAX_CPUS];
+
+struct CpuTables {
+    gdt: GlobalDescriptorTable,
+    selectors: Selectors,
+    /// 中断栈表(IST)使用的栈, 按 IST 下标排列
+    ///
+    /// - 这些异常可能发生在内核栈已经不可用的时候(如栈溢出), 所以各自使用独立的栈
+    /// - 栈从页分配器中分配, 下方有不映射的保护页, IST 栈本身溢出时会触发异常而不是覆盖相邻内存
+    /// - page fault 不使用 IST, 这样它可以嵌套发生而不会覆盖正在使用的栈
+    ist_stacks: [StackBounds; 3],
 }
 
-struct Selectors {
-    code_selector: SegmentSelector,
-    tss_selector: SegmentSelector,
+/// 各个 CPU 的 GDT 布局相同, 依次是:
+/// 内核代码段, 内核数据段, 用户数据段, 用户代码段, TSS
+///
+/// - `SYSCALL` 从 `STAR` 中取内核代码段, 并把下一项作为栈段
+/// - `SYSRET` 以 `STAR` 中的基址加 8 为用户栈段, 加 16 为用户代码段,
+///   所以用户数据段必须紧挨在用户代码段之前
+#[derive(Debug, Clone, Copy)]
+pub struct Selectors {
+    pub kernel_code: SegmentSelector,
+    pub kernel_data: SegmentSelector,
+    pub user_data: SegmentSelector,
+    pub user_code: SegmentSelector,
+    pub tss: SegmentSelector,
 }
 
-lazy_static! {
-    static ref GDT: (GlobalDescriptorTable, Selectors) = {
-        let mut gdt = GlobalDescriptorTable::new();
-        let code_selector = gdt.add_entry(Descriptor::kernel_code_segment());
-        let tss_selector = gdt.add_entry(Descriptor::tss_segment(&TSS));
-        (
-            gdt,
-            Selectors {
-                code_selector,
-                tss_selector,
-            },
-        )
+fn alloc_ist_stack() -> StackBounds {
+    memory::alloc_kernel_stack(IST_STACK_PAGES).expect("failed to allocate IST stack")
+}
+
+fn new_cpu_tables(cpu: usize) -> CpuTables {
+    let ist_stacks = [alloc_ist_stack(), alloc_ist_stack(), alloc_ist_stack()];
+    let tss = TSS[cpu].get_or_init(|| {
+        let mut tss = TaskStateSegment::new();
+        for (index, stack) in ist_stacks.iter().enumerate() {
+            tss.interrupt_stack_table[index] = stack.end();
+        }
+        Tss(UnsafeCell::new(tss))
+    });
+
+    let mut gdt = GlobalDescriptorTable::new();
+    let selectors = Selectors {
+        kernel_code: gdt.add_entry(Descriptor::kernel_code_segment()),
+        kernel_data: gdt.add_entry(Descriptor::kernel_data_segment()),
+        user_data: gdt.add_entry(Descriptor::user_data_segment()),
+        user_code: gdt.add_entry(Descriptor::user_code_segment()),
+        tss: gdt.add_entry(Descriptor::tss_segment(unsafe { &*tss.0.get() })),
     };
+    CpuTables {
+        gdt,
+        selectors,
+        ist_stacks,
+    }
+}
+
+/// 返回当前 CPU 下标为 `index` 的 IST 栈的地址范围
+pub fn ist_stack(index: u16) -> Option<StackBounds> {
+    let tables = CPU_TABLES[smp::current_cpu()].get()?;
+    tables.ist_stacks.get(usize::from(index)).copied()
+}
+
+/// 返回当前 CPU 的段选择子
+pub fn selectors() -> Selectors {
+    CPU_TABLES[smp::current_cpu()]
+        .get()
+        .expect("gdt::init not called")
+        .selectors
 }
 
+/// 设置当前 CPU 从用户态陷入内核时使用的栈
+///
+/// - 中断和异常由 CPU 从 TSS 的 `privilege_stack_table[0]` 取栈, `SYSCALL` 入口从 CPU 私有数据中取栈,
+///   两处同时更新
+/// - 每个线程有自己的内核栈, 切换线程时调用
+pub fn set_kernel_stack(top: VirtAddr) {
+    let tss = TSS[smp::current_cpu()].get().expect("gdt::init not called");
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        unsafe { (*tss.0.get()).privilege_stack_table[0] = top };
+        percpu!(kernel_stack).store(top.as_u64(), Ordering::Relaxed);
+    });
+}
+
+/// 在 BSP 上加载GDT和TSS
+///
+/// - IST 栈从页分配器中分配, 所以必须在 [`memory::init_kernel_memory`] 之后调用
 pub fn init() {
-    use x86_64::instructions::segmentation::{Segment, CS};
+    init_cpu(0);
+}
+
+/// 为编号为 `cpu` 的 CPU 创建并加载它自己的 GDT 和 TSS, 由该 CPU 自己调用
+pub fn init_cpu(cpu: usize) {
+    use x86_64::instructions::segmentation::{Segment, CS, SS};
     use x86_64::instructions::tables::load_tss;
 
-    GDT.0.load();
+    let tables = CPU_TABLES[cpu].get_or_init(|| new_cpu_tables(cpu));
+    tables.gdt.load();
     unsafe {
-        CS::set_reg(GDT.1.code_selector);
-        load_tss(GDT.1.tss_selector);
+        CS::set_reg(tables.selectors.kernel_code);
+        SS::set_reg(tables.selectors.kernel_data);
+        load_tss(tables.selectors.tss);
     }
 }
@@ -0,0 +1,118 @@
+//! 中断的延迟处理(下半部)
+//!
+//! - 中断处理函数只做最少的工作(如读出设备寄存器), 然后通过 [`defer`] 把剩下的工作放进队列
+//! - 每条 IRQ 线一个无锁队列, 入队不加锁也不分配内存, 可以在中断上下文中调用
+//! - 队列由 [`run_pending`] 在开中断的普通上下文中清空, 目前由执行器在每轮轮询前调用,
+//!   以及空闲线程调用, 所以延迟的工作可以加锁, 分配内存, 打印输出
+//! - 空闲的执行器线程登记在 [`WAITER`] 中, 入队时把它唤醒
+
+use super::irq::IRQ_LINES;
+use crate::thread::Waiter;
+use alloc::vec::Vec;
+use conquer_once::spin::OnceCell;
+use core::sync::atomic::{AtomicU64, Ordering};
+use crossbeam_queue::ArrayQueue;
+
+/// 每条 IRQ 线最多积压的工作项个数
+const QUEUE_CAPACITY: usize = 32;
+
+static QUEUES: OnceCell<Vec<ArrayQueue<Work>>> = OnceCell::uninit();
+
+/// 有新的工作项时唤醒的线程
+pub static WAITER: Waiter = Waiter::new();
+
+/// 因为队列已满或未初始化而丢弃的工作项个数
+static DROPPED: AtomicU64 = AtomicU64::new(0);
+
+/// 一个延迟执行的工作项: 函数指针加一个参数, 可以直接拷贝, 入队时不需要分配内存
+#[derive(Debug, Clone, Copy)]
+pub struct Work {
+    func: fn(usize),
+    arg: usize,
+}
+
+impl Work {
+    pub const fn new(func: fn(usize), arg: usize) -> Self {
+        Work { func, arg }
+    }
+
+    fn run(self) {
+        (self.func)(self.arg)
+    }
+}
+
+/// 分配各条 IRQ 线的队列, 需要在堆初始化之后调用
+pub(super) fn init() {
+    QUEUES
+        .try_init_once(|| {
+            (0..IRQ_LINES)
+                .map(|_| ArrayQueue::new(QUEUE_CAPACITY))
+                .collect()
+        })
+        .expect("deferred::init should only be called once");
+}
+
+/// 把工作项放进 IRQ 线 `line` 的队列, 可以在中断上下文中调用
+///
+/// - 队列已满时丢弃工作项并计数, 返回 `false`
+pub fn defer(line: u8, work: Work) -> bool {
+    let queued = QUEUES
+        .try_get()
+        .ok()
+        .and_then(|queues| queues.get(usize::from(line)))
+        .is_some_and(|queue| queue.push(work).is_ok());
+    if queued {
+        WAITER.wake();
+    } else {
+        DROPPED.fetch_add(1, Ordering::Relaxed);
+    }
+    queued
+}
+
+/// 是否有尚未执行的工作项
+pub fn has_pending() -> bool {
+    QUEUES
+        .try_get()
+        .is_ok_and(|queues| queues.iter().any(|queue| !queue.is_empty()))
+}
+
+/// 执行所有队列中的工作项, 返回执行的个数
+///
+/// - 必须在开中断的普通上下文中调用, 工作项执行期间新到来的中断照常入队
+pub fn run_pending() -> usize {
+    debug_assert!(x86_64::instructions::interrupts::are_enabled());
+
+    let Ok(queues) = QUEUES.try_get() else {
+        return 0;
+    };
+    let mut count = 0;
+    for queue in queues {
+        while let Some(work) = queue.pop() {
+            work.run();
+            count += 1;
+        }
+    }
+    count
+}
+
+/// 返回被丢弃的工作项个数
+pub fn dropped() -> u64 {
+    DROPPED.load(Ordering::Relaxed)
+}
+
+#[test_case]
+fn test_run_pending_work() {
+    use core::sync::atomic::AtomicUsize;
+
+    static SUM: AtomicUsize = AtomicUsize::new(0);
+    fn add(value: usize) {
+        SUM.fetch_add(value, Ordering::SeqCst);
+    }
+
+    assert!(defer(super::irq::LPT2, Work::new(add, 40)));
+    assert!(defer(super::irq::LPT2, Work::new(add, 2)));
+    assert!(has_pending());
+    run_pending();
+    assert_eq!(SUM.load(Ordering::SeqCst), 42);
+    assert!(!has_pending());
+}
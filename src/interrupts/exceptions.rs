@@ -0,0 +1,241 @@
+//! CPU 异常处理
+//!
+//! - 所有体系结构定义的异常都经由 [`trap`](super::trap) 的入口桩进入 [`handle`]
+//! - #NM 用于懒切换线程的扩展寄存器状态, 见 [`thread::fpu`](crate::thread::fpu)
+//! - 看门狗通过 NMI 在卡住的 CPU 上打印现场, 见 [`watchdog`](crate::watchdog)
+//! - 除断点, #NM 和看门狗的 NMI 外的异常都是致命的, 统一走 [`fatal`] 打印现场和栈回溯后 panic;
+//!   例外是进程在用户态触发的异常, 只结束这个进程
+
+use super::trap::{self, TrapFrame};
+use crate::backtrace::Backtrace;
+use crate::{gdt, println, process, thread, watchdog};
+use core::fmt;
+use x86_64::registers::control::Cr2;
+use x86_64::structures::idt::{InterruptDescriptorTable, PageFaultErrorCode};
+
+pub const DIVIDE_ERROR: u8 = 0;
+pub const DEBUG: u8 = 1;
+pub const NON_MASKABLE_INTERRUPT: u8 = 2;
+pub const BREAKPOINT: u8 = 3;
+pub const OVERFLOW: u8 = 4;
+pub const BOUND_RANGE_EXCEEDED: u8 = 5;
+pub const INVALID_OPCODE: u8 = 6;
+pub const DEVICE_NOT_AVAILABLE: u8 = 7;
+pub const DOUBLE_FAULT: u8 = 8;
+pub const INVALID_TSS: u8 = 10;
+pub const SEGMENT_NOT_PRESENT: u8 = 11;
+pub const STACK_SEGMENT_FAULT: u8 = 12;
+pub const GENERAL_PROTECTION_FAULT: u8 = 13;
+pub const PAGE_FAULT: u8 = 14;
+pub const X87_FLOATING_POINT: u8 = 16;
+pub const ALIGNMENT_CHECK: u8 = 17;
+pub const MACHINE_CHECK: u8 = 18;
+pub const SIMD_FLOATING_POINT: u8 = 19;
+pub const VIRTUALIZATION: u8 = 20;
+pub const CONTROL_PROTECTION: u8 = 21;
+pub const HYPERVISOR_INJECTION: u8 = 28;
+pub const VMM_COMMUNICATION: u8 = 29;
+pub const SECURITY_EXCEPTION: u8 = 30;
+
+const EXCEPTION_NAMES: [&str; 32] = [
+    "DIVIDE ERROR (#DE)",
+    "DEBUG (#DB)",
+    "NON-MASKABLE INTERRUPT",
+    "BREAKPOINT (#BP)",
+    "OVERFLOW (#OF)",
+    "BOUND RANGE EXCEEDED (#BR)",
+    "INVALID OPCODE (#UD)",
+    "DEVICE NOT AVAILABLE (#NM)",
+    "DOUBLE FAULT (#DF)",
+    "COPROCESSOR SEGMENT OVERRUN",
+    "INVALID TSS (#TS)",
+    "SEGMENT NOT PRESENT (#NP)",
+    "STACK-SEGMENT FAULT (#SS)",
+    "GENERAL PROTECTION FAULT (#GP)",
+    "PAGE FAULT (#PF)",
+    "RESERVED",
+    "X87 FLOATING-POINT (#MF)",
+    "ALIGNMENT CHECK (#AC)",
+    "MACHINE CHECK (#MC)",
+    "SIMD FLOATING-POINT (#XM)",
+    "VIRTUALIZATION (#VE)",
+    "CONTROL PROTECTION (#CP)",
+    "RESERVED",
+    "RESERVED",
+    "RESERVED",
+    "RESERVED",
+    "RESERVED",
+    "RESERVED",
+    "HYPERVISOR INJECTION (#HV)",
+    "VMM COMMUNICATION (#VC)",
+    "SECURITY EXCEPTION (#SX)",
+    "RESERVED",
+];
+
+/// 返回异常向量的名称
+pub fn name(vector: u8) -> &'static str {
+    EXCEPTION_NAMES
+        .get(usize::from(vector))
+        .copied()
+        .unwrap_or("UNKNOWN")
+}
+
+/// 在IDT中为所有体系结构定义的异常安装入口桩
+pub(super) fn install(idt: &mut InterruptDescriptorTable) {
+    unsafe {
+        idt.divide_error
+            .set_handler_addr(trap::stub_addr(DIVIDE_ERROR));
+        idt.debug.set_handler_addr(trap::stub_addr(DEBUG));
+        idt.non_maskable_interrupt
+            .set_handler_addr(trap::stub_addr(NON_MASKABLE_INTERRUPT))
+            .set_stack_index(gdt::NMI_IST_INDEX);
+        idt.breakpoint.set_handler_addr(trap::stub_addr(BREAKPOINT));
+        idt.overflow.set_handler_addr(trap::stub_addr(OVERFLOW));
+        idt.bound_range_exceeded
+            .set_handler_addr(trap::stub_addr(BOUND_RANGE_EXCEEDED));
+        idt.invalid_opcode
+            .set_handler_addr(trap::stub_addr(INVALID_OPCODE));
+        idt.device_not_available
+            .set_handler_addr(trap::stub_addr(DEVICE_NOT_AVAILABLE));
+        idt.double_fault
+            .set_handler_addr(trap::stub_addr(DOUBLE_FAULT))
+            .set_stack_index(gdt::DOUBLE_FAULT_IST_INDEX);
+        idt.invalid_tss
+            .set_handler_addr(trap::stub_addr(INVALID_TSS));
+        idt.segment_not_present
+            .set_handler_addr(trap::stub_addr(SEGMENT_NOT_PRESENT));
+        idt.stack_segment_fault
+            .set_handler_addr(trap::stub_addr(STACK_SEGMENT_FAULT));
+        idt.general_protection_fault
+            .set_handler_addr(trap::stub_addr(GENERAL_PROTECTION_FAULT));
+        idt.page_fault.set_handler_addr(trap::stub_addr(PAGE_FAULT));
+        idt.x87_floating_point
+            .set_handler_addr(trap::stub_addr(X87_FLOATING_POINT));
+        idt.alignment_check
+            .set_handler_addr(trap::stub_addr(ALIGNMENT_CHECK));
+        idt.machine_check
+            .set_handler_addr(trap::stub_addr(MACHINE_CHECK))
+            .set_stack_index(gdt::MACHINE_CHECK_IST_INDEX);
+        idt.simd_floating_point
+            .set_handler_addr(trap::stub_addr(SIMD_FLOATING_POINT));
+        idt.virtualization
+            .set_handler_addr(trap::stub_addr(VIRTUALIZATION));
+        idt.cp_protection_exception
+            .set_handler_addr(trap::stub_addr(CONTROL_PROTECTION));
+        idt.hv_injection_exception
+            .set_handler_addr(trap::stub_addr(HYPERVISOR_INJECTION));
+        idt.vmm_communication_exception
+            .set_handler_addr(trap::stub_addr(VMM_COMMUNICATION));
+        idt.security_exception
+            .set_handler_addr(trap::stub_addr(SECURITY_EXCEPTION));
+    }
+}
+
+/// 异常分发
+///
+/// - 断点异常打印现场后返回, 继续执行 `int3` 之后的指令
+/// - 进程在用户态触发的异常标记进程为被 kill, 返回用户态之前结束它
+/// - 其余异常都无法恢复, 交给 [`fatal`]
+pub(super) fn handle(frame: &mut TrapFrame) {
+    let vector = frame.vector as u8;
+    let from_user = frame.stack_frame.code_segment & 3 == 3;
+    match vector {
+        BREAKPOINT => {
+            println!("EXCEPTION: BREAKPOINT\n{:#?}", frame.stack_frame);
+        }
+        DEVICE_NOT_AVAILABLE => thread::fpu::handle_device_not_available(),
+        NON_MASKABLE_INTERRUPT if watchdog::handle_nmi(frame) => {}
+        _ if from_user && process::kill_current_on_fault() => {
+            println!(
+                "process {:?}: {} at {:#x}, killed",
+                process::current().expect("faulting process missing"),
+                name(vector),
+                frame.stack_frame.instruction_pointer.as_u64()
+            );
+        }
+        _ => fatal(frame),
+    }
+}
+
+/// 致命异常的公共出口: 打印异常名, 错误码解析, CPU 帧, 通用寄存器和栈回溯, 然后 panic
+pub fn fatal(frame: &TrapFrame) -> ! {
+    let vector = frame.vector as u8;
+
+    println!("EXCEPTION: {}", name(vector));
+    println!("{}", ErrorCode::new(vector, frame.error_code));
+    println!("{:#?}", frame.stack_frame);
+    println!("{:?}", frame.registers);
+    println!(
+        "{}",
+        Backtrace::from_frame(
+            frame.stack_frame.instruction_pointer.as_u64(),
+            frame.registers.rbp
+        )
+    );
+    panic!("fatal exception {} ({})", vector, name(vector));
+}
+
+/// 按异常类型解析错误码
+enum ErrorCode {
+    None,
+    Raw(u64),
+    Selector(SelectorErrorCode),
+    PageFault(PageFaultErrorCode),
+}
+
+impl ErrorCode {
+    fn new(vector: u8, code: u64) -> Self {
+        match vector {
+            INVALID_TSS | SEGMENT_NOT_PRESENT | STACK_SEGMENT_FAULT | GENERAL_PROTECTION_FAULT => {
+                ErrorCode::Selector(SelectorErrorCode(code))
+            }
+            PAGE_FAULT => ErrorCode::PageFault(PageFaultErrorCode::from_bits_truncate(code)),
+            DOUBLE_FAULT | ALIGNMENT_CHECK | CONTROL_PROTECTION | VMM_COMMUNICATION
+            | SECURITY_EXCEPTION => ErrorCode::Raw(code),
+            _ => ErrorCode::None,
+        }
+    }
+}
+
+impl fmt::Display for ErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ErrorCode::None => write!(f, "Error Code: none"),
+            ErrorCode::Raw(code) => write!(f, "Error Code: {:#x}", code),
+            ErrorCode::Selector(selector) => write!(f, "Error Code: {}", selector),
+            ErrorCode::PageFault(code) => {
+                writeln!(f, "Accessed Address: {:?}", Cr2::read())?;
+                write!(f, "Error Code: {:?}", code)
+            }
+        }
+    }
+}
+
+/// #TS/#NP/#SS/#GP 的段选择子错误码
+///
+/// - bit 0: 异常由外部事件引起
+/// - bit 1-2: 选择子所在的表, `01`/`11` 为 IDT, `00` 为 GDT, `10` 为 LDT
+/// - bit 3-15: 选择子索引
+struct SelectorErrorCode(u64);
+
+impl fmt::Display for SelectorErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let code = self.0;
+        if code == 0 {
+            return write!(f, "0x0 (not segment related)");
+        }
+        let table = match (code >> 1) & 0b11 {
+            0b00 => "GDT",
+            0b10 => "LDT",
+            _ => "IDT",
+        };
+        write!(
+            f,
+            "{:#x} (external: {}, table: {}, index: {})",
+            code,
+            code & 1 != 0,
+            table,
+            (code >> 3) & 0x1fff
+        )
+    }
+}
@@ -0,0 +1,262 @@
+//! 硬件中断(IRQ)的运行时注册
+//!
+//! - 驱动通过 [`register_irq`] 把处理函数挂到某条 IRQ 线上, 不需要修改 IDT
+//! - 同一条线可以挂多个处理函数(共享中断), 中断到来时依次调用; 同一个处理函数不能在一条线上注册两次
+//! - 线上的第一个处理函数注册时自动在 PIC 中取消屏蔽, 最后一个注销时重新屏蔽.
+//!   注册和注销在 `PICS` 锁内修改处理函数表和屏蔽位, 是不是第一个/最后一个不会判断错
+//! - EOI 由 [`dispatch`] 统一发送, 处理函数不需要关心
+//! - IRQ7/IRQ15 可能是 PIC 产生的虚假中断, [`dispatch`] 读取 ISR 加以区分, 不会交给处理函数
+
+use super::stats;
+use super::trap::{self, TrapFrame};
+use super::{PICS, PIC_1_OFFSET};
+use core::sync::atomic::{AtomicUsize, Ordering};
+use pic8259::ChainedPics;
+use x86_64::instructions::port::Port;
+use x86_64::structures::idt::InterruptDescriptorTable;
+
+pub const TIMER: u8 = 0;
+pub const KEYBOARD: u8 = 1;
+pub const CASCADE: u8 = 2;
+pub const COM2: u8 = 3;
+pub const COM1: u8 = 4;
+pub const LPT2: u8 = 5;
+pub const FLOPPY: u8 = 6;
+pub const LPT1: u8 = 7;
+pub const RTC: u8 = 8;
+pub const MOUSE: u8 = 12;
+pub const FPU: u8 = 13;
+pub const PRIMARY_ATA: u8 = 14;
+pub const SECONDARY_ATA: u8 = 15;
+
+/// 两片 8259 PIC 一共 16 条 IRQ 线
+pub const IRQ_LINES: usize = 16;
+
+/// 每条 IRQ 线最多可以共享的处理函数个数
+pub const MAX_HANDLERS_PER_LINE: usize = 4;
+
+/// IRQ 处理函数, 在中断上下文中执行, 所以不能阻塞或分配内存
+pub type IrqHandler = fn();
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IrqError {
+    /// IRQ 线号超出范围
+    InvalidLine(u8),
+    /// 这条线上的处理函数已满
+    LineFull(u8),
+    /// 这个处理函数已经注册在这条线上
+    AlreadyRegistered(u8),
+    /// 这个处理函数没有注册在这条线上
+    NotRegistered(u8),
+}
+
+/// 处理函数表, 0 表示空槽位, 其余为函数指针
+///
+/// 用原子量而不是锁, 这样中断上下文中的 [`dispatch`] 读表时不会和注册/注销互相等待;
+/// 写表只在持有 `PICS` 锁时进行
+static HANDLERS: [[AtomicUsize; MAX_HANDLERS_PER_LINE]; IRQ_LINES] =
+    [const { [const { AtomicUsize::new(0) }; MAX_HANDLERS_PER_LINE] }; IRQ_LINES];
+
+/// 返回 IRQ 线对应的中断向量号
+pub const fn vector(line: u8) -> u8 {
+    PIC_1_OFFSET + line
+}
+
+/// 在IDT中为所有 IRQ 向量安装入口桩
+pub(super) fn install(idt: &mut InterruptDescriptorTable) {
+    for line in 0..IRQ_LINES as u8 {
+        let vector = vector(line);
+        unsafe {
+            idt[usize::from(vector)].set_handler_addr(trap::stub_addr(vector));
+        }
+    }
+}
+
+/// 在 IRQ 线 `line` 上注册处理函数
+pub fn register_irq(line: u8, handler: IrqHandler) -> Result<(), IrqError> {
+    let slots = HANDLERS
+        .get(usize::from(line))
+        .ok_or(IrqError::InvalidLine(line))?;
+
+    let handler = handler as usize;
+    let mut pics = PICS.lock();
+    if slots
+        .iter()
+        .any(|slot| slot.load(Ordering::Relaxed) == handler)
+    {
+        return Err(IrqError::AlreadyRegistered(line));
+    }
+    let slot = slots
+        .iter()
+        .find(|slot| slot.load(Ordering::Relaxed) == 0)
+        .ok_or(IrqError::LineFull(line))?;
+    let first = slots.iter().all(|slot| slot.load(Ordering::Relaxed) == 0);
+    slot.store(handler, Ordering::Release);
+
+    if first {
+        set_masked(&mut pics, line, false);
+    }
+    Ok(())
+}
+
+/// 从 IRQ 线 `line` 上注销处理函数
+pub fn unregister_irq(line: u8, handler: IrqHandler) -> Result<(), IrqError> {
+    let slots = HANDLERS
+        .get(usize::from(line))
+        .ok_or(IrqError::InvalidLine(line))?;
+
+    let handler = handler as usize;
+    let mut pics = PICS.lock();
+    let slot = slots
+        .iter()
+        .find(|slot| slot.load(Ordering::Relaxed) == handler)
+        .ok_or(IrqError::NotRegistered(line))?;
+    slot.store(0, Ordering::Release);
+    let last = slots.iter().all(|slot| slot.load(Ordering::Relaxed) == 0);
+
+    if last {
+        set_masked(&mut pics, line, true);
+    }
+    Ok(())
+}
+
+/// 返回 IRQ 线上已注册的处理函数个数
+pub fn registered_handlers(line: u8) -> usize {
+    HANDLERS.get(usize::from(line)).map_or(0, |slots| {
+        slots
+            .iter()
+            .filter(|slot| slot.load(Ordering::Acquire) != 0)
+            .count()
+    })
+}
+
+/// 返回 IRQ 线当前是否在 PIC 中被屏蔽
+pub fn is_masked(line: u8) -> bool {
+    let masks = unsafe { PICS.lock().read_masks() };
+    let (pic, bit) = (usize::from(line / 8), line % 8);
+    masks[pic] & (1 << bit) != 0
+}
+
+/// 屏蔽所有 IRQ 线, 只保留级联线 IRQ2
+///
+/// - 没有处理函数的线保持屏蔽, [`register_irq`] 时再打开
+pub(super) fn mask_all() {
+    unsafe { PICS.lock().write_masks(!(1 << CASCADE), u8::MAX) };
+}
+
+/// 在 PIC 中屏蔽或取消屏蔽 IRQ 线
+///
+/// - 从片上的线需要同时打开主片上的级联线 IRQ2 才能送达 CPU
+fn set_masked(pics: &mut ChainedPics, line: u8, masked: bool) {
+    let [mut master, mut slave] = unsafe { pics.read_masks() };
+    let (mask, bit) = if line < 8 {
+        (&mut master, line)
+    } else {
+        (&mut slave, line - 8)
+    };
+    if masked {
+        *mask |= 1 << bit;
+    } else {
+        *mask &= !(1 << bit);
+    }
+    if line >= 8 && !masked {
+        master &= !(1 << CASCADE);
+    }
+    unsafe { pics.write_masks(master, slave) };
+}
+
+const PIC_1_COMMAND: u16 = 0x20;
+const PIC_2_COMMAND: u16 = 0xa0;
+/// OCW3: 下一次读命令端口返回 ISR(In-Service Register)
+const CMD_READ_ISR: u8 = 0x0b;
+const CMD_END_OF_INTERRUPT: u8 = 0x20;
+
+/// 读取两片 PIC 的 ISR, 某一位为 1 表示对应的 IRQ 正在被处理
+fn read_isr() -> [u8; 2] {
+    // 持有 PICS 锁, 避免和其他对命令端口的访问交错
+    let _pics = PICS.lock();
+    let mut master: Port<u8> = Port::new(PIC_1_COMMAND);
+    let mut slave: Port<u8> = Port::new(PIC_2_COMMAND);
+    unsafe {
+        master.write(CMD_READ_ISR);
+        slave.write(CMD_READ_ISR);
+        [master.read(), slave.read()]
+    }
+}
+
+/// 判断 IRQ7/IRQ15 是否为虚假中断, 其余的线总是返回 `false`
+///
+/// - PIC 在 INTA 周期中发现请求已经撤销时, 会以最低优先级的 IRQ7(从片为 IRQ15)上报,
+///   但不会在 ISR 中置位, 所以 ISR 对应位为 0 就是虚假中断
+fn is_spurious(line: u8) -> bool {
+    match line {
+        LPT1 => read_isr()[0] & (1 << 7) == 0,
+        SECONDARY_ATA => read_isr()[1] & (1 << 7) == 0,
+        _ => false,
+    }
+}
+
+/// IRQ 分发: 依次调用这条线上的所有处理函数, 然后发送 EOI
+///
+/// - 虚假的 IRQ7 不能发送 EOI; 虚假的 IRQ15 只向主片发送 EOI, 因为主片确实在处理级联线
+/// - 没有任何处理函数的线上到来的中断也记为虚假中断
+pub(super) fn dispatch(frame: &mut TrapFrame) {
+    let vector = frame.vector as u8;
+    let line = vector - PIC_1_OFFSET;
+
+    if is_spurious(line) {
+        stats::record_spurious(vector);
+        if line == SECONDARY_ATA {
+            let _pics = PICS.lock();
+            unsafe { Port::new(PIC_1_COMMAND).write(CMD_END_OF_INTERRUPT) };
+        }
+        return;
+    }
+
+    let mut handled = false;
+    for slot in &HANDLERS[usize::from(line)] {
+        let handler = slot.load(Ordering::Acquire);
+        if handler != 0 {
+            let handler: IrqHandler = unsafe { core::mem::transmute(handler) };
+            handler();
+            handled = true;
+        }
+    }
+    if !handled {
+        stats::record_spurious(vector);
+    }
+
+    unsafe {
+        PICS.lock().notify_end_of_interrupt(vector);
+    }
+}
+
+#[test_case]
+fn test_register_unregister_irq() {
+    fn handler() {}
+
+    assert_eq!(registered_handlers(LPT2), 0);
+    register_irq(LPT2, handler).expect("register failed");
+    assert_eq!(registered_handlers(LPT2), 1);
+    assert!(!is_masked(LPT2));
+    assert_eq!(
+        register_irq(LPT2, handler),
+        Err(IrqError::AlreadyRegistered(LPT2))
+    );
+    assert_eq!(registered_handlers(LPT2), 1);
+
+    unregister_irq(LPT2, handler).expect("unregister failed");
+    assert_eq!(registered_handlers(LPT2), 0);
+    assert!(is_masked(LPT2));
+    assert_eq!(
+        unregister_irq(LPT2, handler),
+        Err(IrqError::NotRegistered(LPT2))
+    );
+}
+
+#[test_case]
+fn test_unused_lines_masked() {
+    assert!(!is_masked(CASCADE));
+    assert!(is_masked(SECONDARY_ATA));
+    assert!(!is_masked(TIMER));
+}
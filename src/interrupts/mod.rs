@@ -0,0 +1,72 @@
+use crate::percpu;
+use crate::smp::lapic;
+use crate::sync::IrqSafeMutex;
+use core::sync::atomic::Ordering;
+use lazy_static::lazy_static;
+use pic8259::ChainedPics;
+use x86_64::structures::idt::InterruptDescriptorTable;
+
+pub mod deferred;
+pub mod exceptions;
+pub mod irq;
+pub mod stats;
+pub mod trap;
+
+pub use irq::{register_irq, unregister_irq, IrqError, IrqHandler};
+
+pub const PIC_1_OFFSET: u8 = 32;
+pub const PIC_2_OFFSET: u8 = PIC_1_OFFSET + 8;
+pub static PICS: IrqSafeMutex<ChainedPics> =
+    IrqSafeMutex::new(unsafe { ChainedPics::new(PIC_1_OFFSET, PIC_2_OFFSET) });
+
+lazy_static! {
+    static ref IDT: InterruptDescriptorTable = {
+        let mut idt = InterruptDescriptorTable::new();
+        exceptions::install(&mut idt);
+        irq::install(&mut idt);
+        for vector in [lapic::TIMER_VECTOR, lapic::SPURIOUS_VECTOR] {
+            unsafe {
+                idt[usize::from(vector)].set_handler_addr(trap::stub_addr(vector));
+            }
+        }
+        idt
+    };
+}
+
+fn timer_interrupt_handler() {
+    // print!(".");
+    crate::time::tick();
+    crate::thread::timer_tick();
+}
+
+/// IDT初始化加载
+///
+/// - 定义好IDT后需要将其地址加载CPU中保存, 这样CPU遇到中断就能直接查表了
+/// - 所有 CPU 共用同一个 IDT, 每个 CPU 启动时各自调用一次
+pub fn init_idt() {
+    IDT.load();
+}
+
+/// 初始化 PIC 并注册内核自身使用的 IRQ
+///
+/// - 初始化后屏蔽所有 IRQ 线, 注册处理函数时才会打开对应的线
+/// - 分配延迟工作队列, 所以必须在堆初始化之后调用
+/// - 设备驱动的 IRQ 由各自的初始化函数通过 [`register_irq`] 注册
+pub fn init_irq() {
+    unsafe { PICS.lock().initialize() };
+    irq::mask_all();
+    deferred::init();
+    register_irq(irq::TIMER, timer_interrupt_handler).expect("failed to register timer IRQ");
+}
+
+/// 当前 CPU 是否正在处理中断或异常
+pub fn in_interrupt() -> bool {
+    percpu!(irq_depth).load(Ordering::Relaxed) > 0
+}
+
+#[test_case]
+fn test_breakpoint_exception() {
+    x86_64::instructions::interrupts::int3();
+}
+
+// random code
@@ -0,0 +1,146 @@
+//! 按向量统计中断次数和处理耗时
+//!
+//! - 由 [`trap`](super::trap) 的分发函数维护, 所有经过入口桩的向量都会被统计
+//! - 耗时以 TSC 周期为单位, 包含处理函数和 EOI
+//! - [`snapshot`] 返回当前所有非零计数的副本, [`report`] 格式化为适合控制台输出的表格
+
+use super::exceptions;
+use alloc::vec::Vec;
+use core::arch::x86_64::_rdtsc;
+use core::fmt;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+/// 单个向量的计数器
+struct VectorStats {
+    count: AtomicU64,
+    spurious: AtomicU64,
+    total_cycles: AtomicU64,
+    max_cycles: AtomicU64,
+}
+
+impl VectorStats {
+    const fn new() -> Self {
+        VectorStats {
+            count: AtomicU64::new(0),
+            spurious: AtomicU64::new(0),
+            total_cycles: AtomicU64::new(0),
+            max_cycles: AtomicU64::new(0),
+        }
+    }
+}
+
+static STATS: [VectorStats; 256] = [const { VectorStats::new() }; 256];
+
+/// 读取时间戳计数器
+pub fn rdtsc() -> u64 {
+    unsafe { _rdtsc() }
+}
+
+/// 记录一次中断, 在调用处理函数之前调用
+pub(super) fn record_entry(vector: u8) {
+    STATS[usize::from(vector)]
+        .count
+        .fetch_add(1, Ordering::Relaxed);
+}
+
+/// 记录处理函数的耗时
+pub(super) fn record_cycles(vector: u8, cycles: u64) {
+    let stats = &STATS[usize::from(vector)];
+    stats.total_cycles.fetch_add(cycles, Ordering::Relaxed);
+    stats.max_cycles.fetch_max(cycles, Ordering::Relaxed);
+}
+
+/// 记录一次虚假中断(没有任何处理函数认领的中断)
+pub(super) fn record_spurious(vector: u8) {
+    STATS[usize::from(vector)]
+        .spurious
+        .fetch_add(1, Ordering::Relaxed);
+}
+
+/// 某个向量在某一时刻的统计值
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VectorSnapshot {
+    pub vector: u8,
+    pub count: u64,
+    pub spurious: u64,
+    pub total_cycles: u64,
+    pub max_cycles: u64,
+}
+
+impl VectorSnapshot {
+    /// 平均每次处理耗费的周期数
+    pub fn average_cycles(&self) -> u64 {
+        self.total_cycles.checked_div(self.count).unwrap_or(0)
+    }
+}
+
+/// 返回单个向量的统计值
+pub fn vector_snapshot(vector: u8) -> VectorSnapshot {
+    let stats = &STATS[usize::from(vector)];
+    VectorSnapshot {
+        vector,
+        count: stats.count.load(Ordering::Relaxed),
+        spurious: stats.spurious.load(Ordering::Relaxed),
+        total_cycles: stats.total_cycles.load(Ordering::Relaxed),
+        max_cycles: stats.max_cycles.load(Ordering::Relaxed),
+    }
+}
+
+/// 返回所有触发过的向量的统计值, 按向量号排序
+pub fn snapshot() -> Vec<VectorSnapshot> {
+    (0..=u8::MAX)
+        .map(vector_snapshot)
+        .filter(|stats| stats.count != 0 || stats.spurious != 0)
+        .collect()
+}
+
+/// 返回可以直接 `println!` 的统计表
+pub fn report() -> Report {
+    Report(snapshot())
+}
+
+/// 格式化后的统计表
+pub struct Report(Vec<VectorSnapshot>);
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:>3}  {:<22} {:>10} {:>8} {:>10} {:>10}",
+            "VEC", "NAME", "COUNT", "SPURIOUS", "AVG CYC", "MAX CYC"
+        )?;
+        for stats in &self.0 {
+            write!(
+                f,
+                "\n{:>3}  {:<22} {:>10} {:>8} {:>10} {:>10}",
+                stats.vector,
+                VectorName(stats.vector),
+                stats.count,
+                stats.spurious,
+                stats.average_cycles(),
+                stats.max_cycles
+            )?;
+        }
+        Ok(())
+    }
+}
+
+struct VectorName(u8);
+
+impl fmt::Display for VectorName {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let vector = self.0;
+        match vector {
+            0..=31 => f.pad(exceptions::name(vector)),
+            32..=47 => f.pad(&alloc::format!("IRQ{}", vector - super::PIC_1_OFFSET)),
+            _ => f.pad(&alloc::format!("VECTOR {}", vector)),
+        }
+    }
+}
+
+#[test_case]
+fn test_breakpoint_is_counted() {
+    let before = vector_snapshot(exceptions::BREAKPOINT).count;
+    x86_64::instructions::interrupts::int3();
+    assert_eq!(vector_snapshot(exceptions::BREAKPOINT).count, before + 1);
+}
@@ -0,0 +1,247 @@
+//! 异常/中断的统一入口
+//!
+//! `extern "x86-interrupt"` 函数拿不到被打断时的通用寄存器, 所以这里用汇编为每个向量生成一个
+//! 入口桩(stub): 桩先压入错误码(CPU 没有压入时补一个 0)和向量号, 再由公共入口保存全部通用寄存器,
+//! 最后以 [`TrapFrame`] 的形式交给 Rust 代码处理.
+//!
+//! - 从用户态陷入时 GS 基址是用户的, 入口用 `swapgs` 换成内核的, 返回前再换回去
+//! - 一般的向量看被打断的 CS 就知道来自哪一态. 使用 IST 的 NMI, #DF 和 #MC 可能打断
+//!   `SYSCALL` 入口 `swapgs` 之前, 或返回用户态时 `swapgs` 之后的窗口, 这时 CS 是内核的而
+//!   GS 基址是用户的. 所以它们读取 `IA32_GS_BASE`, 不在内核的 CPU 私有数据范围内时才 `swapgs`
+//! - 入口是否执行了 `swapgs` 记录在 `r12` 中, 返回时按它恢复原来的 GS 基址
+
+use super::exceptions::{DOUBLE_FAULT, MACHINE_CHECK, NON_MASKABLE_INTERRUPT};
+use super::stats;
+use crate::percpu;
+use crate::smp::{lapic, MAX_CPUS};
+use core::arch::global_asm;
+use core::fmt;
+use core::sync::atomic::Ordering;
+use x86_64::structures::idt::InterruptStackFrameValue;
+use x86_64::VirtAddr;
+
+/// 生成入口桩的向量个数: 覆盖全部 256 个向量, IDT 中只安装实际使用的向量
+pub const STUB_COUNT: usize = 256;
+
+/// `IA32_GS_BASE` MSR
+const IA32_GS_BASE: u32 = 0xC000_0101;
+
+/// 每个入口桩占用的字节数, 桩的地址为 `trap_stubs + vector * STUB_SIZE`
+const STUB_SIZE: usize = 16;
+
+global_asm!(
+    ".pushsection .text.trap_stubs, \"ax\"",
+    ".balign 16",
+    ".global trap_stubs",
+    "trap_stubs:",
+    ".set trap_vector, 0",
+    ".rept {count}",
+    ".balign {stub_size}",
+    // 这些向量由 CPU 压入错误码, 其余的补一个 0, 保证栈布局一致
+    ".if !(trap_vector == 8 || (trap_vector >= 10 && trap_vector <= 14) || trap_vector == 17 || trap_vector == 21 || trap_vector == 29 || trap_vector == 30)",
+    "pushq $0",
+    ".endif",
+    "pushq $trap_vector",
+    "jmp trap_common",
+    ".set trap_vector, trap_vector + 1",
+    ".endr",
+    "",
+    "trap_common:",
+    "pushq %rax",
+    "pushq %rbx",
+    "pushq %rcx",
+    "pushq %rdx",
+    "pushq %rsi",
+    "pushq %rdi",
+    "pushq %rbp",
+    "pushq %r8",
+    "pushq %r9",
+    "pushq %r10",
+    "pushq %r11",
+    "pushq %r12",
+    "pushq %r13",
+    "pushq %r14",
+    "pushq %r15",
+    // r12 记录是否执行了 swapgs, 它由被调用者保存, 调用分发函数之后不变
+    "xorl %r12d, %r12d",
+    // 通用寄存器之上依次是向量号, 错误码, RIP 和 CS
+    "movq 120(%rsp), %rax",
+    "cmpq ${nmi}, %rax",
+    "je 2f",
+    "cmpq ${double_fault}, %rax",
+    "je 2f",
+    "cmpq ${machine_check}, %rax",
+    "je 2f",
+    "testb $3, 144(%rsp)",
+    "jz 3f",
+    "jmp 1f",
+    // IST 向量: GS 基址不在 PERCPU 数组内时是用户的
+    "2:",
+    "movl ${gs_base}, %ecx",
+    "rdmsr",
+    "shlq $32, %rdx",
+    "orq %rdx, %rax",
+    "leaq {percpu}(%rip), %rcx",
+    "subq %rcx, %rax",
+    "cmpq ${percpu_size}, %rax",
+    "jb 3f",
+    "1:",
+    "swapgs",
+    "movl $1, %r12d",
+    "3:",
+    // CPU 帧(40) + 错误码和向量号(16) + 通用寄存器(120) = 176, 此时 rsp 已 16 字节对齐
+    "movq %rsp, %rdi",
+    "cld",
+    "call {dispatch}",
+    "testl %r12d, %r12d",
+    "jz 4f",
+    "swapgs",
+    "4:",
+    "popq %r15",
+    "popq %r14",
+    "popq %r13",
+    "popq %r12",
+    "popq %r11",
+    "popq %r10",
+    "popq %r9",
+    "popq %r8",
+    "popq %rbp",
+    "popq %rdi",
+    "popq %rsi",
+    "popq %rdx",
+    "popq %rcx",
+    "popq %rbx",
+    "popq %rax",
+    // 跳过向量号和错误码
+    "addq $16, %rsp",
+    "iretq",
+    ".popsection",
+    count = const STUB_COUNT,
+    stub_size = const STUB_SIZE,
+    dispatch = sym trap_dispatch,
+    nmi = const NON_MASKABLE_INTERRUPT,
+    double_fault = const DOUBLE_FAULT,
+    machine_check = const MACHINE_CHECK,
+    gs_base = const IA32_GS_BASE,
+    percpu = sym percpu::PERCPU,
+    percpu_size = const core::mem::size_of::<percpu::PerCpu>() * MAX_CPUS,
+    options(att_syntax)
+);
+
+extern "C" {
+    static trap_stubs: u8;
+}
+
+/// 返回向量 `vector` 的入口桩地址, 用于填写 IDT
+pub fn stub_addr(vector: u8) -> VirtAddr {
+    assert!(
+        usize::from(vector) < STUB_COUNT,
+        "no trap stub for vector {}",
+        vector
+    );
+    let base = core::ptr::addr_of!(trap_stubs);
+    VirtAddr::from_ptr(base) + usize::from(vector) * STUB_SIZE
+}
+
+/// 公共入口保存的通用寄存器, 字段顺序与压栈顺序相反
+#[derive(Clone, Copy, Default)]
+#[repr(C)]
+pub struct Registers {
+    pub r15: u64,
+    pub r14: u64,
+    pub r13: u64,
+    pub r12: u64,
+    pub r11: u64,
+    pub r10: u64,
+    pub r9: u64,
+    pub r8: u64,
+    pub rbp: u64,
+    pub rdi: u64,
+    pub rsi: u64,
+    pub rdx: u64,
+    pub rcx: u64,
+    pub rbx: u64,
+    pub rax: u64,
+}
+
+impl fmt::Debug for Registers {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let rows = [
+            [("RAX", self.rax), ("RBX", self.rbx), ("RCX", self.rcx)],
+            [("RDX", self.rdx), ("RSI", self.rsi), ("RDI", self.rdi)],
+            [("RBP", self.rbp), ("R8 ", self.r8), ("R9 ", self.r9)],
+            [("R10", self.r10), ("R11", self.r11), ("R12", self.r12)],
+        ];
+        for row in rows {
+            for (name, value) in row {
+                write!(f, "{}={:#018x} ", name, value)?;
+            }
+            writeln!(f)?;
+        }
+        write!(
+            f,
+            "R13={:#018x} R14={:#018x} R15={:#018x}",
+            self.r13, self.r14, self.r15
+        )
+    }
+}
+
+/// 入口桩在栈上构造的完整现场
+#[derive(Debug)]
+#[repr(C)]
+pub struct TrapFrame {
+    pub registers: Registers,
+    pub vector: u64,
+    pub error_code: u64,
+    pub stack_frame: InterruptStackFrameValue,
+}
+
+/// 所有入口桩的 Rust 侧分发函数, 同时维护 [`stats`] 中的计数和耗时
+///
+/// - IRQ 处理完成后是线程抢占点, 被换下的线程恢复时从这里返回
+/// - 返回用户态之前检查当前进程是否已被 kill
+extern "C" fn trap_dispatch(frame: &mut TrapFrame) {
+    let vector = frame.vector as u8;
+    let irq_depth = percpu!(irq_depth);
+    irq_depth.fetch_add(1, Ordering::Relaxed);
+    stats::record_entry(vector);
+    let start = stats::rdtsc();
+
+    let is_irq = match vector {
+        0..=31 => {
+            super::exceptions::handle(frame);
+            false
+        }
+        // local APIC 定时器只用来唤醒看门狗的监视者, 不是抢占点
+        lapic::TIMER_VECTOR => {
+            lapic::eoi();
+            false
+        }
+        // local APIC 的虚假中断不需要 EOI
+        lapic::SPURIOUS_VECTOR => {
+            stats::record_spurious(vector);
+            false
+        }
+        _ => {
+            super::irq::dispatch(frame);
+            true
+        }
+    };
+
+    stats::record_cycles(vector, stats::rdtsc().wrapping_sub(start));
+    // 看门狗要打印被时钟中断打断的现场, 所以在这里而不是时钟中断处理函数中检查
+    if vector == super::PIC_1_OFFSET + super::irq::TIMER {
+        crate::watchdog::check(frame);
+    }
+
+    // 被换下的线程和换上的线程都不应继承中断嵌套深度, 所以先退出中断再抢占,
+    // 嵌套在其他中断中时不抢占
+    let outermost = irq_depth.fetch_sub(1, Ordering::Relaxed) == 1;
+    if outermost && is_irq {
+        crate::thread::preempt_on_irq_exit();
+    }
+    // 返回用户态之前结束已被 kill 的进程
+    if outermost && frame.stack_frame.code_segment & 3 == 3 {
+        crate::process::exit_if_killed();
+    }
+}
@@ -6,15 +6,30 @@
 #![feature(abi_x86_interrupt)]
 
 use bootloader::BootInfo;
+use core::fmt::{self, Write};
 use core::panic::PanicInfo;
 
+pub mod acpi;
+pub mod address_space;
 pub mod allocator;
+pub mod backtrace;
+pub mod cpu;
+pub mod elf;
 pub mod gdt;
 pub mod interrupts;
+pub mod loader;
 pub mod memory;
+pub mod percpu;
+pub mod process;
 pub mod serial;
+pub mod smp;
+pub mod sync;
+pub mod syscall;
 pub mod task;
+pub mod thread;
+pub mod time;
 pub mod vga_buffer;
+pub mod watchdog;
 
 extern crate alloc;
 
@@ -66,10 +81,44 @@ pub fn test_runner(tests: &[&dyn Testable]) {
 pub fn test_panic_handler(info: &PanicInfo) -> ! {
     serial_println!("[failed]\n");
     serial_println!("Error: {}\n", info);
+    serial_println!("{}", backtrace::Backtrace::capture());
     exit_qemu(QemuExitCode::Failed);
     hlt_loop();
 }
 
+/// 期望 panic 的测试使用: panic 信息以 `prefix` 开头时测试通过, 否则以失败退出
+pub fn test_expect_panic(info: &PanicInfo, prefix: &str) -> ! {
+    let mut matcher = PrefixMatcher {
+        rest: prefix.as_bytes(),
+        matched: true,
+    };
+    let _ = write!(matcher, "{}", info.message());
+    if matcher.matched && matcher.rest.is_empty() {
+        serial_println!("[ok]");
+        exit_qemu(QemuExitCode::Success);
+    } else {
+        serial_println!("[failed]\n\nError: {}\n", info);
+        exit_qemu(QemuExitCode::Failed);
+    }
+    hlt_loop();
+}
+
+/// 边格式化边和前缀比较, 不需要缓冲整条 panic 信息
+struct PrefixMatcher<'a> {
+    /// 还没有比较的前缀
+    rest: &'a [u8],
+    matched: bool,
+}
+
+impl fmt::Write for PrefixMatcher<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let n = s.len().min(self.rest.len());
+        self.matched &= s.as_bytes()[..n] == self.rest[..n];
+        self.rest = &self.rest[n..];
+        Ok(())
+    }
+}
+
 // Entry point for `cargo test`
 #[cfg(test)]
 fn test_kernel_main(boot_info: &'static BootInfo) -> ! {
@@ -87,25 +136,48 @@ fn panic(info: &PanicInfo) -> ! {
 }
 
 pub fn init(boot_info: &'static BootInfo) {
-    // 初始化全局描述符表
+    // BSP 的 CPU 私有数据, 之后的初始化都可能用到
+    percpu::init(0);
+    percpu::current().set_stack(Some(memory::boot_stack(boot_info)));
+    // 启用 SSE 等 SIMD 扩展, 供用户程序使用
+    cpu::init();
+
+    // 初始化内核页表和帧分配器, 之后的 IST 栈和堆都从这里分配
+    use x86_64::VirtAddr;
+
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    unsafe { memory::init_kernel_memory(phys_mem_offset, &boot_info.memory_map) };
+
+    // 动态内存(堆内存)分配器初始化
+    memory::with_kernel_memory(|mapper, frame_allocator| {
+        allocator::init_heap(mapper, frame_allocator)
+    })
+    .expect("heap initialization failed");
+
+    // 初始化全局描述符表和系统调用入口
     gdt::init();
+    syscall::init();
 
     // 初始化中断描述符表
     interrupts::init_idt();
-    unsafe { interrupts::PICS.lock().initialize() };
+    interrupts::init_irq();
+    task::keyboard::init();
+
+    // 时钟中断驱动线程调度
+    time::init();
+    thread::init();
 
     // 启用中断
     x86_64::instructions::interrupts::enable();
 
-    // 动态内存(堆内存)分配器初始化
-    use memory::BootInfoFrameAllocator;
-    use x86_64::VirtAddr;
-
-    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
-    let mut mapper = unsafe { memory::init(phys_mem_offset) };
-    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
-
-    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+    // 启动其他 CPU, 等待时需要时钟中断; 失败时以已经上线的 CPU 继续启动
+    if let Err(err) = smp::init() {
+        println!(
+            "WARNING: SMP initialization failed ({:?}), continuing with {} CPU(s)",
+            err,
+            smp::cpu_count()
+        );
+    }
 }
 
 pub fn hlt_loop() -> ! {
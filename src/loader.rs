@@ -0,0 +1,193 @@
+//! 用户程序加载
+//!
+//! - [`load`] 为 ELF 文件创建新的地址空间, 按段的权限映射 `PT_LOAD` 段, 并在用户栈上按
+//!   System V x86_64 ABI 准备 argc, argv, envp 和辅助向量(auxv)
+//! - [`exec`] 让当前线程切换到新地址空间, 以 ring 3 从程序入口开始执行
+
+use crate::address_space::{self, AddressSpace, NotMapped, USER_END};
+use crate::elf::{Elf, ElfError, ProgramHeader};
+use crate::{syscall, thread};
+use alloc::vec::Vec;
+use x86_64::structures::paging::mapper::MapToError;
+use x86_64::structures::paging::{PageTableFlags, Size4KiB};
+use x86_64::VirtAddr;
+
+/// 用户栈的页数, 栈顶为用户地址范围的末尾
+pub const USER_STACK_PAGES: u64 = 16;
+pub const USER_STACK_TOP: u64 = USER_END;
+const USER_STACK_SIZE: u64 = USER_STACK_PAGES * 4096;
+
+/// 辅助向量的类型
+pub const AT_NULL: u64 = 0;
+pub const AT_PHDR: u64 = 3;
+pub const AT_PHENT: u64 = 4;
+pub const AT_PHNUM: u64 = 5;
+pub const AT_PAGESZ: u64 = 6;
+pub const AT_ENTRY: u64 = 9;
+pub const AT_RANDOM: u64 = 25;
+
+#[derive(Debug)]
+pub enum LoadError {
+    Elf(ElfError),
+    /// 段不在用户地址范围内
+    SegmentOutOfRange,
+    /// 入口不在可执行的段中
+    BadEntry,
+    /// 参数和环境变量放不进用户栈
+    ArgumentsTooLarge,
+    Mapping(MapToError<Size4KiB>),
+}
+
+impl From<ElfError> for LoadError {
+    fn from(err: ElfError) -> Self {
+        LoadError::Elf(err)
+    }
+}
+
+impl From<MapToError<Size4KiB>> for LoadError {
+    fn from(err: MapToError<Size4KiB>) -> Self {
+        LoadError::Mapping(err)
+    }
+}
+
+impl From<NotMapped> for LoadError {
+    fn from(_: NotMapped) -> Self {
+        // 写入的都是刚映射的页, 只有参数超出用户栈时才会遇到
+        LoadError::ArgumentsTooLarge
+    }
+}
+
+/// 加载完成, 可以开始执行的程序
+pub struct Program {
+    pub address_space: AddressSpace,
+    pub entry: VirtAddr,
+    /// 初始栈指针, 指向 argc
+    pub stack_pointer: VirtAddr,
+}
+
+/// 把 ELF 文件 `image` 加载到新的地址空间中
+pub fn load(image: &[u8], argv: &[&str], envp: &[&str]) -> Result<Program, LoadError> {
+    let elf = Elf::parse(image)?;
+    let segments = || elf.program_headers().filter(|header| header.is_load());
+
+    let entry = elf.entry();
+    let in_code = |s: &ProgramHeader| s.mem_range().is_some_and(|r| r.contains(&entry));
+    if !segments().any(|s| s.is_executable() && in_code(&s)) {
+        return Err(LoadError::BadEntry);
+    }
+
+    let mut address_space = AddressSpace::new()?;
+    for segment in segments() {
+        if !address_space::is_user_range(segment.vaddr, segment.mem_size) {
+            return Err(LoadError::SegmentOutOfRange);
+        }
+        let mut flags = PageTableFlags::empty();
+        if segment.is_writable() {
+            flags |= PageTableFlags::WRITABLE;
+        }
+        if !segment.is_executable() {
+            flags |= PageTableFlags::NO_EXECUTE;
+        }
+        let start = VirtAddr::new(segment.vaddr);
+        address_space.map(start, segment.mem_size, flags)?;
+        // 新映射的页已经清零, 文件大小之外的部分(.bss)不需要再处理
+        address_space.write(start, elf.segment_data(&segment))?;
+    }
+
+    // 程序头表如果被某个段加载到了内存中, 通过 AT_PHDR 告诉程序它的地址
+    let ph_offset = elf.program_header_offset();
+    let phdr = segments()
+        .find(|s| s.offset <= ph_offset && ph_offset < s.offset + s.file_size)
+        .map(|s| s.vaddr + (ph_offset - s.offset));
+    let mut auxv = Vec::new();
+    if let Some(phdr) = phdr {
+        auxv.push((AT_PHDR, phdr));
+    }
+    auxv.push((AT_PHENT, 56));
+    auxv.push((AT_PHNUM, elf.program_header_count() as u64));
+    auxv.push((AT_PAGESZ, 4096));
+    auxv.push((AT_ENTRY, entry));
+
+    let stack_pointer = build_stack(&mut address_space, argv, envp, auxv)?;
+    Ok(Program {
+        address_space,
+        entry: VirtAddr::new(entry),
+        stack_pointer,
+    })
+}
+
+/// 映射用户栈并写入初始内容, 返回初始栈指针
+///
+/// 从高地址到低地址依次是: AT_RANDOM 指向的 16 字节, 参数和环境变量字符串,
+/// 对齐填充, 辅助向量, envp, argv, argc. 栈指针指向 argc, 16 字节对齐
+fn build_stack(
+    address_space: &mut AddressSpace,
+    argv: &[&str],
+    envp: &[&str],
+    mut auxv: Vec<(u64, u64)>,
+) -> Result<VirtAddr, LoadError> {
+    let stack_bottom = USER_STACK_TOP - USER_STACK_SIZE;
+    address_space.map(
+        VirtAddr::new(stack_bottom),
+        USER_STACK_SIZE,
+        PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE,
+    )?;
+
+    let mut top = USER_STACK_TOP;
+    let mut push_bytes = |bytes: &[u8], address_space: &mut AddressSpace| {
+        top = top.checked_sub(bytes.len() as u64)?;
+        (top >= stack_bottom).then_some(())?;
+        address_space.write(VirtAddr::new(top), bytes).ok()?;
+        Some(top)
+    };
+
+    let random = crate::interrupts::stats::rdtsc().to_le_bytes().repeat(2);
+    let random = push_bytes(&random, address_space).ok_or(LoadError::ArgumentsTooLarge)?;
+    auxv.push((AT_RANDOM, random));
+
+    let mut push_strings = |strings: &[&str], address_space: &mut AddressSpace| {
+        strings
+            .iter()
+            .map(|s| {
+                let mut bytes = Vec::with_capacity(s.len() + 1);
+                bytes.extend_from_slice(s.as_bytes());
+                bytes.push(0);
+                push_bytes(&bytes, address_space).ok_or(LoadError::ArgumentsTooLarge)
+            })
+            .collect::<Result<Vec<u64>, LoadError>>()
+    };
+    let argv_ptrs = push_strings(argv, address_space)?;
+    let envp_ptrs = push_strings(envp, address_space)?;
+
+    let mut words = Vec::new();
+    words.push(argv.len() as u64);
+    words.extend_from_slice(&argv_ptrs);
+    words.push(0);
+    words.extend_from_slice(&envp_ptrs);
+    words.push(0);
+    for (key, value) in auxv {
+        words.push(key);
+        words.push(value);
+    }
+    words.extend_from_slice(&[AT_NULL, 0]);
+
+    let size = (words.len() * 8) as u64;
+    let sp = top
+        .checked_sub(size)
+        .map(|sp| sp & !0xf)
+        .filter(|&sp| sp >= stack_bottom)
+        .ok_or(LoadError::ArgumentsTooLarge)?;
+    let bytes: Vec<u8> = words.iter().flat_map(|word| word.to_le_bytes()).collect();
+    address_space.write(VirtAddr::new(sp), &bytes)?;
+    Ok(VirtAddr::new(sp))
+}
+
+/// 让当前线程运行 `program`, 不返回
+///
+/// - 地址空间交给当前线程, 线程之后的每次切换都会加载它的页表
+pub fn exec(program: Program) -> ! {
+    unsafe {
+        thread::set_page_table(program.address_space.page_table());
+        syscall::enter_user_mode(program.entry, program.stack_pointer)
+    }
+}
@@ -20,6 +20,9 @@ fn kernel_main(boot_info: &'static BootInfo) -> ! {
     // 内核初始化
     rust_os::init(boot_info);
 
+    let cpu = rust_os::cpu::info();
+    println!("CPU: {} {}", cpu.vendor(), cpu.brand());
+
     // 调试中断
     // x86_64::instructions::interrupts::int3();
 
@@ -35,7 +38,8 @@ fn kernel_main(boot_info: &'static BootInfo) -> ! {
     // }
     // stack_overflow();
 
-    // 死锁
+    // 死锁: 输出锁现在是 IrqSafeMutex, 不会再被时钟中断打断;
+    // 调试构建中在中断处理里重复获取持有的锁会由 sync::lockdep 报告
     // loop {
     //     use rust_os::print;
 
@@ -83,15 +87,34 @@ fn kernel_main(boot_info: &'static BootInfo) -> ! {
     // 创建一个新的映射
     // test_create_new_map(boot_info);
 
+    // 中断统计
+    // println!("{}", rust_os::interrupts::stats::report());
+
     // try execute async tasks
     // async print_key_presses
+    // 执行器运行在单独的内核线程中, 由时钟中断和其他线程轮转
     use rust_os::task::executor::Executor;
-    use rust_os::task::{keyboard, Task};
-
-    let mut executor = Executor::new();
-    executor.spawn(Task::new(example_task()));
-    executor.spawn(Task::new(keyboard::print_key_presses()));
-    executor.run();
+    use rust_os::task::{keyboard, Priority};
+    use rust_os::thread;
+
+    // 任务 5 秒不返回, 或 BSP 5 秒没有时钟中断时打印现场并 panic.
+    // 调试器暂停也会触发, 调试时注释掉
+    rust_os::watchdog::enable(5000, rust_os::watchdog::Action::Panic);
+
+    let executor_thread = thread::spawn_thread(|| {
+        let mut executor = Executor::new();
+        executor
+            .spawn_named("example", example_task(), Priority::Normal)
+            .expect("failed to spawn example task");
+        // 键盘输入优先于其他任务
+        executor
+            .spawn_named("keyboard", keyboard::print_key_presses(), Priority::High)
+            .expect("failed to spawn keyboard task");
+        // 打印每次轮询的结果和耗时
+        // executor.set_tracing(true);
+        executor.run();
+    });
+    thread::join(executor_thread).expect("failed to join executor thread");
 
     #[allow(unreachable_code)]
     #[cfg(test)]
@@ -106,6 +129,7 @@ fn kernel_main(boot_info: &'static BootInfo) -> ! {
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
     println!("{}", info);
+    println!("{}", rust_os::backtrace::Backtrace::capture());
     rust_os::hlt_loop();
 }
 
@@ -1,9 +1,225 @@
 use bootloader::bootinfo::{MemoryMap, MemoryRegionType};
+use bootloader::BootInfo;
+use core::sync::atomic::{AtomicU64, Ordering};
+use x86_64::structures::paging::mapper::MapToError;
+use x86_64::structures::paging::page::PageRange;
 use x86_64::structures::paging::{
-    FrameAllocator, Mapper, OffsetPageTable, Page, PageTable, PhysFrame, Size4KiB,
+    FrameAllocator, FrameDeallocator, Mapper, OffsetPageTable, Page, PageTable, PageTableFlags,
+    PhysFrame, Size4KiB,
 };
 use x86_64::{PhysAddr, VirtAddr};
 
+/// 内核全局的页表和帧分配器, 由 [`init_kernel_memory`] 初始化
+static KERNEL_MEMORY: spin::Mutex<Option<KernelMemory>> = spin::Mutex::new(None);
+
+/// 全部物理内存在虚拟地址空间中的映射起点
+static PHYSICAL_MEMORY_OFFSET: AtomicU64 = AtomicU64::new(0);
+
+/// 内核页表(4级页表)的物理地址, 即引导程序交给内核时 CR3 中的页表
+static KERNEL_PAGE_TABLE: AtomicU64 = AtomicU64::new(0);
+
+/// 低于 1 MiB 的帧不交给帧分配器, 留给只能使用实模式地址的代码(如 AP 启动代码)
+pub const LOW_MEMORY_END: u64 = 0x10_0000;
+
+struct KernelMemory {
+    mapper: OffsetPageTable<'static>,
+    frame_allocator: BootInfoFrameAllocator,
+}
+
+/// 初始化内核全局的页表和帧分配器
+/// # Safety
+/// 调用者必须保证 `physical_memory_offset` 处映射了全部物理内存, 且 `memory_map` 中标记为
+/// 可用的帧确实未被使用. 这个函数只能调用一次.
+pub unsafe fn init_kernel_memory(physical_memory_offset: VirtAddr, memory_map: &'static MemoryMap) {
+    let mut memory = KERNEL_MEMORY.lock();
+    assert!(memory.is_none(), "kernel memory already initialized");
+    PHYSICAL_MEMORY_OFFSET.store(physical_memory_offset.as_u64(), Ordering::Relaxed);
+    let (kernel_page_table, _) = x86_64::registers::control::Cr3::read();
+    KERNEL_PAGE_TABLE.store(
+        kernel_page_table.start_address().as_u64(),
+        Ordering::Relaxed,
+    );
+    *memory = Some(KernelMemory {
+        mapper: init(physical_memory_offset),
+        frame_allocator: BootInfoFrameAllocator::init(memory_map),
+    });
+}
+
+/// 借用内核全局的页表和帧分配器
+///
+/// - 持锁期间关闭中断, 避免和中断处理中的调用互相等待
+pub fn with_kernel_memory<R>(
+    f: impl FnOnce(&mut OffsetPageTable<'static>, &mut BootInfoFrameAllocator) -> R,
+) -> R {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let mut memory = KERNEL_MEMORY.lock();
+        let memory = memory.as_mut().expect("kernel memory not initialized");
+        f(&mut memory.mapper, &mut memory.frame_allocator)
+    })
+}
+
+/// 返回物理地址 `addr` 在物理内存映射中的虚拟地址
+pub fn phys_to_virt(addr: PhysAddr) -> VirtAddr {
+    VirtAddr::new(PHYSICAL_MEMORY_OFFSET.load(Ordering::Relaxed) + addr.as_u64())
+}
+
+/// 内核页表所在的帧, 内核线程和内核自身的映射都使用这个页表
+pub fn kernel_page_table() -> PhysFrame {
+    PhysFrame::containing_address(PhysAddr::new(KERNEL_PAGE_TABLE.load(Ordering::Relaxed)))
+}
+
+/// 内核的帧分配器还可以分配的帧数
+pub fn free_frames() -> usize {
+    with_kernel_memory(|_, frame_allocator| frame_allocator.free_frames())
+}
+
+/// 返回一个 1 MiB 以下的可用帧, 不存在时返回 `None`
+///
+/// - 这些帧不会被帧分配器分配, 目前只有 AP 启动代码使用, 所以总是返回同一个帧
+pub fn low_memory_frame() -> Option<PhysFrame> {
+    with_kernel_memory(|_, frame_allocator| frame_allocator.low_memory_frame())
+}
+
+/// 内核栈所在的虚拟地址区域, 独占4级页表项 170(512 GiB)
+pub const KERNEL_STACKS_START: u64 = 170 << 39;
+pub const KERNEL_STACKS_SIZE: u64 = 512 * 1024 * 1024 * 1024;
+
+/// 一段内核栈的地址范围 `[start, end)`, 栈从 `end` 向下增长
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StackBounds {
+    start: VirtAddr,
+    end: VirtAddr,
+}
+
+impl StackBounds {
+    pub fn new(start: VirtAddr, end: VirtAddr) -> Self {
+        StackBounds { start, end }
+    }
+
+    pub fn start(&self) -> VirtAddr {
+        self.start
+    }
+
+    pub fn end(&self) -> VirtAddr {
+        self.end
+    }
+
+    /// 栈下方不做映射的保护页, 栈溢出时访问它会触发 page fault
+    pub fn guard_page(&self) -> Page {
+        Page::containing_address(self.start - 1u64)
+    }
+
+    pub fn contains(&self, addr: VirtAddr) -> bool {
+        self.start <= addr && addr < self.end
+    }
+}
+
+/// 引导程序提供的栈的页数, bootloader 0.9 的默认值
+const BOOT_STACK_PAGES: u64 = 512;
+
+/// 引导程序提供的栈, 即 0 号线程的栈
+///
+/// - 没有配置 `kernel-stack-address` 时, bootloader 0.9 把栈放在 `BootInfo` 所在页之后,
+///   中间隔一页保护页
+pub fn boot_stack(boot_info: &'static BootInfo) -> StackBounds {
+    let boot_info_page = VirtAddr::from_ptr(boot_info).align_down(Page::<Size4KiB>::SIZE);
+    let start = boot_info_page + 2 * Page::<Size4KiB>::SIZE;
+    StackBounds::new(start, start + BOOT_STACK_PAGES * Page::<Size4KiB>::SIZE)
+}
+
+/// 分配一段 `pages` 页大小的内核栈
+///
+/// - 每段栈的下方都留出一页不映射的保护页, 溢出时触发 page fault 而不是悄悄覆盖相邻的内存
+/// - 虚拟地址只增不减, 物理帧来自全局帧分配器
+/// - 中途失败时撤销已经建立的映射并归还帧, 只浪费这段虚拟地址
+pub fn alloc_kernel_stack(pages: u64) -> Result<StackBounds, MapToError<Size4KiB>> {
+    static NEXT_STACK: AtomicU64 = AtomicU64::new(KERNEL_STACKS_START);
+
+    let reserved = (pages + 1) * Page::<Size4KiB>::SIZE;
+    let guard = NEXT_STACK.fetch_add(reserved, Ordering::Relaxed);
+    assert!(
+        guard + reserved <= KERNEL_STACKS_START + KERNEL_STACKS_SIZE,
+        "kernel stack region exhausted"
+    );
+
+    let start = VirtAddr::new(guard + Page::<Size4KiB>::SIZE);
+    let end = start + pages * Page::<Size4KiB>::SIZE;
+    let page_range = Page::range(
+        Page::containing_address(start),
+        Page::containing_address(end),
+    );
+
+    with_kernel_memory(|mapper, frame_allocator| {
+        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+        for page in page_range {
+            let result = match frame_allocator.allocate_frame() {
+                Some(frame) => unsafe {
+                    mapper
+                        .map_to(page, frame, flags, frame_allocator)
+                        .map(|flush| flush.flush())
+                        .inspect_err(|_| frame_allocator.deallocate_frame(frame))
+                },
+                None => Err(MapToError::FrameAllocationFailed),
+            };
+            if let Err(err) = result {
+                unmap_and_free(mapper, frame_allocator, Page::range(page_range.start, page));
+                return Err(err);
+            }
+        }
+        Ok(())
+    })?;
+
+    Ok(StackBounds { start, end })
+}
+
+/// 撤销 `pages` 的映射并归还它们的帧
+fn unmap_and_free(
+    mapper: &mut OffsetPageTable,
+    frame_allocator: &mut BootInfoFrameAllocator,
+    pages: PageRange,
+) {
+    for page in pages {
+        if let Ok((frame, flush)) = mapper.unmap(page) {
+            flush.flush();
+            unsafe { frame_allocator.deallocate_frame(frame) };
+        }
+    }
+}
+
+/// 设备寄存器(MMIO)映射所在的虚拟地址区域, 独占4级页表项 171(512 GiB)
+pub const KERNEL_MMIO_START: u64 = 171 << 39;
+pub const KERNEL_MMIO_SIZE: u64 = 512 * 1024 * 1024 * 1024;
+
+/// 把从 `phys` 开始的 `size` 字节设备寄存器映射到内核地址空间, 返回对应的虚拟地址
+///
+/// - 映射禁用缓存, 保证每次读写都真正到达设备
+/// - 和内核栈一样, 虚拟地址只增不减
+pub fn map_mmio(phys: PhysAddr, size: u64) -> Result<VirtAddr, MapToError<Size4KiB>> {
+    static NEXT_MMIO: AtomicU64 = AtomicU64::new(KERNEL_MMIO_START);
+
+    let first = PhysFrame::<Size4KiB>::containing_address(phys);
+    let last = PhysFrame::<Size4KiB>::containing_address(phys + size.max(1) - 1u64);
+    let frames = PhysFrame::range_inclusive(first, last);
+    let reserved = (frames.count() as u64) * Page::<Size4KiB>::SIZE;
+    let start = NEXT_MMIO.fetch_add(reserved, Ordering::Relaxed);
+    assert!(
+        start + reserved <= KERNEL_MMIO_START + KERNEL_MMIO_SIZE,
+        "kernel MMIO region exhausted"
+    );
+
+    with_kernel_memory(|mapper, frame_allocator| {
+        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_CACHE;
+        for (i, frame) in frames.enumerate() {
+            let page =
+                Page::containing_address(VirtAddr::new(start) + i as u64 * Page::<Size4KiB>::SIZE);
+            unsafe { mapper.map_to(page, frame, flags, frame_allocator)?.flush() };
+        }
+        Ok::<(), MapToError<Size4KiB>>(())
+    })?;
+
+    Ok(VirtAddr::new(start) + (phys - first.start_address()))
+}
+
 /// # Safety
 pub unsafe fn init(physical_memory_offset: VirtAddr) -> OffsetPageTable<'static> {
     let level_4_table = active_level_4_table(physical_memory_offset);
@@ -91,6 +307,11 @@ unsafe impl FrameAllocator<Size4KiB> for EmptyFrameAllocator {
 pub struct BootInfoFrameAllocator {
     memory_map: &'static MemoryMap,
     next: usize,
+    /// 内存地图中可用的帧数
+    usable: usize,
+    /// 释放的帧组成的链表, 下一个帧的物理地址存放在帧自身的前 8 字节中, 0 表示链表结束
+    free_list: Option<PhysFrame>,
+    free_list_len: usize,
 }
 
 impl BootInfoFrameAllocator {
@@ -98,10 +319,20 @@ impl BootInfoFrameAllocator {
     /// # Safety
     /// 这个函数是不安全的, 因为调用者必须保证传递的内存 map 是有效的, 主要的要求是，所有在其中被标记为 "可用 "的帧都是真正未使用的
     pub unsafe fn init(memory_map: &'static MemoryMap) -> Self {
-        BootInfoFrameAllocator {
+        let mut allocator = BootInfoFrameAllocator {
             memory_map,
             next: 0,
-        }
+            usable: 0,
+            free_list: None,
+            free_list_len: 0,
+        };
+        allocator.usable = allocator.usable_frames().count();
+        allocator
+    }
+
+    /// 还可以分配的帧数: 内存地图中还没有取出的帧, 加上释放后等待复用的帧
+    pub fn free_frames(&self) -> usize {
+        self.usable.saturating_sub(self.next) + self.free_list_len
     }
 
     /// 返回内存映射中指定的可用框架的迭代器。
@@ -111,17 +342,72 @@ impl BootInfoFrameAllocator {
         let usable_regions = regions.filter(|r| r.region_type == MemoryRegionType::Usable);
         // 将每个区域映射到其地址范围
         let addr_ranges = usable_regions.map(|r| r.range.start_addr()..r.range.end_addr());
-        // 转化为一个帧起始地址的迭代器
-        let frame_addresses = addr_ranges.flat_map(|r| r.step_by(4096));
+        // 转化为一个帧起始地址的迭代器, 跳过 1 MiB 以下的帧
+        let frame_addresses = addr_ranges
+            .flat_map(|r| r.step_by(4096))
+            .filter(|addr| *addr >= LOW_MEMORY_END);
         // 从起始地址创建 `PhysFrame`  类型
         frame_addresses.map(|addr| PhysFrame::containing_address(PhysAddr::new(addr)))
     }
 }
 
+impl BootInfoFrameAllocator {
+    /// 返回第一个 1 MiB 以下的可用帧, 0 号帧(实模式中断向量表和 BIOS 数据区)除外
+    fn low_memory_frame(&self) -> Option<PhysFrame> {
+        self.memory_map
+            .iter()
+            .filter(|r| r.region_type == MemoryRegionType::Usable)
+            .flat_map(|r| (r.range.start_addr()..r.range.end_addr()).step_by(4096))
+            .find(|addr| *addr != 0 && *addr < LOW_MEMORY_END)
+            .map(|addr| PhysFrame::containing_address(PhysAddr::new(addr)))
+    }
+}
+
+/// 先复用释放的帧, 没有时再从内存地图中取新的帧
 unsafe impl FrameAllocator<Size4KiB> for BootInfoFrameAllocator {
     fn allocate_frame(&mut self) -> Option<PhysFrame> {
+        if let Some(frame) = self.free_list {
+            let next = unsafe { phys_to_virt(frame.start_address()).as_ptr::<u64>().read() };
+            // 1 MiB 以下的帧不会被分配, 所以 0 不是有效的帧地址
+            self.free_list =
+                (next != 0).then(|| PhysFrame::containing_address(PhysAddr::new(next)));
+            self.free_list_len -= 1;
+            return Some(frame);
+        }
         let frame = self.usable_frames().nth(self.next);
         self.next += 1;
         frame
     }
 }
+
+impl FrameDeallocator<Size4KiB> for BootInfoFrameAllocator {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame) {
+        let next = self
+            .free_list
+            .map_or(0, |frame| frame.start_address().as_u64());
+        phys_to_virt(frame.start_address())
+            .as_mut_ptr::<u64>()
+            .write(next);
+        self.free_list = Some(frame);
+        self.free_list_len += 1;
+    }
+}
+
+#[test_case]
+fn test_kernel_regions_own_l4_entries() {
+    for (start, size) in [
+        (KERNEL_STACKS_START, KERNEL_STACKS_SIZE),
+        (KERNEL_MMIO_START, KERNEL_MMIO_SIZE),
+    ] {
+        // 一个4级页表项覆盖 512 GiB
+        assert_eq!(start % (1 << 39), 0);
+        assert_eq!(
+            VirtAddr::new(start).p4_index(),
+            VirtAddr::new(start + size - 1).p4_index()
+        );
+    }
+    assert_ne!(
+        VirtAddr::new(KERNEL_STACKS_START).p4_index(),
+        VirtAddr::new(KERNEL_MMIO_START).p4_index()
+    );
+}
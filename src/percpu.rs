@@ -0,0 +1,158 @@
+//! 每个 CPU 私有的数据
+//!
+//! - 每个 CPU 有一个 [`PerCpu`], 它的地址写在该 CPU 的 `IA32_GS_BASE` 中, 通过 `gs:[0]`
+//!   一条指令就能取到当前 CPU 的数据, 不需要加锁, 也不需要查询 local APIC ID
+//! - 用 [`percpu!`](crate::percpu!) 访问当前 CPU 的字段, 得到的是 `&'static` 引用
+//! - 字段都是原子类型或自带锁, 线程被换到其他 CPU 后继续使用旧引用只会读到别的 CPU 的值,
+//!   不会造成未定义行为. 目前线程不会在 CPU 之间迁移
+//! - 进入用户态之前需要用 `swapgs` 把内核的 GS 基址换到 `IA32_KERNEL_GS_BASE` 中
+
+use crate::memory::StackBounds;
+use crate::smp::MAX_CPUS;
+#[cfg(debug_assertions)]
+use crate::sync::lockdep::HeldLocks;
+use crate::task::poll_state::PollState;
+use crate::thread::ThreadId;
+use alloc::collections::VecDeque;
+use core::arch::asm;
+use core::mem::offset_of;
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use spin::Mutex;
+use x86_64::registers::model_specific::GsBase;
+use x86_64::VirtAddr;
+
+#[repr(C)]
+pub struct PerCpu {
+    /// 自身的地址, 必须是第一个字段, [`current`] 通过 `gs:[0]` 读取它
+    self_addr: AtomicUsize,
+    /// 当前线程的内核栈顶, `SYSCALL` 入口切换到这个栈, 见 [`crate::gdt::set_kernel_stack`]
+    pub kernel_stack: AtomicU64,
+    /// `SYSCALL` 入口暂存用户栈指针的位置
+    user_rsp: AtomicU64,
+    /// CPU 编号, BSP 为 0
+    pub cpu_id: AtomicUsize,
+    /// 正在这个 CPU 上运行的线程的编号
+    pub current_thread: AtomicU64,
+    /// 当前执行流所在的栈, 见 [`PerCpu::stack`]
+    stack_start: AtomicU64,
+    stack_end: AtomicU64,
+    /// 这个 CPU 的可运行线程队列, 只在关中断时访问
+    pub run_queue: Mutex<VecDeque<ThreadId>>,
+    /// 中断嵌套深度, 大于 0 表示正在处理中断或异常
+    pub irq_depth: AtomicUsize,
+    /// 当前线程的扩展状态保存区, 见 `thread::fpu`
+    pub fpu_current: AtomicUsize,
+    /// 扩展寄存器中的状态所属线程的保存区, 0 表示不属于任何线程
+    pub fpu_owner: AtomicUsize,
+    /// 当前线程的轮询状态, 切换线程时由调度器换出换入
+    pub(crate) poll: PollState,
+    /// 这个 CPU 持有的锁, 用于检查加锁顺序
+    #[cfg(debug_assertions)]
+    pub held_locks: HeldLocks,
+}
+
+impl PerCpu {
+    const fn new() -> Self {
+        PerCpu {
+            self_addr: AtomicUsize::new(0),
+            kernel_stack: AtomicU64::new(0),
+            user_rsp: AtomicU64::new(0),
+            cpu_id: AtomicUsize::new(0),
+            current_thread: AtomicU64::new(0),
+            stack_start: AtomicU64::new(0),
+            stack_end: AtomicU64::new(0),
+            run_queue: Mutex::new(VecDeque::new()),
+            irq_depth: AtomicUsize::new(0),
+            fpu_current: AtomicUsize::new(0),
+            fpu_owner: AtomicUsize::new(0),
+            poll: PollState::new(),
+            #[cfg(debug_assertions)]
+            held_locks: HeldLocks::new(),
+        }
+    }
+}
+
+impl PerCpu {
+    /// 当前执行流(线程, 或 AP 的启动流程)所在的栈, 栈回溯只在这个范围内沿帧指针链前进
+    ///
+    /// - 由切换线程的代码在切换栈之前更新, 未知时为 `None`
+    pub fn stack(&self) -> Option<StackBounds> {
+        let start = self.stack_start.load(Ordering::Relaxed);
+        let end = self.stack_end.load(Ordering::Relaxed);
+        (start < end).then(|| StackBounds::new(VirtAddr::new(start), VirtAddr::new(end)))
+    }
+
+    pub fn set_stack(&self, stack: Option<StackBounds>) {
+        let (start, end) = stack.map_or((0, 0), |stack| {
+            (stack.start().as_u64(), stack.end().as_u64())
+        });
+        // 先清空再写入, 中途被打断的回溯最多看到一个空范围
+        self.stack_end.store(0, Ordering::Relaxed);
+        self.stack_start.store(start, Ordering::Relaxed);
+        self.stack_end.store(end, Ordering::Relaxed);
+    }
+}
+
+/// 汇编代码通过 `gs:[偏移]` 访问的字段
+pub const KERNEL_STACK_OFFSET: usize = offset_of!(PerCpu, kernel_stack);
+pub const USER_RSP_OFFSET: usize = offset_of!(PerCpu, user_rsp);
+
+/// 所有 CPU 的数据区, 异常入口通过比较 GS 基址和它的地址范围判断当前是否是内核的 GS 基址
+pub(crate) static PERCPU: [PerCpu; MAX_CPUS] = [const { PerCpu::new() }; MAX_CPUS];
+
+/// 把编号为 `cpu` 的数据区设为当前 CPU 的数据区
+///
+/// - 每个 CPU 都要在访问 [`percpu!`](crate::percpu!) 之前由自己调用一次, BSP 在内核初始化的最开始调用
+pub fn init(cpu: usize) {
+    let percpu = &PERCPU[cpu];
+    percpu
+        .self_addr
+        .store(percpu as *const PerCpu as usize, Ordering::Relaxed);
+    percpu.cpu_id.store(cpu, Ordering::Relaxed);
+    GsBase::write(VirtAddr::from_ptr(percpu));
+}
+
+/// 返回编号为 `cpu` 的数据区, 用于在 CPU 上线之前为它准备数据
+pub fn get(cpu: usize) -> &'static PerCpu {
+    &PERCPU[cpu]
+}
+
+/// 返回当前 CPU 的数据区
+///
+/// - 当前 CPU 必须已经调用过 [`init`], 否则 GS 基址为 0, 读取 `gs:[0]` 会触发 page fault
+pub fn current() -> &'static PerCpu {
+    let addr: usize;
+    unsafe {
+        asm!("mov {}, gs:[0]", out(reg) addr, options(nostack, readonly, preserves_flags));
+        &*(addr as *const PerCpu)
+    }
+}
+
+/// 返回当前 CPU 的数据区, 当前 CPU 还没有调用 [`init`] 时返回 `None`
+///
+/// - 供加锁等在初始化之前也可能执行的代码使用, 需要读一次 MSR, 比 [`current`] 慢
+pub fn try_current() -> Option<&'static PerCpu> {
+    if GsBase::read().is_null() {
+        None
+    } else {
+        Some(current())
+    }
+}
+
+/// 访问当前 CPU 的数据区中的字段, 如 `percpu!(irq_depth)`
+#[macro_export]
+macro_rules! percpu {
+    ($field:ident) => {
+        &$crate::percpu::current().$field
+    };
+}
+
+#[test_case]
+fn test_percpu_bsp() {
+    assert_eq!(percpu!(cpu_id).load(Ordering::Relaxed), 0);
+    assert_eq!(percpu!(irq_depth).load(Ordering::Relaxed), 0);
+    assert_eq!(
+        percpu!(current_thread).load(Ordering::Relaxed),
+        crate::thread::current().as_u64()
+    );
+}
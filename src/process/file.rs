@@ -0,0 +1,162 @@
+//! 进程的打开文件表
+//!
+//! - 文件描述符是表中的下标, 新打开的文件使用最小的空闲下标
+//! - 新进程的 0, 1, 2 号描述符(标准输入, 输出, 错误输出)都指向 [`Console`]
+//! - 同一个文件可以出现在多个表项中, 用 `Arc` 共享
+//! - 在有文件系统之前, [`open`] 能打开的只有几个固定路径
+
+use super::programs;
+use crate::{print, serial_print};
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use spin::Mutex;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileError {
+    /// 描述符没有对应的打开文件
+    BadDescriptor,
+    /// 文件不支持这种操作
+    NotSupported,
+}
+
+pub trait File: Send + Sync {
+    /// 读取到 `buf` 中, 返回读到的字节数, 0 表示文件结束
+    fn read(&self, buf: &mut [u8]) -> Result<usize, FileError>;
+
+    /// 写入 `buf`, 返回写入的字节数
+    fn write(&self, buf: &[u8]) -> Result<usize, FileError>;
+}
+
+/// 控制台: 写入的内容同时输出到 VGA 文本缓冲区和串口, 读取总是返回文件结束
+pub struct Console;
+
+impl File for Console {
+    fn read(&self, _buf: &mut [u8]) -> Result<usize, FileError> {
+        Ok(0)
+    }
+
+    fn write(&self, buf: &[u8]) -> Result<usize, FileError> {
+        for chunk in buf.utf8_chunks() {
+            print!("{}", chunk.valid());
+            serial_print!("{}", chunk.valid());
+            if !chunk.invalid().is_empty() {
+                print!("\u{fffd}");
+                serial_print!("\u{fffd}");
+            }
+        }
+        Ok(buf.len())
+    }
+}
+
+/// 空设备: 读取总是返回文件结束, 写入的内容被丢弃
+pub struct Null;
+
+impl File for Null {
+    fn read(&self, _buf: &mut [u8]) -> Result<usize, FileError> {
+        Ok(0)
+    }
+
+    fn write(&self, buf: &[u8]) -> Result<usize, FileError> {
+        Ok(buf.len())
+    }
+}
+
+/// 编译进内核的用户程序映像, 只读, 从头顺序读取
+pub struct ProgramFile {
+    image: &'static [u8],
+    offset: Mutex<usize>,
+}
+
+impl File for ProgramFile {
+    fn read(&self, buf: &mut [u8]) -> Result<usize, FileError> {
+        let mut offset = self.offset.lock();
+        let rest = &self.image[*offset..];
+        let len = rest.len().min(buf.len());
+        buf[..len].copy_from_slice(&rest[..len]);
+        *offset += len;
+        Ok(len)
+    }
+
+    fn write(&self, _buf: &[u8]) -> Result<usize, FileError> {
+        Err(FileError::NotSupported)
+    }
+}
+
+/// 按路径打开文件
+///
+/// - `/dev/console`: [`Console`]
+/// - `/dev/null`: [`Null`]
+/// - `/bin/<程序名>`: 用户程序的 ELF 映像, 见 [`programs`]
+pub fn open(path: &str) -> Option<Arc<dyn File>> {
+    match path {
+        "/dev/console" => Some(Arc::new(Console)),
+        "/dev/null" => Some(Arc::new(Null)),
+        _ => {
+            let image = programs::find(path.strip_prefix("/bin/")?)?;
+            Some(Arc::new(ProgramFile {
+                image,
+                offset: Mutex::new(0),
+            }))
+        }
+    }
+}
+
+pub type Fd = usize;
+
+#[derive(Default)]
+pub struct FileTable {
+    files: Vec<Option<Arc<dyn File>>>,
+}
+
+impl FileTable {
+    /// 创建文件表, 0, 1, 2 号描述符指向控制台
+    pub fn with_console() -> Self {
+        let console: Arc<dyn File> = Arc::new(Console);
+        FileTable {
+            files: alloc::vec![Some(console.clone()), Some(console.clone()), Some(console)],
+        }
+    }
+
+    pub fn get(&self, fd: Fd) -> Result<Arc<dyn File>, FileError> {
+        self.files
+            .get(fd)
+            .and_then(Option::clone)
+            .ok_or(FileError::BadDescriptor)
+    }
+
+    /// 加入一个打开的文件, 返回它的描述符
+    pub fn insert(&mut self, file: Arc<dyn File>) -> Fd {
+        match self.files.iter().position(Option::is_none) {
+            Some(fd) => {
+                self.files[fd] = Some(file);
+                fd
+            }
+            None => {
+                self.files.push(Some(file));
+                self.files.len() - 1
+            }
+        }
+    }
+
+    /// 关闭描述符 `fd`
+    pub fn remove(&mut self, fd: Fd) -> Result<Arc<dyn File>, FileError> {
+        self.files
+            .get_mut(fd)
+            .and_then(Option::take)
+            .ok_or(FileError::BadDescriptor)
+    }
+
+    /// 关闭所有文件
+    pub fn clear(&mut self) {
+        self.files.clear();
+    }
+
+    /// 打开的文件个数
+    pub fn len(&self) -> usize {
+        self.files.iter().flatten().count()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
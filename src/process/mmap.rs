@@ -0,0 +1,100 @@
+//! 进程通过 `mmap` 建立的匿名映射
+//!
+//! - 记录每段映射的范围, 寻找空闲地址时只需要查这张表, 不需要逐页查询页表
+//! - 限制单次映射的长度和进程映射的总量, 一个进程不能耗尽全部物理内存
+
+use alloc::collections::BTreeMap;
+
+/// 单次 `mmap` 最多映射的字节数
+pub const MAX_MMAP_LEN: u64 = 16 * 1024 * 1024;
+
+/// 每个进程最多映射的字节数
+pub const MAX_MMAP_TOTAL: u64 = 64 * 1024 * 1024;
+
+/// 按起始地址排列的映射范围 `[start, end)`, 互不重叠
+#[derive(Debug, Default)]
+pub struct MmapRegions {
+    regions: BTreeMap<u64, u64>,
+    total: u64,
+}
+
+impl MmapRegions {
+    pub const fn new() -> Self {
+        MmapRegions {
+            regions: BTreeMap::new(),
+            total: 0,
+        }
+    }
+
+    /// 已经映射的字节数
+    pub fn total(&self) -> u64 {
+        self.total
+    }
+
+    /// 再映射 `len` 字节是否会超过 [`MAX_MMAP_TOTAL`]
+    pub fn would_exceed(&self, len: u64) -> bool {
+        self.total.saturating_add(len) > MAX_MMAP_TOTAL
+    }
+
+    /// 在 `[base, limit)` 中找第一段不与已有映射重叠, 长 `len` 字节的范围
+    pub fn find_free(&self, base: u64, limit: u64, len: u64) -> Option<u64> {
+        let mut candidate = base;
+        for (&start, &end) in self.regions.range(..limit) {
+            if end <= candidate {
+                continue;
+            }
+            if start >= candidate.checked_add(len)? {
+                break;
+            }
+            candidate = end;
+        }
+        candidate
+            .checked_add(len)
+            .filter(|&end| end <= limit)
+            .map(|_| candidate)
+    }
+
+    /// 记录新的映射 `[start, start + len)`, 调用者保证它不与已有映射重叠
+    pub fn insert(&mut self, start: u64, len: u64) {
+        self.regions.insert(start, start + len);
+        self.total += len;
+    }
+
+    /// 删除 `[start, start + len)` 与已有映射重叠的部分, 部分重叠的映射被截断或拆分
+    pub fn remove(&mut self, start: u64, len: u64) {
+        let end = start.saturating_add(len);
+        let overlapping: alloc::vec::Vec<(u64, u64)> = self
+            .regions
+            .range(..end)
+            .filter(|&(_, &region_end)| region_end > start)
+            .map(|(&region_start, &region_end)| (region_start, region_end))
+            .collect();
+        for (region_start, region_end) in overlapping {
+            self.regions.remove(&region_start);
+            self.total -= region_end - region_start;
+            if region_start < start {
+                self.insert(region_start, start - region_start);
+            }
+            if region_end > end {
+                self.insert(end, region_end - end);
+            }
+        }
+    }
+}
+
+#[test_case]
+fn test_find_free_skips_regions() {
+    let mut regions = MmapRegions::new();
+    assert_eq!(regions.find_free(0x1000, 0x10000, 0x2000), Some(0x1000));
+    regions.insert(0x1000, 0x2000);
+    regions.insert(0x4000, 0x1000);
+    assert_eq!(regions.find_free(0x1000, 0x10000, 0x1000), Some(0x3000));
+    assert_eq!(regions.find_free(0x1000, 0x10000, 0x2000), Some(0x5000));
+    assert_eq!(regions.find_free(0x1000, 0x6000, 0x2000), None);
+
+    // 从中间解除映射会拆分原来的范围
+    regions.remove(0x1000, 0x1000);
+    regions.remove(0x4800, 0x100);
+    assert_eq!(regions.total(), 0x1000 + 0x800 + 0x700);
+    assert_eq!(regions.find_free(0x1000, 0x10000, 0x1000), Some(0x1000));
+}
@@ -0,0 +1,385 @@
+//! 进程
+//!
+//! - 进程是运行用户程序的单位: 有自己的进程号, 地址空间和打开文件表, 由一个内核线程执行
+//! - 进程之间有父子关系. 由内核线程(而不是进程)创建的进程没有父进程, 由内核线程负责 [`wait`]
+//! - 进程结束后成为僵尸(zombie), 保留退出状态直到被 [`wait`] 回收. 父进程先结束时子进程被
+//!   托管给内核, 之后结束时立即从进程表中移除
+//! - 进程的地址空间在它的线程结束并切换离开之后才释放: [`wait`] 先 join 线程再丢弃进程,
+//!   不经过 `wait` 移除的进程留到下一次 `wait` 时再 join 和释放
+//! - [`kill`] 只做标记, 目标进程在下一次从内核返回用户态时结束; 阻塞在 [`wait`] 中的进程会被
+//!   唤醒. 用户态触发的异常也以同样的方式结束进程
+//! - 进程表和调度器一样只在关中断时访问. 加锁顺序是先进程表, 后调度器
+
+pub mod file;
+pub mod mmap;
+pub mod programs;
+
+use crate::address_space::AddressSpace;
+use crate::loader::{self, LoadError, Program};
+use crate::syscall;
+use crate::thread::{self, ThreadId};
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU64, Ordering};
+use file::FileTable;
+use mmap::MmapRegions;
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+static PROCESSES: Mutex<ProcessTable> = Mutex::new(ProcessTable::new());
+
+#[derive(Debug, Clone, Copy, PartialEq, PartialOrd, Eq, Ord, Hash)]
+pub struct Pid(u64);
+
+impl Pid {
+    fn new() -> Self {
+        static NEXT_PID: AtomicU64 = AtomicU64::new(1);
+        Pid(NEXT_PID.fetch_add(1, Ordering::Relaxed))
+    }
+
+    pub fn from_u64(pid: u64) -> Self {
+        Pid(pid)
+    }
+
+    pub fn as_u64(&self) -> u64 {
+        self.0
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExitStatus {
+    /// 调用 exit 结束, 带退出码
+    Exited(i32),
+    /// 被 [`kill`] 结束, 或在用户态触发了异常
+    Killed,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProcessState {
+    Running,
+    /// 在 [`wait`] 中等待子进程结束
+    Blocked,
+    /// 已结束, 等待父进程回收
+    Zombie(ExitStatus),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProcessError {
+    /// 进程不存在或已经结束
+    NoSuchProcess,
+    /// 没有符合条件的子进程
+    NoChild,
+    /// 等待时当前进程被 kill
+    Interrupted,
+}
+
+/// 进程的公开信息, 见 [`info`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ProcessInfo {
+    pub pid: Pid,
+    pub parent: Option<Pid>,
+    pub state: ProcessState,
+    pub thread: ThreadId,
+}
+
+pub struct Process {
+    pid: Pid,
+    parent: Option<Pid>,
+    state: ProcessState,
+    /// 执行这个进程的线程
+    thread: ThreadId,
+    address_space: AddressSpace,
+    files: FileTable,
+    /// 通过 `mmap` 建立的映射
+    mmaps: MmapRegions,
+    /// 已被 kill, 返回用户态前结束
+    killed: bool,
+    /// 父进程已结束, 自己结束时立即回收
+    detached: bool,
+}
+
+impl Process {
+    pub fn pid(&self) -> Pid {
+        self.pid
+    }
+
+    pub fn parent(&self) -> Option<Pid> {
+        self.parent
+    }
+
+    pub fn address_space(&mut self) -> &mut AddressSpace {
+        &mut self.address_space
+    }
+
+    pub fn files(&mut self) -> &mut FileTable {
+        &mut self.files
+    }
+
+    /// 地址空间和其中通过 `mmap` 建立的映射
+    pub fn memory(&mut self) -> (&mut AddressSpace, &mut MmapRegions) {
+        (&mut self.address_space, &mut self.mmaps)
+    }
+
+    fn info(&self) -> ProcessInfo {
+        ProcessInfo {
+            pid: self.pid,
+            parent: self.parent,
+            state: self.state,
+            thread: self.thread,
+        }
+    }
+}
+
+struct ProcessTable {
+    processes: BTreeMap<Pid, Process>,
+    /// 线程到它所执行的进程, 进程结束时删除
+    threads: BTreeMap<ThreadId, Pid>,
+    /// 阻塞在 [`wait`] 中的线程, 任何进程结束时全部唤醒, 由它们自己重新检查
+    waiters: Vec<ThreadId>,
+    /// 不经过 [`wait`] 就被移除的进程, 下一次 `wait` 时 join 它们的线程, 再释放线程记录和地址空间
+    unjoined: Vec<Process>,
+}
+
+impl ProcessTable {
+    const fn new() -> Self {
+        ProcessTable {
+            processes: BTreeMap::new(),
+            threads: BTreeMap::new(),
+            waiters: Vec::new(),
+            unjoined: Vec::new(),
+        }
+    }
+
+    fn current_pid(&self) -> Option<Pid> {
+        self.threads.get(&thread::current()).copied()
+    }
+
+    fn current_mut(&mut self) -> Option<&mut Process> {
+        let pid = self.current_pid()?;
+        self.processes.get_mut(&pid)
+    }
+
+    /// 把进程 `pid` 变为僵尸, 关闭它的文件, 托管它的子进程, 唤醒等待者
+    fn exit(&mut self, pid: Pid, status: ExitStatus) {
+        let process = self
+            .processes
+            .get_mut(&pid)
+            .expect("exiting process missing");
+        process.state = ProcessState::Zombie(status);
+        process.files.clear();
+        let (thread, detached) = (process.thread, process.detached);
+        self.threads.remove(&thread);
+        // 当前线程还在使用这个地址空间, 不能在这里释放
+        if detached {
+            let process = self
+                .processes
+                .remove(&pid)
+                .expect("exiting process missing");
+            self.unjoined.push(process);
+        }
+
+        let mut zombies = Vec::new();
+        for child in self.processes.values_mut() {
+            if child.parent != Some(pid) {
+                continue;
+            }
+            child.parent = None;
+            child.detached = true;
+            if matches!(child.state, ProcessState::Zombie(_)) {
+                zombies.push(child.pid);
+            }
+        }
+        for zombie in zombies {
+            let child = self.processes.remove(&zombie).expect("zombie missing");
+            self.unjoined.push(child);
+        }
+
+        for waiter in core::mem::take(&mut self.waiters) {
+            thread::unpark(waiter);
+        }
+    }
+}
+
+/// 在关中断的状态下访问进程表
+fn with_table<R>(f: impl FnOnce(&mut ProcessTable) -> R) -> R {
+    interrupts::without_interrupts(|| f(&mut PROCESSES.lock()))
+}
+
+/// 加载 ELF 映像 `image` 并创建进程开始执行, 当前进程(如果有)成为它的父进程
+pub fn spawn(image: &[u8], argv: &[&str], envp: &[&str]) -> Result<Pid, LoadError> {
+    let Program {
+        address_space,
+        entry,
+        stack_pointer,
+    } = loader::load(image, argv, envp)?;
+    let page_table = address_space.page_table();
+
+    // 持有进程表时创建线程: 关中断期间新线程不会运行, 它运行时一定能在进程表中找到自己
+    Ok(with_table(|table| {
+        let pid = Pid::new();
+        let thread = thread::spawn_thread(move || unsafe {
+            thread::set_page_table(page_table);
+            syscall::enter_user_mode(entry, stack_pointer)
+        });
+        let process = Process {
+            pid,
+            parent: table.current_pid(),
+            state: ProcessState::Running,
+            thread,
+            address_space,
+            files: FileTable::with_console(),
+            mmaps: MmapRegions::new(),
+            killed: false,
+            detached: false,
+        };
+        table.processes.insert(pid, process);
+        table.threads.insert(thread, pid);
+        pid
+    }))
+}
+
+/// 结束当前进程, 不返回
+///
+/// - 必须在进程的线程中调用
+pub fn exit(status: ExitStatus) -> ! {
+    with_table(|table| {
+        let pid = table
+            .current_pid()
+            .expect("exit called outside of a process");
+        table.exit(pid, status);
+    });
+    thread::exit();
+}
+
+/// 等待一个子进程结束并回收它, 返回它的进程号和退出状态
+///
+/// - `target` 为 `None` 时等待任意一个子进程
+/// - 在内核线程中调用时, 等待的是没有父进程的进程
+pub fn wait(target: Option<Pid>) -> Result<(Pid, ExitStatus), ProcessError> {
+    // 这些线程已经或即将结束, join 不会等待太久
+    for process in with_table(|table| core::mem::take(&mut table.unjoined)) {
+        let _ = thread::join(process.thread);
+    }
+    loop {
+        let result = with_table(|table| {
+            let current = table.current_pid();
+            if table.current_mut().is_some_and(|process| process.killed) {
+                return Some(Err(ProcessError::Interrupted));
+            }
+
+            let mut any = false;
+            let mut zombie = None;
+            let children = table.processes.values().filter(|process| {
+                process.parent == current
+                    && !process.detached
+                    && target.map_or(true, |pid| pid == process.pid)
+            });
+            for child in children {
+                any = true;
+                if let ProcessState::Zombie(status) = child.state {
+                    zombie = Some((child.pid, status));
+                    break;
+                }
+            }
+
+            let result = match zombie {
+                Some((pid, status)) => {
+                    let process = table.processes.remove(&pid).expect("zombie missing");
+                    Some(Ok((process, status)))
+                }
+                None if !any => Some(Err(ProcessError::NoChild)),
+                None => {
+                    table.waiters.push(thread::current());
+                    None
+                }
+            };
+            if let Some(process) = table.current_mut() {
+                process.state = match result {
+                    Some(_) => ProcessState::Running,
+                    None => ProcessState::Blocked,
+                };
+            }
+            result
+        });
+
+        match result {
+            Some(Ok((process, status))) => {
+                // 进程的线程可能还没有切换离开, 等它结束后再释放它的线程记录和地址空间
+                let _ = thread::join(process.thread);
+                return Ok((process.pid, status));
+            }
+            Some(Err(err)) => return Err(err),
+            None => thread::park(),
+        }
+    }
+}
+
+/// 结束进程 `pid`
+///
+/// - 目标是当前进程时立即结束, 不返回; 否则在它下一次返回用户态时结束
+pub fn kill(pid: Pid) -> Result<(), ProcessError> {
+    let is_current = with_table(|table| {
+        let current = table.current_pid();
+        let process = table
+            .processes
+            .get_mut(&pid)
+            .filter(|process| !matches!(process.state, ProcessState::Zombie(_)))
+            .ok_or(ProcessError::NoSuchProcess)?;
+        process.killed = true;
+        thread::unpark(process.thread);
+        Ok(current == Some(pid))
+    })?;
+    if is_current {
+        exit(ExitStatus::Killed);
+    }
+    Ok(())
+}
+
+/// 当前线程所执行的进程
+pub fn current() -> Option<Pid> {
+    with_table(|table| table.current_pid())
+}
+
+/// 在关中断的状态下访问当前进程, 当前线程不执行进程时返回 `None`
+pub fn with_current<R>(f: impl FnOnce(&mut Process) -> R) -> Option<R> {
+    with_table(|table| table.current_mut().map(f))
+}
+
+/// 进程 `pid` 的信息, 已被回收时返回 `None`
+pub fn info(pid: Pid) -> Option<ProcessInfo> {
+    with_table(|table| table.processes.get(&pid).map(Process::info))
+}
+
+/// 进程 `pid` 尚未回收的子进程
+pub fn children(pid: Pid) -> Vec<Pid> {
+    with_table(|table| {
+        table
+            .processes
+            .values()
+            .filter(|process| process.parent == Some(pid))
+            .map(|process| process.pid)
+            .collect()
+    })
+}
+
+/// 标记当前进程因用户态异常而结束, 当前线程不执行进程时返回 `false`
+///
+/// - 由异常处理调用, 进程在异常返回用户态之前由 [`exit_if_killed`] 结束
+pub(crate) fn kill_current_on_fault() -> bool {
+    with_table(|table| {
+        table
+            .current_mut()
+            .map(|process| process.killed = true)
+            .is_some()
+    })
+}
+
+/// 返回用户态之前调用: 当前进程已被 kill 时结束它
+///
+/// - 调用者不能持有任何锁, 且已经退出中断嵌套
+pub(crate) fn exit_if_killed() {
+    let killed = with_table(|table| table.current_mut().is_some_and(|process| process.killed));
+    if killed {
+        exit(ExitStatus::Killed);
+    }
+}
@@ -0,0 +1,19 @@
+//! 编译进内核的用户程序
+//!
+//! - `user/programs/` 下的每个程序都由构建脚本编译并登记在这里, 以文件名(不含扩展名)查找
+//! - 在有文件系统之前, 这是用户程序能 spawn 的全部程序
+
+include!(concat!(env!("OUT_DIR"), "/user_programs.rs"));
+
+/// 按名字查找程序的 ELF 映像
+pub fn find(name: &str) -> Option<&'static [u8]> {
+    USER_PROGRAMS
+        .iter()
+        .find(|(program, _)| *program == name)
+        .map(|(_, image)| *image)
+}
+
+/// 所有程序的名字
+pub fn names() -> impl Iterator<Item = &'static str> {
+    USER_PROGRAMS.iter().map(|(name, _)| *name)
+}
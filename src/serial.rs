@@ -1,26 +1,33 @@
+use crate::sync::IrqSafeMutex;
 use lazy_static::lazy_static;
-use spin::Mutex;
 use uart_16550::SerialPort;
 
 lazy_static! {
-    pub static ref SERIAL1: Mutex<SerialPort> = {
+    pub static ref SERIAL1: IrqSafeMutex<SerialPort> = {
         let mut serial_port = unsafe { SerialPort::new(0x3F8) };
         serial_port.init();
-        Mutex::new(serial_port)
+        IrqSafeMutex::new(serial_port)
     };
 }
 
 #[doc(hidden)]
 pub fn _print(args: ::core::fmt::Arguments) {
     use core::fmt::Write;
-    use x86_64::instructions::interrupts;
 
-    interrupts::without_interrupts(|| {
-        SERIAL1
-            .lock()
-            .write_fmt(args)
-            .expect("Printing to serial failed");
-    });
+    SERIAL1
+        .lock()
+        .write_fmt(args)
+        .expect("Printing to serial failed");
+}
+
+/// 不获取 `SERIAL1` 直接写串口, 用于锁可能被卡住的代码持有时的报告
+#[doc(hidden)]
+pub fn _print_unlocked(args: ::core::fmt::Arguments) {
+    use core::fmt::Write;
+
+    // 端口已经由 SERIAL1 初始化过
+    let mut serial_port = unsafe { SerialPort::new(0x3F8) };
+    let _ = serial_port.write_fmt(args);
 }
 
 /// Prints to the host through the serial interface.
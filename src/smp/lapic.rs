@@ -0,0 +1,138 @@
+//! 本地 APIC(local APIC)
+//!
+//! - 每个 CPU 都有自己的 local APIC, 寄存器映射在同一个物理地址上, 各自访问的是自己的那份
+//! - 用来发送处理器间中断(IPI): 启动其他 CPU, 以及看门狗的 NMI; 外部中断仍然经由 8259 PIC 送到 BSP
+//! - 定时器只有看门狗的监视者使用, 用来定期把它从 `hlt` 中唤醒
+
+use crate::memory;
+use core::sync::atomic::{AtomicU64, Ordering};
+use x86_64::structures::paging::mapper::MapToError;
+use x86_64::structures::paging::Size4KiB;
+use x86_64::PhysAddr;
+
+/// local APIC 的虚假中断向量, 低 4 位必须全为 1
+pub const SPURIOUS_VECTOR: u8 = 0xFF;
+/// local APIC 定时器的中断向量
+pub const TIMER_VECTOR: u8 = 0xF0;
+
+/// 寄存器相对基址的偏移
+const REG_ID: u64 = 0x20;
+const REG_EOI: u64 = 0xB0;
+const REG_SPURIOUS: u64 = 0xF0;
+const REG_ERROR_STATUS: u64 = 0x280;
+const REG_ICR_LOW: u64 = 0x300;
+const REG_ICR_HIGH: u64 = 0x310;
+const REG_LVT_TIMER: u64 = 0x320;
+const REG_TIMER_INITIAL: u64 = 0x380;
+const REG_TIMER_CURRENT: u64 = 0x390;
+const REG_TIMER_DIVIDE: u64 = 0x3E0;
+/// 寄存器区域的大小
+const REGS_SIZE: u64 = 0x400;
+
+/// 虚假中断向量寄存器中的软件使能位
+const SPURIOUS_APIC_ENABLE: u32 = 1 << 8;
+/// ICR 的投递模式和标志
+const ICR_NMI: u32 = 0b100 << 8;
+const ICR_INIT: u32 = 0b101 << 8;
+const ICR_STARTUP: u32 = 0b110 << 8;
+const ICR_LEVEL_ASSERT: u32 = 1 << 14;
+const ICR_DELIVERY_PENDING: u32 = 1 << 12;
+/// 定时器 LVT 的标志
+const LVT_MASKED: u32 = 1 << 16;
+const LVT_TIMER_PERIODIC: u32 = 1 << 17;
+/// 定时器按总线时钟的 1/16 计数
+const TIMER_DIVIDE_BY_16: u32 = 0b0011;
+
+/// 寄存器映射后的虚拟地址, 为 0 表示尚未初始化
+static BASE: AtomicU64 = AtomicU64::new(0);
+
+/// 映射位于 `phys` 的 local APIC 寄存器, 只需要在 BSP 上调用一次
+pub fn init(phys: PhysAddr) -> Result<(), MapToError<Size4KiB>> {
+    let base = memory::map_mmio(phys, REGS_SIZE)?;
+    BASE.store(base.as_u64(), Ordering::Release);
+    Ok(())
+}
+
+/// 是否已经调用过 [`init`]
+pub fn is_initialized() -> bool {
+    BASE.load(Ordering::Acquire) != 0
+}
+
+fn base() -> u64 {
+    let base = BASE.load(Ordering::Acquire);
+    assert_ne!(base, 0, "local APIC not initialized");
+    base
+}
+
+fn read(reg: u64) -> u32 {
+    unsafe { core::ptr::read_volatile((base() + reg) as *const u32) }
+}
+
+fn write(reg: u64, value: u32) {
+    unsafe { core::ptr::write_volatile((base() + reg) as *mut u32, value) }
+}
+
+/// 软件使能当前 CPU 的 local APIC, 每个 CPU 都要调用
+pub fn enable() {
+    write(
+        REG_SPURIOUS,
+        SPURIOUS_APIC_ENABLE | u32::from(SPURIOUS_VECTOR),
+    );
+}
+
+/// 当前 CPU 的 local APIC ID
+pub fn id() -> u8 {
+    (read(REG_ID) >> 24) as u8
+}
+
+/// 向 local APIC ID 为 `apic_id` 的 CPU 发送 INIT IPI, 使它进入等待 SIPI 的状态
+pub fn send_init(apic_id: u8) {
+    send_ipi(apic_id, ICR_INIT | ICR_LEVEL_ASSERT);
+}
+
+/// 向 `apic_id` 发送 STARTUP IPI, 目标 CPU 从物理地址 `page << 12` 处以实模式开始执行
+pub fn send_startup(apic_id: u8, page: u8) {
+    send_ipi(apic_id, ICR_STARTUP | ICR_LEVEL_ASSERT | u32::from(page));
+}
+
+/// 向 `apic_id` 发送 NMI
+pub fn send_nmi(apic_id: u8) {
+    send_ipi(apic_id, ICR_NMI | ICR_LEVEL_ASSERT);
+}
+
+/// 通知 local APIC 当前中断已经处理完
+pub fn eoi() {
+    write(REG_EOI, 0);
+}
+
+/// 以 `count` 为初值启动当前 CPU 的定时器, 计数减到 0 时产生 [`TIMER_VECTOR`] 中断
+///
+/// - `periodic` 为真时自动重新装载初值, 否则只触发一次
+/// - `count` 的单位与频率有关, 需要用 [`timer_current`] 对照其他时钟测量
+pub fn start_timer(count: u32, periodic: bool) {
+    write(REG_TIMER_DIVIDE, TIMER_DIVIDE_BY_16);
+    let mode = if periodic { LVT_TIMER_PERIODIC } else { 0 };
+    write(REG_LVT_TIMER, mode | u32::from(TIMER_VECTOR));
+    write(REG_TIMER_INITIAL, count);
+}
+
+/// 停止当前 CPU 的定时器
+pub fn stop_timer() {
+    write(REG_LVT_TIMER, LVT_MASKED | u32::from(TIMER_VECTOR));
+    write(REG_TIMER_INITIAL, 0);
+}
+
+/// 定时器的当前计数
+pub fn timer_current() -> u32 {
+    read(REG_TIMER_CURRENT)
+}
+
+fn send_ipi(apic_id: u8, command: u32) {
+    write(REG_ERROR_STATUS, 0);
+    write(REG_ICR_HIGH, u32::from(apic_id) << 24);
+    // 写 ICR 低 32 位时发出中断
+    write(REG_ICR_LOW, command);
+    while read(REG_ICR_LOW) & ICR_DELIVERY_PENDING != 0 {
+        core::hint::spin_loop();
+    }
+}
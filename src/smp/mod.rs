@@ -0,0 +1,173 @@
+//! 多处理器启动
+//!
+//! - 开机时只有 BSP(bootstrap processor) 在运行, 其他 CPU(AP) 停在等待 INIT 的状态
+//! - [`init`] 从 ACPI MADT 中找到所有 CPU, 依次用 INIT-SIPI-SIPI 序列唤醒它们
+//! - AP 加载自己的 GDT/TSS 和共享的 IDT 后进入空闲循环, 目前还不参与线程调度;
+//!   第一个 AP 兼作看门狗的监视者, 见 [`watchdog`](crate::watchdog)
+
+pub mod lapic;
+mod trampoline;
+
+use crate::{acpi, gdt, interrupts, memory, percpu, println, syscall, time};
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use trampoline::Trampoline;
+use x86_64::structures::paging::mapper::MapToError;
+use x86_64::structures::paging::Size4KiB;
+
+/// 支持的最大 CPU 数, 多出的 CPU 不会被启动
+pub const MAX_CPUS: usize = 16;
+
+/// 每个 AP 初始栈的页数
+const AP_STACK_PAGES: u64 = 8;
+
+/// INIT 之后, 两次 SIPI 之间, 以及等待 AP 上线的时间
+const INIT_DELAY_MS: u64 = 10;
+const STARTUP_DELAY_MS: u64 = 1;
+const ONLINE_TIMEOUT_MS: u64 = 100;
+
+/// 已上线的 CPU 数, BSP 总是在线
+static CPU_COUNT: AtomicUsize = AtomicUsize::new(1);
+
+static ONLINE: [AtomicBool; MAX_CPUS] = [const { AtomicBool::new(false) }; MAX_CPUS];
+
+#[derive(Debug)]
+pub enum SmpError {
+    /// 找不到 ACPI MADT
+    NoMadt,
+    /// 1 MiB 以下没有可以放启动代码的帧
+    NoLowMemory,
+    /// 映射 local APIC 寄存器, 启动代码或 AP 栈失败
+    Mapping(MapToError<Size4KiB>),
+    /// local APIC ID 为该值的 AP 没有在规定时间内上线
+    Timeout(u8),
+}
+
+impl From<MapToError<Size4KiB>> for SmpError {
+    fn from(err: MapToError<Size4KiB>) -> Self {
+        SmpError::Mapping(err)
+    }
+}
+
+/// 已上线的 CPU 数
+pub fn cpu_count() -> usize {
+    CPU_COUNT.load(Ordering::Acquire)
+}
+
+/// 当前 CPU 的编号, BSP 为 0, AP 按启动顺序从 1 开始编号, 没有上线的 AP 也占用一个编号
+pub fn current_cpu() -> usize {
+    percpu!(cpu_id).load(Ordering::Relaxed)
+}
+
+/// 编号为 `cpu` 的 CPU 是否已经进入 Rust 代码并完成初始化
+pub fn is_online(cpu: usize) -> bool {
+    ONLINE
+        .get(cpu)
+        .is_some_and(|online| online.load(Ordering::Acquire))
+}
+
+/// 启动所有 AP, 返回时能启动的 AP 都已上线
+///
+/// - 等待 AP 时依赖时钟中断计时, 所以必须在开中断之后调用
+/// - 某个 AP 启动失败时打印警告并继续启动其余的 AP. 超时的 AP 可能已经用它的编号加载了 TSS
+///   (TSS 描述符被标记为忙)并初始化了一部分 CPU 私有数据, 所以它的编号不再分配给其他 AP
+pub fn init() -> Result<(), SmpError> {
+    assert!(
+        x86_64::instructions::interrupts::are_enabled(),
+        "smp::init requires interrupts to be enabled"
+    );
+
+    let madt = acpi::madt().ok_or(SmpError::NoMadt)?;
+    lapic::init(madt.local_apic_address)?;
+    lapic::enable();
+    let bsp = lapic::id();
+    ONLINE[0].store(true, Ordering::Release);
+
+    let mut aps = madt
+        .apic_ids
+        .iter()
+        .copied()
+        .filter(|&id| id != bsp)
+        .take(MAX_CPUS - 1)
+        .peekable();
+    if aps.peek().is_none() {
+        return Ok(());
+    }
+
+    let frame = memory::low_memory_frame().ok_or(SmpError::NoLowMemory)?;
+    let trampoline = Trampoline::install(frame)?;
+    let mut result = Ok(());
+    for (cpu, apic_id) in (1..).zip(aps) {
+        match start_ap(&trampoline, cpu, apic_id) {
+            Ok(()) => {}
+            Err(SmpError::Timeout(apic_id)) => {
+                println!(
+                    "WARNING: CPU with local APIC ID {} did not come online",
+                    apic_id
+                );
+            }
+            Err(err) => {
+                result = Err(err);
+                break;
+            }
+        }
+    }
+    trampoline.remove();
+    result
+}
+
+/// 用 INIT-SIPI-SIPI 序列启动一个 AP, 并等待它上线
+///
+/// - 所有 AP 共用同一份启动参数, 所以只能一个一个地启动
+fn start_ap(trampoline: &Trampoline, cpu: usize, apic_id: u8) -> Result<(), SmpError> {
+    let stack = memory::alloc_kernel_stack(AP_STACK_PAGES)?;
+    percpu::get(cpu).set_stack(Some(stack));
+    trampoline.prepare(stack.end(), ap_main, cpu as u64);
+
+    lapic::send_init(apic_id);
+    delay_ms(INIT_DELAY_MS);
+    for _ in 0..2 {
+        lapic::send_startup(apic_id, trampoline.vector());
+        delay_ms(STARTUP_DELAY_MS);
+        if is_online(cpu) {
+            return Ok(());
+        }
+    }
+
+    let deadline = time::ticks() + time::ms_to_ticks(ONLINE_TIMEOUT_MS);
+    while !is_online(cpu) {
+        if time::ticks() > deadline {
+            // 让它回到等待 INIT 的状态, 以免之后用下一个 AP 的启动参数上线
+            lapic::send_init(apic_id);
+            return Err(SmpError::Timeout(apic_id));
+        }
+        x86_64::instructions::hlt();
+    }
+    Ok(())
+}
+
+/// 等待至少 `ms` 毫秒
+///
+/// - 时钟精度是一个 tick, 多等一个 tick 保证不会因为恰好跨过 tick 边界而等得太短
+fn delay_ms(ms: u64) {
+    let deadline = time::ticks() + time::ms_to_ticks(ms) + 1;
+    while time::ticks() < deadline {
+        x86_64::instructions::hlt();
+    }
+}
+
+/// AP 的 Rust 入口, 由启动代码在 AP 自己的栈上调用
+extern "C" fn ap_main(cpu: u64) -> ! {
+    let cpu = cpu as usize;
+    percpu::init(cpu);
+    crate::cpu::init();
+    gdt::init_cpu(cpu);
+    syscall::init();
+    interrupts::init_idt();
+    lapic::enable();
+
+    CPU_COUNT.fetch_add(1, Ordering::AcqRel);
+    ONLINE[cpu].store(true, Ordering::Release);
+
+    x86_64::instructions::interrupts::enable();
+    crate::watchdog::ap_idle();
+}
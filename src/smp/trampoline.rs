@@ -0,0 +1,206 @@
+//! AP 启动代码
+//!
+//! - 收到 STARTUP IPI 后 AP 处于实模式, 从 1 MiB 以下的某一页开始执行, 所以这段代码要先复制到
+//!   低端内存的一个帧中. 代码本身与位置无关, 运行时根据 `cs` 算出自己被加载到的物理地址
+//! - 依次进入保护模式, 开启 PAE 和长模式, 使用 BSP 的页表, 最后切换到为它分配的栈并调用 Rust 入口
+//! - 开启分页后取下一条指令时仍使用低端的物理地址, 所以这一页需要恒等映射
+
+use crate::memory;
+use core::arch::global_asm;
+use x86_64::registers::control::Cr3;
+use x86_64::registers::model_specific::{Efer, EferFlags};
+use x86_64::structures::paging::mapper::MapToError;
+use x86_64::structures::paging::{Mapper, Page, PageTableFlags, PhysFrame, Size4KiB};
+use x86_64::VirtAddr;
+
+global_asm!(
+    ".pushsection .text.ap_trampoline, \"ax\"",
+    ".balign 16",
+    ".global ap_trampoline_start",
+    "ap_trampoline_start:",
+    ".code16",
+    "cli",
+    "cld",
+    "movw %cs, %ax",
+    "movw %ax, %ds",
+    // ebx = 这一页的物理地址, 之后的绝对地址都以它为基准
+    "xorl %ebx, %ebx",
+    "movw %ax, %bx",
+    "shll $4, %ebx",
+    // 补上 GDT 指针和远跳转目标中的物理地址
+    "leal (ap_gdt - ap_trampoline_start)(%ebx), %eax",
+    "movl %eax, (ap_gdt_ptr + 2 - ap_trampoline_start)",
+    "leal (ap_protected - ap_trampoline_start)(%ebx), %eax",
+    "movl %eax, (ap_far_ptr - ap_trampoline_start)",
+    "lgdtl (ap_gdt_ptr - ap_trampoline_start)",
+    "movl %cr0, %eax",
+    "orl $1, %eax",
+    "movl %eax, %cr0",
+    "ljmpl *(ap_far_ptr - ap_trampoline_start)",
+    "",
+    ".code32",
+    "ap_protected:",
+    "movw $0x10, %ax",
+    "movw %ax, %ds",
+    "movw %ax, %es",
+    "movw %ax, %ss",
+    // 这一页的末尾作为临时栈, 只用于下面的远返回
+    "leal 0x1000(%ebx), %esp",
+    // CR4.PAE
+    "movl %cr4, %eax",
+    "orl $0x20, %eax",
+    "movl %eax, %cr4",
+    "movl (ap_cr3 - ap_trampoline_start)(%ebx), %eax",
+    "movl %eax, %cr3",
+    // EFER 使用与 BSP 相同的设置(LME, NXE 等)
+    "movl $0xC0000080, %ecx",
+    "rdmsr",
+    "orl (ap_efer - ap_trampoline_start)(%ebx), %eax",
+    "wrmsr",
+    // CR0.PG | CR0.PE
+    "movl %cr0, %eax",
+    "orl $0x80000001, %eax",
+    "movl %eax, %cr0",
+    "leal (ap_long - ap_trampoline_start)(%ebx), %eax",
+    "pushl $0x18",
+    "pushl %eax",
+    "lretl",
+    "",
+    ".code64",
+    "ap_long:",
+    // 数据段寄存器置空, 之后换成内核自己的 GDT 时不会留下指向旧表的选择子
+    "xorl %eax, %eax",
+    "movw %ax, %ds",
+    "movw %ax, %es",
+    "movw %ax, %ss",
+    "movw %ax, %fs",
+    "movw %ax, %gs",
+    // 从 32 位模式进入后寄存器的高 32 位未定义
+    "movl %ebx, %ebx",
+    "movq (ap_stack_top - ap_trampoline_start)(%rbx), %rsp",
+    "movq (ap_arg - ap_trampoline_start)(%rbx), %rdi",
+    "movq (ap_entry - ap_trampoline_start)(%rbx), %rax",
+    "xorl %ebp, %ebp",
+    "callq *%rax",
+    "ud2",
+    "",
+    ".balign 8",
+    "ap_gdt:",
+    ".quad 0",
+    ".quad 0x00CF9A000000FFFF", // 0x08: 32 位代码段
+    ".quad 0x00CF92000000FFFF", // 0x10: 32 位数据段
+    ".quad 0x00AF9A000000FFFF", // 0x18: 64 位代码段
+    "ap_gdt_ptr:",
+    ".word 4 * 8 - 1",
+    ".long 0",
+    "ap_far_ptr:",
+    ".long 0",
+    ".word 0x08",
+    "",
+    // 与 `BootData` 的布局一致, 由 BSP 在每次启动 AP 之前填写
+    ".balign 8",
+    ".global ap_boot_data",
+    "ap_boot_data:",
+    "ap_cr3: .quad 0",
+    "ap_efer: .quad 0",
+    "ap_stack_top: .quad 0",
+    "ap_entry: .quad 0",
+    "ap_arg: .quad 0",
+    ".global ap_trampoline_end",
+    "ap_trampoline_end:",
+    ".popsection",
+    options(att_syntax)
+);
+
+extern "C" {
+    static ap_trampoline_start: u8;
+    static ap_trampoline_end: u8;
+    static ap_boot_data: u8;
+}
+
+/// 传给 AP 的启动参数, 位于启动代码的末尾
+#[repr(C)]
+struct BootData {
+    cr3: u64,
+    efer: u64,
+    stack_top: u64,
+    entry: u64,
+    arg: u64,
+}
+
+/// AP 的 Rust 入口, 参数是 [`Trampoline::prepare`] 中传入的 `arg`
+pub(super) type ApEntry = extern "C" fn(arg: u64) -> !;
+
+/// 复制到低端内存中的启动代码
+pub(super) struct Trampoline {
+    frame: PhysFrame,
+    /// 恒等映射是否由这里建立, 是则在 [`Trampoline::remove`] 时撤销
+    mapped: bool,
+}
+
+impl Trampoline {
+    /// 把启动代码复制到 `frame` 中并为它建立恒等映射
+    pub(super) fn install(frame: PhysFrame) -> Result<Self, MapToError<Size4KiB>> {
+        let start = core::ptr::addr_of!(ap_trampoline_start);
+        let end = core::ptr::addr_of!(ap_trampoline_end);
+        let len = end as usize - start as usize;
+        assert!(len <= 4096 - 16, "AP trampoline does not fit in one page");
+
+        let dst = memory::phys_to_virt(frame.start_address()).as_mut_ptr::<u8>();
+        unsafe { core::ptr::copy_nonoverlapping(start, dst, len) };
+
+        let page = Page::containing_address(VirtAddr::new(frame.start_address().as_u64()));
+        let mapped = memory::with_kernel_memory(|mapper, frame_allocator| {
+            match mapper.translate_page(page) {
+                Ok(mapped) if mapped == frame => Ok(false),
+                Ok(mapped) => Err(MapToError::PageAlreadyMapped(mapped)),
+                Err(_) => {
+                    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+                    unsafe { mapper.map_to(page, frame, flags, frame_allocator)?.flush() };
+                    Ok(true)
+                }
+            }
+        })?;
+
+        Ok(Trampoline { frame, mapped })
+    }
+
+    /// STARTUP IPI 中的向量, 即启动代码所在的页号
+    pub(super) fn vector(&self) -> u8 {
+        (self.frame.start_address().as_u64() >> 12) as u8
+    }
+
+    /// 填写下一个 AP 的启动参数: 栈顶, 入口和传给入口的参数
+    pub(super) fn prepare(&self, stack_top: VirtAddr, entry: ApEntry, arg: u64) {
+        let (cr3, _) = Cr3::read();
+        let cr3 = cr3.start_address().as_u64();
+        // 切换到长模式之前 CR3 只有 32 位
+        assert!(cr3 < 1 << 32, "level 4 page table above 4 GiB");
+
+        let offset = core::ptr::addr_of!(ap_boot_data) as usize
+            - core::ptr::addr_of!(ap_trampoline_start) as usize;
+        let data = (memory::phys_to_virt(self.frame.start_address()) + offset).as_mut_ptr();
+        let boot_data = BootData {
+            cr3,
+            // LMA 由 CPU 设置, 不能写入
+            efer: (Efer::read() - EferFlags::LONG_MODE_ACTIVE).bits(),
+            stack_top: stack_top.align_down(16u64).as_u64(),
+            entry: entry as usize as u64,
+            arg,
+        };
+        unsafe { core::ptr::write_volatile(data, boot_data) };
+    }
+
+    /// 撤销恒等映射, 所有 AP 启动完成后调用
+    pub(super) fn remove(self) {
+        if self.mapped {
+            let page: Page<Size4KiB> =
+                Page::containing_address(VirtAddr::new(self.frame.start_address().as_u64()));
+            memory::with_kernel_memory(|mapper, _| {
+                if let Ok((_, flush)) = mapper.unmap(page) {
+                    flush.flush();
+                }
+            });
+        }
+    }
+}
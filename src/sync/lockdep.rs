@@ -0,0 +1,436 @@
+//! 调试构建中的锁检查
+//!
+//! - 每把 [`IrqSafeMutex`](super::IrqSafeMutex) 记录持有者的 CPU, 上下文(线程号或中断处理)和加锁位置
+//! - 同一个 CPU 重复获取自己持有的锁(例如中断处理中打印时, 被打断的代码正持有输出锁)直接 panic,
+//!   信息中给出两次加锁的位置
+//! - 仿照 Linux 的 lockdep 记录锁之间的获取顺序: 持有 A 时获取 B 记一条 A -> B 的边, 获取锁时
+//!   如果图中已经存在从它到当前持有的某把锁的路径, 说明两条执行路径的加锁顺序相反, 可能死锁, 也 panic
+//! - 自旋超过 [`SPIN_TIMEOUT_CYCLES`] 时通过串口报告一次, 然后继续等待
+//! - 和 Linux 的 lockdep 一样, 锁的类别由创建位置决定: 同一处创建的锁(例如每个执行器中的同一把锁)
+//!   属于同一个类别, 共享加锁顺序. 最多跟踪 [`MAX_CLASSES`] 个创建位置,
+//!   之后新位置创建的锁只做重入和超时检查
+//! - 报告不经过 `SERIAL1`, 直接写串口, 避免等待的正是输出锁; 检查失败导致 panic 后,
+//!   panic 处理中获取当前 CPU 已持有的锁时强制解锁, 保证能打印出信息
+
+use crate::{percpu, serial};
+use core::arch::x86_64::_rdtsc;
+use core::fmt;
+use core::panic::Location;
+use core::ptr;
+use core::sync::atomic::{AtomicBool, AtomicPtr, AtomicU64, AtomicUsize, Ordering};
+use spin::{Mutex, MutexGuard};
+
+/// 跟踪加锁顺序的锁类别数
+pub const MAX_CLASSES: usize = 64;
+/// 每个 CPU 同时持有的锁最多记录这么多把, 更多的不参与顺序检查
+const MAX_HELD: usize = 16;
+/// 自旋等待超过这么多个 TSC 周期时报告, 约为 1 秒
+pub const SPIN_TIMEOUT_CYCLES: u64 = 2_000_000_000;
+
+/// 类别未分配
+const NO_CLASS: usize = 0;
+/// 类别已用完, 不跟踪顺序
+const UNTRACKED: usize = usize::MAX;
+/// 持有者上下文: 中断处理
+const IRQ_CONTEXT: u64 = u64::MAX;
+/// 持有者上下文: CPU 私有数据初始化之前
+const EARLY_CONTEXT: u64 = u64::MAX - 1;
+
+static NEXT_CLASS: AtomicUsize = AtomicUsize::new(1);
+/// 每个类别的创建位置, 用来查找类别, 也在报告中指代这类锁
+static CLASS_SITES: [AtomicPtr<Location<'static>>; MAX_CLASSES] =
+    [const { AtomicPtr::new(ptr::null_mut()) }; MAX_CLASSES];
+/// `ORDER[a]` 的第 b 位表示曾经在持有 a 时获取 b
+static ORDER: [AtomicU64; MAX_CLASSES] = [const { AtomicU64::new(0) }; MAX_CLASSES];
+/// 锁检查已经报告错误并开始 panic
+static PANICKING: AtomicBool = AtomicBool::new(false);
+
+/// 一把锁的调试状态
+pub(super) struct LockState {
+    /// 创建这把锁的位置
+    created: &'static Location<'static>,
+    /// 缓存的类别, 第一次加锁时按创建位置查找
+    class: AtomicUsize,
+    /// 持有者的 CPU 编号加 1, 0 表示没有持有者
+    owner_cpu: AtomicUsize,
+    /// 持有者的线程号, 或 [`IRQ_CONTEXT`], [`EARLY_CONTEXT`]
+    owner_context: AtomicU64,
+    site: AtomicPtr<Location<'static>>,
+}
+
+/// 一个 CPU 当前持有的锁, 只由这个 CPU 在关中断时访问
+#[derive(Default)]
+pub struct HeldLocks {
+    classes: [AtomicUsize; MAX_HELD],
+    len: AtomicUsize,
+}
+
+impl HeldLocks {
+    pub const fn new() -> Self {
+        HeldLocks {
+            classes: [const { AtomicUsize::new(NO_CLASS) }; MAX_HELD],
+            len: AtomicUsize::new(0),
+        }
+    }
+
+    fn iter(&self) -> impl Iterator<Item = usize> + '_ {
+        let len = self.len.load(Ordering::Relaxed).min(MAX_HELD);
+        self.classes[..len]
+            .iter()
+            .map(|class| class.load(Ordering::Relaxed))
+    }
+
+    fn push(&self, class: usize) {
+        let len = self.len.fetch_add(1, Ordering::Relaxed);
+        if len < MAX_HELD {
+            self.classes[len].store(class, Ordering::Relaxed);
+        }
+    }
+
+    /// 锁不一定按获取的相反顺序释放, 从栈顶找到这个类别删除
+    fn remove(&self, class: usize) {
+        let len = self.len.load(Ordering::Relaxed);
+        if len > MAX_HELD {
+            self.len.store(len - 1, Ordering::Relaxed);
+            return;
+        }
+        if let Some(index) = (0..len)
+            .rev()
+            .find(|&i| self.classes[i].load(Ordering::Relaxed) == class)
+        {
+            for i in index..len - 1 {
+                let next = self.classes[i + 1].load(Ordering::Relaxed);
+                self.classes[i].store(next, Ordering::Relaxed);
+            }
+            self.len.store(len - 1, Ordering::Relaxed);
+        }
+    }
+}
+
+impl LockState {
+    pub(super) const fn new(created: &'static Location<'static>) -> Self {
+        LockState {
+            created,
+            class: AtomicUsize::new(NO_CLASS),
+            owner_cpu: AtomicUsize::new(0),
+            owner_context: AtomicU64::new(0),
+            site: AtomicPtr::new(ptr::null_mut()),
+        }
+    }
+
+    fn class(&self) -> usize {
+        let class = self.class.load(Ordering::Relaxed);
+        if class != NO_CLASS {
+            return class;
+        }
+        let class = class_of(self.created);
+        self.class.store(class, Ordering::Relaxed);
+        class
+    }
+
+    fn owner(&self) -> Owner {
+        Owner {
+            cpu: self.owner_cpu.load(Ordering::Relaxed).checked_sub(1),
+            context: self.owner_context.load(Ordering::Relaxed),
+            site: location(self.site.load(Ordering::Relaxed)),
+        }
+    }
+
+    /// 获取锁之前的检查: 重入和加锁顺序
+    fn check(&self, site: &'static Location<'static>) {
+        let Some(percpu) = percpu::try_current() else {
+            return;
+        };
+        // 释放时先清除持有者再解锁, 所以持有者是自己就说明锁还在自己手里
+        let cpu = percpu.cpu_id.load(Ordering::Relaxed);
+        if self.owner_cpu.load(Ordering::Relaxed) == cpu + 1 {
+            fail(format_args!(
+                "recursive acquisition of lock {} at {} from {}; already held by {}",
+                describe(self.class()),
+                site,
+                Context::current(),
+                self.owner(),
+            ));
+        }
+
+        let class = self.class();
+        if class == UNTRACKED {
+            return;
+        }
+        for held in percpu.held_locks.iter() {
+            if held == class || held == UNTRACKED {
+                continue;
+            }
+            if reachable(class, held) {
+                fail(format_args!(
+                    "lock order inversion: acquiring lock {} at {} while holding lock {}, \
+                     but {} has been acquired before {} elsewhere",
+                    describe(class),
+                    site,
+                    describe(held),
+                    describe(class),
+                    describe(held),
+                ));
+            }
+            ORDER[held].fetch_or(1 << class, Ordering::Relaxed);
+        }
+    }
+
+    /// 获取锁之后记录持有者
+    fn acquired(&self, site: &'static Location<'static>) {
+        let Some(percpu) = percpu::try_current() else {
+            return;
+        };
+        let class = self.class();
+        self.owner_cpu
+            .store(percpu.cpu_id.load(Ordering::Relaxed) + 1, Ordering::Relaxed);
+        self.owner_context
+            .store(Context::current().as_u64(), Ordering::Relaxed);
+        self.site.store(location_ptr(site), Ordering::Relaxed);
+        percpu.held_locks.push(class);
+    }
+
+    /// 释放锁之前清除持有者
+    pub(super) fn released(&self) {
+        let Some(percpu) = percpu::try_current() else {
+            return;
+        };
+        self.owner_cpu.store(0, Ordering::Relaxed);
+        self.site.store(ptr::null_mut(), Ordering::Relaxed);
+        let class = self.class.load(Ordering::Relaxed);
+        if class != NO_CLASS {
+            percpu.held_locks.remove(class);
+        }
+    }
+}
+
+/// 带检查地获取 `mutex`, 调用者已经关中断
+pub(super) fn lock<'a, T: ?Sized>(
+    mutex: &'a Mutex<T>,
+    state: &LockState,
+    site: &'static Location<'static>,
+) -> MutexGuard<'a, T> {
+    let this_cpu = percpu::try_current().map(|percpu| percpu.cpu_id.load(Ordering::Relaxed));
+    if PANICKING.load(Ordering::Relaxed)
+        && this_cpu.is_some_and(|cpu| state.owner_cpu.load(Ordering::Relaxed) == cpu + 1)
+    {
+        // 持有这把锁的代码已经因为检查失败而 panic, 不会再释放它
+        state.released();
+        unsafe { mutex.force_unlock() };
+    } else {
+        state.check(site);
+    }
+
+    let start = unsafe { _rdtsc() };
+    let mut reported = false;
+    let guard = loop {
+        if let Some(guard) = mutex.try_lock() {
+            break guard;
+        }
+        if !reported && unsafe { _rdtsc() } - start > SPIN_TIMEOUT_CYCLES {
+            reported = true;
+            report(format_args!(
+                "lock {} at {} from {} is still spinning after {} cycles; held by {}",
+                describe(state.class()),
+                site,
+                Context::current(),
+                SPIN_TIMEOUT_CYCLES,
+                state.owner(),
+            ));
+        }
+        core::hint::spin_loop();
+    };
+    state.acquired(site);
+    guard
+}
+
+/// 不等待地获取 `mutex`, 成功时记录持有者; 不会死锁, 所以不做检查
+pub(super) fn try_lock<'a, T: ?Sized>(
+    mutex: &'a Mutex<T>,
+    state: &LockState,
+    site: &'static Location<'static>,
+) -> Option<MutexGuard<'a, T>> {
+    let guard = mutex.try_lock()?;
+    state.acquired(site);
+    Some(guard)
+}
+
+/// 创建位置为 `site` 的锁的类别, 第一次遇到这个位置时分配新类别
+fn class_of(site: &'static Location<'static>) -> usize {
+    'retry: loop {
+        let next = NEXT_CLASS.load(Ordering::Acquire);
+        let known_sites = CLASS_SITES.iter().enumerate().take(next).skip(1);
+        for (class, known) in known_sites {
+            match location(known.load(Ordering::Acquire)) {
+                Some(known) if known == site => return class,
+                Some(_) => {}
+                // 其他 CPU 刚分配了这个类别, 还没有写入位置
+                None => {
+                    core::hint::spin_loop();
+                    continue 'retry;
+                }
+            }
+        }
+        if next >= MAX_CLASSES {
+            return UNTRACKED;
+        }
+        if NEXT_CLASS
+            .compare_exchange(next, next + 1, Ordering::AcqRel, Ordering::Acquire)
+            .is_ok()
+        {
+            CLASS_SITES[next].store(location_ptr(site), Ordering::Release);
+            return next;
+        }
+    }
+}
+
+/// 加锁顺序图中是否有从 `from` 到 `to` 的路径
+fn reachable(from: usize, to: usize) -> bool {
+    let mut visited = 1u64 << from;
+    let mut frontier = visited;
+    while frontier != 0 {
+        let mut next = 0;
+        let mut rest = frontier;
+        while rest != 0 {
+            let class = rest.trailing_zeros() as usize;
+            rest &= rest - 1;
+            next |= ORDER[class].load(Ordering::Relaxed);
+        }
+        if next & (1 << to) != 0 {
+            return true;
+        }
+        frontier = next & !visited;
+        visited |= next;
+    }
+    false
+}
+
+/// 加锁时所处的上下文, 编码为线程号或 [`IRQ_CONTEXT`], [`EARLY_CONTEXT`]
+#[derive(Clone, Copy)]
+struct Context(u64);
+
+impl Context {
+    fn current() -> Self {
+        match percpu::try_current() {
+            Some(percpu) if percpu.irq_depth.load(Ordering::Relaxed) > 0 => Context(IRQ_CONTEXT),
+            Some(percpu) => Context(percpu.current_thread.load(Ordering::Relaxed)),
+            None => Context(EARLY_CONTEXT),
+        }
+    }
+
+    fn as_u64(self) -> u64 {
+        self.0
+    }
+}
+
+impl fmt::Display for Context {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.0 {
+            IRQ_CONTEXT => f.write_str("an interrupt handler"),
+            EARLY_CONTEXT => f.write_str("early boot"),
+            id => write!(f, "thread {}", id),
+        }
+    }
+}
+
+struct Owner {
+    cpu: Option<usize>,
+    context: u64,
+    site: Option<&'static Location<'static>>,
+}
+
+impl fmt::Display for Owner {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match (self.cpu, self.site) {
+            (Some(cpu), Some(site)) => write!(
+                f,
+                "{} on CPU {} (locked at {})",
+                Context(self.context),
+                cpu,
+                site
+            ),
+            _ => f.write_str("nobody"),
+        }
+    }
+}
+
+/// 锁的称呼: 类别号和创建位置
+struct Class(usize);
+
+fn describe(class: usize) -> Class {
+    Class(class)
+}
+
+impl fmt::Display for Class {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let site = CLASS_SITES
+            .get(self.0)
+            .and_then(|site| location(site.load(Ordering::Relaxed)));
+        match site {
+            Some(site) => write!(f, "#{} (created at {})", self.0, site),
+            None => f.write_str("#?"),
+        }
+    }
+}
+
+fn location_ptr(site: &'static Location<'static>) -> *mut Location<'static> {
+    site as *const Location<'static> as *mut Location<'static>
+}
+
+fn location(ptr: *mut Location<'static>) -> Option<&'static Location<'static>> {
+    unsafe { ptr.as_ref() }
+}
+
+fn report(args: fmt::Arguments) {
+    serial::_print_unlocked(format_args!("lockdep: {}\n", args));
+}
+
+fn fail(args: fmt::Arguments) -> ! {
+    report(args);
+    PANICKING.store(true, Ordering::Relaxed);
+    panic!("lockdep: {}", args);
+}
+
+#[test_case]
+fn test_lock_order_is_recorded() {
+    use super::IrqSafeMutex;
+
+    let a = IrqSafeMutex::new(());
+    let b = IrqSafeMutex::new(());
+    {
+        let _a = a.lock();
+        let _b = b.lock();
+    }
+    let class_a = a.state.class.load(Ordering::Relaxed);
+    let class_b = b.state.class.load(Ordering::Relaxed);
+    if class_a == UNTRACKED || class_b == UNTRACKED {
+        return;
+    }
+    assert!(reachable(class_a, class_b));
+    assert!(!reachable(class_b, class_a));
+    assert_eq!(a.state.owner_cpu.load(Ordering::Relaxed), 0);
+    // 顺序一致的再次加锁不会报错, 释放顺序也不必相反
+    let guard_a = a.lock();
+    let guard_b = b.lock();
+    drop(guard_a);
+    drop(guard_b);
+    assert_eq!(percpu::current().held_locks.iter().count(), 0);
+}
+
+#[test_case]
+fn test_classes_are_keyed_by_creation_site() {
+    use super::IrqSafeMutex;
+
+    let before = NEXT_CLASS.load(Ordering::Relaxed);
+    // 比类别总数还多的锁, 都在同一处创建
+    let locks: [IrqSafeMutex<()>; 2 * MAX_CLASSES] =
+        core::array::from_fn(|_| IrqSafeMutex::new(()));
+    for lock in &locks {
+        drop(lock.lock());
+    }
+    assert!(NEXT_CLASS.load(Ordering::Relaxed) <= before + 1);
+    let class = locks[0].state.class.load(Ordering::Relaxed);
+    assert!(locks
+        .iter()
+        .all(|lock| lock.state.class.load(Ordering::Relaxed) == class));
+}
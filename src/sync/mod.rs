@@ -0,0 +1,157 @@
+//! 同步原语
+//!
+//! - [`IrqSafeMutex`]: 加锁时关中断, 解锁时恢复加锁前的中断状态. 中断处理中也会获取的锁
+//!   (输出, PIC, 堆分配器)都应使用它, 否则持锁期间到来的中断会在同一个 CPU 上死锁
+//! - 关中断只防止本 CPU 的重入, 其他 CPU 仍然通过自旋互斥
+//! - 调试构建中每次加锁都经过 [`lockdep`] 检查重入, 加锁顺序和过长的自旋
+
+#[cfg(debug_assertions)]
+pub mod lockdep;
+
+use core::fmt;
+use core::mem::ManuallyDrop;
+use core::ops::{Deref, DerefMut};
+use core::panic::Location;
+use spin::{Mutex, MutexGuard};
+use x86_64::instructions::interrupts;
+
+/// 持锁期间关中断的自旋锁
+pub struct IrqSafeMutex<T: ?Sized> {
+    #[cfg(debug_assertions)]
+    state: lockdep::LockState,
+    inner: Mutex<T>,
+}
+
+impl<T> IrqSafeMutex<T> {
+    /// 调试构建中, 调用位置决定这把锁在 [`lockdep`] 中的类别
+    #[track_caller]
+    pub const fn new(value: T) -> Self {
+        IrqSafeMutex {
+            #[cfg(debug_assertions)]
+            state: lockdep::LockState::new(Location::caller()),
+            inner: Mutex::new(value),
+        }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.inner.into_inner()
+    }
+}
+
+impl<T: ?Sized> IrqSafeMutex<T> {
+    /// 关中断并获取锁, 守卫释放时先解锁再恢复中断状态
+    #[track_caller]
+    pub fn lock(&self) -> IrqSafeMutexGuard<'_, T> {
+        let site = Location::caller();
+        let interrupts_enabled = interrupts::are_enabled();
+        interrupts::disable();
+        #[cfg(debug_assertions)]
+        let guard = lockdep::lock(&self.inner, &self.state, site);
+        #[cfg(not(debug_assertions))]
+        let guard = {
+            let _ = site;
+            self.inner.lock()
+        };
+        self.guard(guard, interrupts_enabled)
+    }
+
+    /// 锁已被持有时返回 `None`, 中断状态不变
+    #[track_caller]
+    pub fn try_lock(&self) -> Option<IrqSafeMutexGuard<'_, T>> {
+        let site = Location::caller();
+        let interrupts_enabled = interrupts::are_enabled();
+        interrupts::disable();
+        #[cfg(debug_assertions)]
+        let guard = lockdep::try_lock(&self.inner, &self.state, site);
+        #[cfg(not(debug_assertions))]
+        let guard = {
+            let _ = site;
+            self.inner.try_lock()
+        };
+        match guard {
+            Some(guard) => Some(self.guard(guard, interrupts_enabled)),
+            None => {
+                if interrupts_enabled {
+                    interrupts::enable();
+                }
+                None
+            }
+        }
+    }
+
+    fn guard<'a>(
+        &'a self,
+        guard: MutexGuard<'a, T>,
+        interrupts_enabled: bool,
+    ) -> IrqSafeMutexGuard<'a, T> {
+        IrqSafeMutexGuard {
+            guard: ManuallyDrop::new(guard),
+            #[cfg(debug_assertions)]
+            state: &self.state,
+            interrupts_enabled,
+        }
+    }
+}
+
+impl<T: ?Sized + fmt::Debug> fmt::Debug for IrqSafeMutex<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.try_lock() {
+            Some(guard) => f
+                .debug_struct("IrqSafeMutex")
+                .field("data", &&*guard)
+                .finish(),
+            None => f.write_str("IrqSafeMutex { <locked> }"),
+        }
+    }
+}
+
+pub struct IrqSafeMutexGuard<'a, T: ?Sized> {
+    guard: ManuallyDrop<MutexGuard<'a, T>>,
+    #[cfg(debug_assertions)]
+    state: &'a lockdep::LockState,
+    /// 加锁前中断是否打开
+    interrupts_enabled: bool,
+}
+
+impl<T: ?Sized> Deref for IrqSafeMutexGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.guard
+    }
+}
+
+impl<T: ?Sized> DerefMut for IrqSafeMutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.guard
+    }
+}
+
+impl<T: ?Sized> Drop for IrqSafeMutexGuard<'_, T> {
+    fn drop(&mut self) {
+        #[cfg(debug_assertions)]
+        self.state.released();
+        unsafe { ManuallyDrop::drop(&mut self.guard) };
+        if self.interrupts_enabled {
+            interrupts::enable();
+        }
+    }
+}
+
+#[test_case]
+fn test_irq_safe_mutex_restores_interrupts() {
+    let mutex = IrqSafeMutex::new(0);
+    assert!(interrupts::are_enabled());
+    {
+        let mut guard = mutex.lock();
+        assert!(!interrupts::are_enabled());
+        *guard += 1;
+        // 嵌套的锁不能提前打开中断
+        let other = IrqSafeMutex::new(());
+        drop(other.lock());
+        assert!(!interrupts::are_enabled());
+        assert!(mutex.try_lock().is_none());
+    }
+    assert!(interrupts::are_enabled());
+    assert_eq!(*mutex.lock(), 1);
+}
@@ -0,0 +1,132 @@
+//! `SYSCALL` 入口和进入用户态
+//!
+//! - `SYSCALL` 不切换栈, 入口先用 `swapgs` 换到内核的 CPU 私有数据, 暂存用户栈指针,
+//!   再切换到当前线程的内核栈, 在栈上构造 [`SyscallFrame`] 交给 Rust 代码
+//! - 返回时恢复参数寄存器, 只有 `rax` 被改为返回值, 最后用 `SYSRET` 回到用户态
+//! - 系统调用在开中断的状态下执行, 可以被抢占和睡眠
+//! - `swapgs` 之前和之后到达的 NMI/#MC 由异常入口根据 `IA32_GS_BASE` 判断是否需要 `swapgs`,
+//!   见 [`trap`](crate::interrupts::trap)
+
+use crate::gdt;
+use crate::percpu::{KERNEL_STACK_OFFSET, USER_RSP_OFFSET};
+use core::arch::{asm, global_asm};
+use x86_64::registers::model_specific::{Efer, EferFlags, LStar, SFMask, Star};
+use x86_64::registers::rflags::RFlags;
+use x86_64::VirtAddr;
+
+global_asm!(
+    ".global syscall_entry",
+    "syscall_entry:",
+    "swapgs",
+    "movq %rsp, %gs:{user_rsp}",
+    "movq %gs:{kernel_stack}, %rsp",
+    // 用户的 rsp, rflags(r11), rip(rcx), 系统调用号和 6 个参数
+    "pushq %gs:{user_rsp}",
+    "pushq %r11",
+    "pushq %rcx",
+    "pushq %rax",
+    "pushq %rdi",
+    "pushq %rsi",
+    "pushq %rdx",
+    "pushq %r10",
+    "pushq %r8",
+    "pushq %r9",
+    // 共 80 字节, 内核栈顶 16 字节对齐, 此时 rsp 仍然对齐
+    "movq %rsp, %rdi",
+    "sti",
+    "call {dispatch}",
+    "cli",
+    "popq %r9",
+    "popq %r8",
+    "popq %r10",
+    "popq %rdx",
+    "popq %rsi",
+    "popq %rdi",
+    "popq %rax",
+    "popq %rcx",
+    "popq %r11",
+    "popq %rsp",
+    "swapgs",
+    "sysretq",
+    user_rsp = const USER_RSP_OFFSET,
+    kernel_stack = const KERNEL_STACK_OFFSET,
+    dispatch = sym super::dispatch,
+    options(att_syntax)
+);
+
+extern "C" {
+    fn syscall_entry();
+}
+
+/// 入口在内核栈上保存的用户现场, 字段顺序与压栈顺序相反
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+pub struct SyscallFrame {
+    pub r9: u64,
+    pub r8: u64,
+    pub r10: u64,
+    pub rdx: u64,
+    pub rsi: u64,
+    pub rdi: u64,
+    /// 调用时是系统调用号, 返回时是返回值
+    pub rax: u64,
+    pub rip: u64,
+    pub rflags: u64,
+    pub rsp: u64,
+}
+
+impl SyscallFrame {
+    /// 按调用约定排列的 6 个参数: rdi, rsi, rdx, r10, r8, r9
+    pub fn args(&self) -> [u64; 6] {
+        [self.rdi, self.rsi, self.rdx, self.r10, self.r8, self.r9]
+    }
+}
+
+/// 设置当前 CPU 的 `SYSCALL` 相关 MSR, 每个 CPU 都要调用
+pub fn init() {
+    let selectors = gdt::selectors();
+    Star::write(
+        selectors.user_code,
+        selectors.user_data,
+        selectors.kernel_code,
+        selectors.kernel_data,
+    )
+    .expect("GDT layout does not match SYSCALL/SYSRET");
+    LStar::write(VirtAddr::new(syscall_entry as usize as u64));
+    // 进入内核时关中断, 清除单步, 方向和对齐检查标志
+    SFMask::write(
+        RFlags::INTERRUPT_FLAG
+            | RFlags::TRAP_FLAG
+            | RFlags::DIRECTION_FLAG
+            | RFlags::ALIGNMENT_CHECK,
+    );
+    unsafe { Efer::update(|flags| *flags |= EferFlags::SYSTEM_CALL_EXTENSIONS) };
+}
+
+/// 以用户态(ring 3)跳转到 `entry` 执行, 使用 `stack_top` 作为用户栈
+///
+/// - 之后从用户态陷入内核时使用当前线程内核栈的栈顶, 调用者在内核栈上的栈帧会被覆盖,
+///   所以这个函数不返回
+///
+/// # Safety
+/// `entry` 和 `stack_top` 必须位于当前地址空间中用户可访问的映射内
+pub unsafe fn enter_user_mode(entry: VirtAddr, stack_top: VirtAddr) -> ! {
+    let selectors = gdt::selectors();
+    let rflags = RFlags::INTERRUPT_FLAG.bits() | 0x2;
+    asm!(
+        "cli",
+        "swapgs",
+        "push {ss}",
+        "push {rsp}",
+        "push {rflags}",
+        "push {cs}",
+        "push {rip}",
+        "iretq",
+        ss = in(reg) u64::from(selectors.user_data.0),
+        rsp = in(reg) stack_top.as_u64(),
+        rflags = in(reg) rflags,
+        cs = in(reg) u64::from(selectors.user_code.0),
+        rip = in(reg) entry.as_u64(),
+        options(noreturn)
+    );
+}
@@ -0,0 +1,366 @@
+//! 系统调用
+//!
+//! - 用户程序把系统调用号放在 `rax`, 参数依次放在 `rdi`, `rsi`, `rdx`, `r10`, `r8`, `r9`,
+//!   然后执行 `syscall`, 返回值在 `rax` 中. 除 `rax`, `rcx`, `r11` 外的寄存器保持不变
+//! - 返回值为负数时表示出错, 取反后是 [`SyscallError`] 中的错误码, 与 Linux 的 errno 相同
+//! - 内核根据系统调用号查 [`SYSCALL_TABLE`] 分发到对应的处理函数
+//! - 用户传入的指针都经过 [`user`] 检查, 字符串以 (指针, 长度) 传递, 不要求以 0 结尾
+//! - 返回用户态之前检查当前进程是否已被 kill
+//!
+//! | 号 | 名字     | 参数                      | 返回值               |
+//! |----|----------|---------------------------|----------------------|
+//! | 0  | exit     | 退出码                    | 不返回               |
+//! | 1  | yield    |                           | 0                    |
+//! | 2  | spawn    | 程序名指针, 长度          | 子进程号             |
+//! | 3  | wait     | 进程号(0 为任意), 状态指针 | 子进程号             |
+//! | 4  | kill     | 进程号                    | 0                    |
+//! | 5  | write    | fd, 缓冲区指针, 长度      | 写入的字节数         |
+//! | 6  | read     | fd, 缓冲区指针, 长度      | 读到的字节数         |
+//! | 7  | getpid   |                           | 进程号               |
+//! | 8  | sleep    | 毫秒数                    | 0                    |
+//! | 9  | mmap     | 地址提示, 长度, 权限      | 映射的起始地址       |
+//! | 10 | munmap   | 地址, 长度                | 0                    |
+//! | 11 | open     | 路径指针, 长度            | fd                   |
+//! | 12 | close    | fd                        | 0                    |
+
+mod entry;
+pub mod user;
+
+pub use entry::{enter_user_mode, init, SyscallFrame};
+
+use crate::address_space::{self, USER_END};
+use crate::loader::LoadError;
+use crate::process::file::{self, FileError};
+use crate::process::mmap::MAX_MMAP_LEN;
+use crate::process::{self, programs, ExitStatus, Pid, Process, ProcessError};
+use crate::thread;
+use crate::time;
+use alloc::vec;
+use x86_64::structures::paging::{Page, PageTableFlags, Size4KiB};
+use x86_64::VirtAddr;
+
+/// 系统调用号
+pub const SYS_EXIT: u64 = 0;
+pub const SYS_YIELD: u64 = 1;
+pub const SYS_SPAWN: u64 = 2;
+pub const SYS_WAIT: u64 = 3;
+pub const SYS_KILL: u64 = 4;
+pub const SYS_WRITE: u64 = 5;
+pub const SYS_READ: u64 = 6;
+pub const SYS_GETPID: u64 = 7;
+pub const SYS_SLEEP: u64 = 8;
+pub const SYS_MMAP: u64 = 9;
+pub const SYS_MUNMAP: u64 = 10;
+pub const SYS_OPEN: u64 = 11;
+pub const SYS_CLOSE: u64 = 12;
+
+/// `mmap` 的权限位, 映射总是可读
+pub const PROT_READ: u64 = 1 << 0;
+pub const PROT_WRITE: u64 = 1 << 1;
+pub const PROT_EXEC: u64 = 1 << 2;
+
+/// 没有地址提示或提示的地址不可用时, `mmap` 从这里开始向上寻找空闲的范围
+pub const MMAP_BASE: u64 = 0x0000_1000_0000_0000;
+
+/// 单次 `read`/`write` 最多传输的字节数, 超过时只传输这么多
+pub const MAX_IO: usize = 64 * 1024;
+
+/// 程序名和路径的最大长度
+pub const MAX_PATH: usize = 256;
+
+/// 系统调用的错误码, 与 Linux 的 errno 相同
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(i64)]
+pub enum SyscallError {
+    /// 程序或文件不存在
+    NoEntry = 2,
+    /// 进程不存在, 或调用者不是进程
+    NoProcess = 3,
+    /// 等待时被 kill
+    Interrupted = 4,
+    /// 文件描述符无效, 或文件不支持这种操作
+    BadFd = 9,
+    /// 没有可以等待的子进程
+    NoChild = 10,
+    /// 内存不足
+    NoMemory = 12,
+    /// 用户指针无效
+    Fault = 14,
+    /// 参数无效
+    Invalid = 22,
+    /// 名字或路径太长
+    NameTooLong = 36,
+    /// 不存在的系统调用
+    NoSys = 38,
+}
+
+impl From<ProcessError> for SyscallError {
+    fn from(err: ProcessError) -> Self {
+        match err {
+            ProcessError::NoSuchProcess => SyscallError::NoProcess,
+            ProcessError::NoChild => SyscallError::NoChild,
+            ProcessError::Interrupted => SyscallError::Interrupted,
+        }
+    }
+}
+
+impl From<LoadError> for SyscallError {
+    fn from(err: LoadError) -> Self {
+        match err {
+            LoadError::Mapping(_) => SyscallError::NoMemory,
+            _ => SyscallError::Invalid,
+        }
+    }
+}
+
+impl From<FileError> for SyscallError {
+    fn from(err: FileError) -> Self {
+        match err {
+            FileError::BadDescriptor | FileError::NotSupported => SyscallError::BadFd,
+        }
+    }
+}
+
+pub type SyscallResult = Result<u64, SyscallError>;
+
+/// 系统调用处理函数, 参数是调用时的 6 个参数寄存器
+pub type SyscallHandler = fn(args: [u64; 6]) -> SyscallResult;
+
+/// 按系统调用号排列的处理函数
+static SYSCALL_TABLE: [SyscallHandler; 13] = [
+    sys_exit, sys_yield, sys_spawn, sys_wait, sys_kill, sys_write, sys_read, sys_getpid, sys_sleep,
+    sys_mmap, sys_munmap, sys_open, sys_close,
+];
+
+/// 由 `syscall_entry` 调用, 把返回值写回 `frame.rax`
+extern "C" fn dispatch(frame: &mut SyscallFrame) {
+    let result = usize::try_from(frame.rax)
+        .ok()
+        .and_then(|number| SYSCALL_TABLE.get(number))
+        .map_or(Err(SyscallError::NoSys), |handler| handler(frame.args()));
+
+    frame.rax = match result {
+        Ok(value) => value,
+        Err(err) => (-(err as i64)) as u64,
+    };
+    process::exit_if_killed();
+}
+
+/// 在当前进程中执行 `f`, 调用者不是进程时返回 [`SyscallError::NoProcess`]
+fn with_process<R>(
+    f: impl FnOnce(&mut Process) -> Result<R, SyscallError>,
+) -> Result<R, SyscallError> {
+    process::with_current(f).ok_or(SyscallError::NoProcess)?
+}
+
+/// 结束当前进程, 退出码为 `args[0]`, 不返回
+///
+/// - 不属于任何进程的用户态线程直接结束线程
+fn sys_exit(args: [u64; 6]) -> SyscallResult {
+    if process::current().is_some() {
+        process::exit(ExitStatus::Exited(args[0] as i32));
+    }
+    thread::exit();
+}
+
+/// 让出 CPU
+fn sys_yield(_args: [u64; 6]) -> SyscallResult {
+    thread::yield_now();
+    Ok(0)
+}
+
+/// 以 `[args[0], args[0] + args[1])` 处的名字启动内核中的用户程序, 返回子进程号
+///
+/// - 子进程的参数只有程序名, 没有环境变量
+fn sys_spawn(args: [u64; 6]) -> SyscallResult {
+    let mut buf = [0; MAX_PATH];
+    let name = with_process(|process| {
+        user::read_str(process.address_space(), args[0], args[1], &mut buf)
+    })?;
+    let image = programs::find(name).ok_or(SyscallError::NoEntry)?;
+    let pid = process::spawn(image, &[name], &[])?;
+    Ok(pid.as_u64())
+}
+
+/// 等待子进程 `args[0]` 结束, 为 0 时等待任意子进程; 返回子进程号
+///
+/// - `args[1]` 不为 0 时, 把退出状态以 `i32` 写到这个地址: 正常退出时为 `退出码 << 8`,
+///   被 kill 时为 9 (与 Linux 的 `wait` 状态一致). 地址在等待之前检查
+fn sys_wait(args: [u64; 6]) -> SyscallResult {
+    let target = (args[0] != 0).then(|| Pid::from_u64(args[0]));
+    let status_ptr = args[1];
+    if status_ptr != 0 {
+        with_process(|process| {
+            user::check(
+                process.address_space(),
+                status_ptr,
+                4,
+                PageTableFlags::WRITABLE,
+            )
+        })?;
+    }
+
+    let (pid, status) = process::wait(target)?;
+    if status_ptr != 0 {
+        let status: i32 = match status {
+            ExitStatus::Exited(code) => (code & 0xff) << 8,
+            ExitStatus::Killed => 9,
+        };
+        with_process(|process| {
+            user::copy_to_user(process.address_space(), status_ptr, &status.to_le_bytes())
+        })?;
+    }
+    Ok(pid.as_u64())
+}
+
+/// 结束进程 `args[0]`; 目标是自己时不返回
+fn sys_kill(args: [u64; 6]) -> SyscallResult {
+    process::kill(Pid::from_u64(args[0]))?;
+    Ok(0)
+}
+
+/// 把 `[args[1], args[1] + args[2])` 写入文件 `args[0]`, 返回写入的字节数
+///
+/// - 先在持有进程表时把数据复制到内核缓冲区, 再释放进程表写入文件
+fn sys_write(args: [u64; 6]) -> SyscallResult {
+    let len = args[2].min(MAX_IO as u64) as usize;
+    let mut buf = vec![0; len];
+    let file = with_process(|process| {
+        let file = process.files().get(args[0] as usize)?;
+        user::copy_from_user(process.address_space(), args[1], &mut buf)?;
+        Ok(file)
+    })?;
+    Ok(file.write(&buf)? as u64)
+}
+
+/// 从文件 `args[0]` 读取最多 `args[2]` 字节到 `args[1]`, 返回读到的字节数
+///
+/// - 先检查缓冲区可写, 读取文件后再复制到用户内存
+fn sys_read(args: [u64; 6]) -> SyscallResult {
+    let (fd, addr) = (args[0] as usize, args[1]);
+    let len = args[2].min(MAX_IO as u64) as usize;
+    let file = with_process(|process| {
+        let file = process.files().get(fd)?;
+        user::check(
+            process.address_space(),
+            addr,
+            len as u64,
+            PageTableFlags::WRITABLE,
+        )?;
+        Ok(file)
+    })?;
+
+    let mut buf = vec![0; len];
+    let read = file.read(&mut buf)?;
+    with_process(|process| user::copy_to_user(process.address_space(), addr, &buf[..read]))?;
+    Ok(read as u64)
+}
+
+/// 返回当前进程的进程号
+fn sys_getpid(_args: [u64; 6]) -> SyscallResult {
+    with_process(|process| Ok(process.pid().as_u64()))
+}
+
+/// 睡眠至少 `args[0]` 毫秒
+///
+/// - 被 kill 时立即结束进程; 被其他原因提前唤醒时继续睡眠
+fn sys_sleep(args: [u64; 6]) -> SyscallResult {
+    // 用户可以传入任意大的毫秒数, 超过上限时相当于永远睡眠
+    let deadline = time::uptime_ms().saturating_add(args[0]);
+    loop {
+        thread::sleep(deadline.saturating_sub(time::uptime_ms()));
+        process::exit_if_killed();
+        if time::uptime_ms() >= deadline {
+            return Ok(0);
+        }
+    }
+}
+
+/// 映射 `args[1]` 字节的清零匿名内存, 权限为 `args[2]`, 返回起始地址
+///
+/// - `args[0]` 是地址提示: 不为 0, 按页对齐且范围空闲时就用它, 否则从 [`MMAP_BASE`] 开始向上
+///   寻找第一段没有被 `mmap` 使用的范围
+/// - 长度向上取整到整页, 不能超过 [`MAX_MMAP_LEN`]; 进程映射的总量不能超过
+///   [`MAX_MMAP_TOTAL`](process::mmap::MAX_MMAP_TOTAL)
+/// - 帧不够时解除这次已经建立的映射, 返回 `NoMemory`
+fn sys_mmap(args: [u64; 6]) -> SyscallResult {
+    let (hint, prot) = (args[0], args[2]);
+    let len = args[1]
+        .checked_next_multiple_of(Page::<Size4KiB>::SIZE)
+        .filter(|&len| len != 0)
+        .ok_or(SyscallError::Invalid)?;
+    if len > MAX_MMAP_LEN {
+        return Err(SyscallError::NoMemory);
+    }
+    if prot & !(PROT_READ | PROT_WRITE | PROT_EXEC) != 0 {
+        return Err(SyscallError::Invalid);
+    }
+    let mut flags = PageTableFlags::empty();
+    if prot & PROT_WRITE != 0 {
+        flags |= PageTableFlags::WRITABLE;
+    }
+    if prot & PROT_EXEC == 0 {
+        flags |= PageTableFlags::NO_EXECUTE;
+    }
+
+    with_process(|process| {
+        let (address_space, mmaps) = process.memory();
+        if mmaps.would_exceed(len) {
+            return Err(SyscallError::NoMemory);
+        }
+        // 长度有上限, 逐页检查的代价也有上限
+        let is_free = |start: u64| {
+            address_space::is_user_range(start, len)
+                && address_space.is_unmapped(VirtAddr::new(start), len)
+        };
+        let start = if hint != 0 && hint % Page::<Size4KiB>::SIZE == 0 && is_free(hint) {
+            hint
+        } else {
+            // 程序映像和栈不在这段范围内, 找到的范围只会与 `mmap` 的映射重叠
+            mmaps
+                .find_free(MMAP_BASE, USER_END, len)
+                .filter(|&start| is_free(start))
+                .ok_or(SyscallError::NoMemory)?
+        };
+        if address_space.map(VirtAddr::new(start), len, flags).is_err() {
+            address_space.unmap(VirtAddr::new(start), len);
+            return Err(SyscallError::NoMemory);
+        }
+        mmaps.insert(start, len);
+        Ok(start)
+    })
+}
+
+/// 解除 `[args[0], args[0] + args[1])` 覆盖的页的映射, 其中没有映射的页被跳过
+///
+/// - 起始地址必须按页对齐
+fn sys_munmap(args: [u64; 6]) -> SyscallResult {
+    let (start, len) = (args[0], args[1]);
+    if start % Page::<Size4KiB>::SIZE != 0 || !address_space::is_user_range(start, len) {
+        return Err(SyscallError::Invalid);
+    }
+    with_process(|process| {
+        let (address_space, mmaps) = process.memory();
+        address_space.unmap(VirtAddr::new(start), len);
+        mmaps.remove(start, len.next_multiple_of(Page::<Size4KiB>::SIZE));
+        Ok(0)
+    })
+}
+
+/// 打开 `[args[0], args[0] + args[1])` 处的路径, 返回文件描述符, 可以打开的路径见 [`file::open`]
+fn sys_open(args: [u64; 6]) -> SyscallResult {
+    let mut buf = [0; MAX_PATH];
+    with_process(|process| {
+        let path = user::read_str(process.address_space(), args[0], args[1], &mut buf)?;
+        let file = file::open(path).ok_or(SyscallError::NoEntry)?;
+        Ok(process.files().insert(file) as u64)
+    })
+}
+
+/// 关闭文件描述符 `args[0]`
+fn sys_close(args: [u64; 6]) -> SyscallResult {
+    with_process(|process| {
+        process.files().remove(args[0] as usize)?;
+        Ok(0)
+    })
+}
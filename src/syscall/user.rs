@@ -0,0 +1,85 @@
+//! 用户指针检查和用户内存复制
+//!
+//! - 系统调用收到的指针由用户程序提供, 使用之前必须确认整段范围都在用户地址范围内, 每一页都已映射,
+//!   带有用户可访问标志, 写入时还要可写; 否则返回 [`SyscallError::Fault`], 不会触发内核中的 page fault
+//! - 复制通过物理内存映射进行, 不直接解引用用户指针. 检查和复制都在持有进程表时完成, 进程只有一个
+//!   线程, 期间它的映射不会改变
+
+use super::SyscallError;
+use crate::address_space::{self, AddressSpace};
+use x86_64::structures::paging::{Page, PageTableFlags, Size4KiB};
+use x86_64::VirtAddr;
+
+/// 检查 `[addr, addr + len)` 位于用户地址范围内, 并且每一页都以 `flags` 映射为用户可访问
+pub fn check(
+    address_space: &AddressSpace,
+    addr: u64,
+    len: u64,
+    flags: PageTableFlags,
+) -> Result<(), SyscallError> {
+    if !address_space::is_user_range(addr, len) {
+        return Err(SyscallError::Fault);
+    }
+    let flags = flags | PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+    let end = addr + len;
+    let mut page = VirtAddr::new(addr).align_down(Page::<Size4KiB>::SIZE);
+    while page.as_u64() < end {
+        match address_space.page_flags(page) {
+            Some(mapped) if mapped.contains(flags) => page += Page::<Size4KiB>::SIZE,
+            _ => return Err(SyscallError::Fault),
+        }
+    }
+    Ok(())
+}
+
+/// 从用户地址 `addr` 复制 `buf.len()` 字节
+pub fn copy_from_user(
+    address_space: &AddressSpace,
+    addr: u64,
+    buf: &mut [u8],
+) -> Result<(), SyscallError> {
+    check(
+        address_space,
+        addr,
+        buf.len() as u64,
+        PageTableFlags::empty(),
+    )?;
+    address_space
+        .read(VirtAddr::new(addr), buf)
+        .map_err(|_| SyscallError::Fault)
+}
+
+/// 把 `data` 复制到用户地址 `addr`, 目标必须可写
+pub fn copy_to_user(
+    address_space: &mut AddressSpace,
+    addr: u64,
+    data: &[u8],
+) -> Result<(), SyscallError> {
+    check(
+        address_space,
+        addr,
+        data.len() as u64,
+        PageTableFlags::WRITABLE,
+    )?;
+    address_space
+        .write(VirtAddr::new(addr), data)
+        .map_err(|_| SyscallError::Fault)
+}
+
+/// 读取用户地址 `addr` 处长度为 `len` 的 UTF-8 字符串, 复制到 `buf` 中
+///
+/// - 长度超过 `buf` 时返回 [`SyscallError::NameTooLong`]
+pub fn read_str<'a>(
+    address_space: &AddressSpace,
+    addr: u64,
+    len: u64,
+    buf: &'a mut [u8],
+) -> Result<&'a str, SyscallError> {
+    let len = usize::try_from(len)
+        .ok()
+        .filter(|&len| len <= buf.len())
+        .ok_or(SyscallError::NameTooLong)?;
+    let buf = &mut buf[..len];
+    copy_from_user(address_space, addr, buf)?;
+    core::str::from_utf8(buf).map_err(|_| SyscallError::Invalid)
+}
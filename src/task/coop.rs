@@ -0,0 +1,93 @@
+//! 协作式让出
+//!
+//! - 执行器每次轮询任务时给它 [`BUDGET`] 点预算, 任务每处理一项工作(例如从流中取出一个值)
+//!   用 [`consume_budget`]/[`poll_budget`] 扣一点. 预算用完后返回 `Pending` 并立即唤醒自己,
+//!   任务排到同优先级队列的末尾, 一连串输入不会让一个任务一直占着执行器
+//! - [`yield_now`] 无条件让出一次
+//! - 不在执行器中轮询(例如测试中手动 poll)时预算不受限制
+//! - 预算属于正在轮询的线程(见 [`poll_state`](super::poll_state)), 多个执行器线程互相抢占时
+//!   不会用掉或重置彼此的预算
+
+use super::poll_state::UNCONSTRAINED;
+use crate::percpu;
+use core::future::Future;
+use core::pin::Pin;
+use core::sync::atomic::Ordering;
+use core::task::{Context, Poll};
+
+/// 每次轮询的预算
+pub const BUDGET: u32 = 128;
+
+/// 以满预算执行一次轮询, 之后恢复为之前的预算
+///
+/// - 轮询中被抢占时预算随线程一起换出, 恢复运行时仍在当前 CPU 上, 所以前后访问的是同一个位置
+pub(super) fn with_budget<R>(poll: impl FnOnce() -> R) -> R {
+    let previous = percpu!(poll).budget.swap(BUDGET, Ordering::Relaxed);
+    let result = poll();
+    percpu!(poll).budget.store(previous, Ordering::Relaxed);
+    result
+}
+
+/// 扣一点预算, 预算用完时唤醒当前任务并返回 `Pending`
+///
+/// - 在 `Stream::poll_next` 等手写的 poll 函数开头调用
+pub fn poll_budget(cx: &mut Context) -> Poll<()> {
+    let budget = &percpu!(poll).budget;
+    let remaining = budget.load(Ordering::Relaxed);
+    match remaining {
+        UNCONSTRAINED => Poll::Ready(()),
+        0 => {
+            cx.waker().wake_by_ref();
+            Poll::Pending
+        }
+        _ => {
+            budget.store(remaining - 1, Ordering::Relaxed);
+            Poll::Ready(())
+        }
+    }
+}
+
+/// 扣一点预算, 预算用完时让出一次
+pub async fn consume_budget() {
+    core::future::poll_fn(poll_budget).await
+}
+
+/// 让出执行器一次, 让其他就绪的任务先运行
+pub fn yield_now() -> YieldNow {
+    YieldNow { yielded: false }
+}
+
+#[must_use = "futures do nothing unless you `.await` or poll them"]
+pub struct YieldNow {
+    yielded: bool,
+}
+
+impl Future for YieldNow {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        if self.yielded {
+            return Poll::Ready(());
+        }
+        self.yielded = true;
+        cx.waker().wake_by_ref();
+        Poll::Pending
+    }
+}
+
+#[test_case]
+fn test_budget_belongs_to_thread() {
+    use crate::thread;
+
+    let budget = || percpu!(poll).budget.load(Ordering::Relaxed);
+    with_budget(|| {
+        percpu!(poll).budget.store(BUDGET - 1, Ordering::Relaxed);
+        // 另一个线程用完自己的预算, 不影响这个线程的预算
+        let other = thread::spawn_thread(|| {
+            with_budget(|| percpu!(poll).budget.store(0, Ordering::Relaxed));
+        });
+        thread::join(other).expect("failed to join thread");
+        assert_eq!(budget(), BUDGET - 1);
+    });
+    assert_eq!(budget(), UNCONSTRAINED);
+}
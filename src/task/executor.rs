@@ -1,12 +1,67 @@
-use super::{Task, TaskId};
+use super::stats::{Report, TaskInfo, TaskLabel, TaskSnapshot};
+use super::{coop, join, JoinHandle, Priority, Task, TaskId};
+use crate::interrupts::{deferred, stats};
 use crate::println;
-use alloc::{collections::BTreeMap, sync::Arc};
+use crate::sync::IrqSafeMutex;
+use crate::thread::{self, Waiter};
+use crate::watchdog;
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::sync::{Arc, Weak};
+use alloc::vec::Vec;
+use core::future::Future;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicUsize, Ordering};
 use core::task::{Context, Poll, Waker};
-use crossbeam_queue::ArrayQueue;
+use crossbeam_queue::SegQueue;
+
+/// 通过 [`Spawner`] 提交, 还没有被执行器接收的任务
+type SpawnedFuture = Pin<Box<dyn Future<Output = ()> + Send>>;
+
+/// 通过 [`Spawner`] 提交的任务: future, 名字和优先级
+type Spawned = (SpawnedFuture, Option<&'static str>, Priority);
+
+/// 执行器中所有任务的统计, 与 [`Spawner`] 共享, 在执行器之外也能查看任务表
+type Registry = Arc<IrqSafeMutex<BTreeMap<TaskId, Arc<TaskInfo>>>>;
+
+/// 就绪队列, 只储存任务号
+///
+/// - 可以增长, 但同一个任务最多在队列中出现一次(见 [`TaskWaker::wake_task`]),
+///   所以长度不会超过执行器中的任务数
+type TaskQueue = Arc<SegQueue<TaskId>>;
+
+/// 全局 [`spawn`] 使用的执行器, 即最近一次开始运行的执行器, 它被丢弃后提交返回
+/// [`SpawnError::NoExecutor`]
+static GLOBAL_SPAWNER: IrqSafeMutex<Option<Spawner>> = IrqSafeMutex::new(None);
+
+/// 执行器默认最多容纳的任务数, 包括已经提交还没有被接收的任务
+pub const DEFAULT_MAX_TASKS: usize = 256;
+
+/// 生成任务失败的原因, 失败时 future 不会被运行, 直接丢弃
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SpawnError {
+    /// 执行器中的任务数已经达到上限
+    TooManyTasks,
+    /// 还没有执行器运行过, 或者执行器已经被丢弃
+    NoExecutor,
+}
+
+/// 占用一个任务名额, 任务数已经达到 `max_tasks` 时失败
+fn reserve_slot(task_count: &AtomicUsize, max_tasks: usize) -> Result<(), SpawnError> {
+    task_count
+        .fetch_update(Ordering::AcqRel, Ordering::Acquire, |count| {
+            (count < max_tasks).then_some(count + 1)
+        })
+        .map(|_| ())
+        .map_err(|_| SpawnError::TooManyTasks)
+}
 
 struct TaskWaker {
     task_id: TaskId,
-    task_queue: Arc<ArrayQueue<TaskId>>,
+    task_queue: TaskQueue,
+    info: Arc<TaskInfo>,
+    /// 空闲时停下的执行器线程
+    waiter: Arc<Waiter>,
 }
 
 /// 因爲 Future::poll 方法接受一個 Context 實例作爲參數，這個實例只能從 Waker 類型構建
@@ -26,37 +81,187 @@ impl Default for TaskWaker {
     fn default() -> Self {
         Self {
             task_id: TaskId(0),
-            task_queue: Arc::new(ArrayQueue::new(1)),
+            task_queue: Arc::new(SegQueue::new()),
+            info: Arc::new(TaskInfo::new(TaskId(0), None, Priority::Normal)),
+            waiter: Arc::new(Waiter::new()),
         }
     }
 }
 
 impl TaskWaker {
-    fn new(task_id: TaskId, task_queue: Arc<ArrayQueue<TaskId>>) -> Self {
+    fn new(
+        task_id: TaskId,
+        task_queue: TaskQueue,
+        info: Arc<TaskInfo>,
+        waiter: Arc<Waiter>,
+    ) -> Self {
         TaskWaker {
             task_id,
             task_queue,
+            info,
+            waiter,
         }
     }
 
     /// 从TaskWaker创建一个Waker实例
-    fn new_waker(task_id: TaskId, task_queue: Arc<ArrayQueue<TaskId>>) -> Waker {
+    fn new_waker(
+        task_id: TaskId,
+        task_queue: TaskQueue,
+        info: Arc<TaskInfo>,
+        waiter: Arc<Waiter>,
+    ) -> Waker {
         // 這個 from 方法負責構建一個 RawWakerVTable 和一個 RawWaker 實例
-        Waker::from(Arc::new(Self::new(task_id, task_queue)))
+        Waker::from(Arc::new(Self::new(task_id, task_queue, info, waiter)))
     }
 
     /// 具体的waker唤醒逻辑
     ///
     /// - 将task_id重新加入task_queue, 这样执行器在下一次轮询时会访问到此task
+    /// - 任务已经在队列中时只计数, 不重复加入, 一连串唤醒不会让队列增长
+    /// - 执行器线程空闲时把它唤醒
     fn wake_task(&self) {
-        self.task_queue.push(self.task_id).expect("task_queue full");
+        if self.info.record_wake() {
+            self.task_queue.push(self.task_id);
+            self.waiter.wake();
+        }
     }
 }
 
+/// 每轮最多轮询的次数, 之后回到 [`Executor::run`] 处理推迟的工作和新提交的任务
+///
+/// - 不断让出的任务会一直留在就绪队列中, 没有这个限制一轮轮询不会结束
+const POLLS_PER_ROUND: usize = 64;
+
+/// 低优先级队列非空时, 连续轮询多少个更高优先级的任务后先轮询它一次
+const STARVATION_LIMIT: u32 = 8;
+
+/// 执行器
+///
+/// - 每个优先级一个就绪队列, 总是先轮询优先级最高的非空队列
+/// - 为防止低优先级的任务饿死, 非空的队列被跳过 [`STARVATION_LIMIT`] 次后优先轮询一次
+/// - 任务数有上限, 达到上限时生成任务返回 [`SpawnError::TooManyTasks`]
 pub struct Executor {
     tasks: BTreeMap<TaskId, Task>,
-    task_queue: Arc<ArrayQueue<TaskId>>,
+    /// 按 [`Priority`] 排列的就绪队列
+    task_queues: [TaskQueue; Priority::COUNT],
+    /// 每个就绪队列非空但被跳过的次数
+    skipped: [u32; Priority::COUNT],
     waker_cache: BTreeMap<TaskId, Waker>,
+    /// 其他任务, 线程和延迟工作通过 [`Spawner`] 提交的任务, 每轮轮询前接收
+    spawned: Arc<SegQueue<Spawned>>,
+    registry: Registry,
+    /// 已经生成还没有完成的任务数, 包括还没有被接收的任务
+    task_count: Arc<AtomicUsize>,
+    max_tasks: usize,
+    /// 空闲时停下的执行器线程, 唤醒任务和提交任务时唤醒它
+    waiter: Arc<Waiter>,
+    /// 每次轮询后打印任务号, 结果和耗时
+    tracing: bool,
+}
+
+/// 向执行器提交任务的句柄, 可以克隆, 在任务, 其他线程和延迟工作中使用
+///
+/// - 提交的任务在执行器下一轮轮询前加入执行器, 所以 future 必须是 `Send`; 执行器空闲时会被唤醒
+/// - 不延长执行器的生命周期, 执行器被丢弃后提交返回 [`SpawnError::NoExecutor`]
+#[derive(Clone)]
+pub struct Spawner {
+    spawned: Weak<SegQueue<Spawned>>,
+    registry: Registry,
+    task_count: Arc<AtomicUsize>,
+    max_tasks: usize,
+    waiter: Arc<Waiter>,
+}
+
+impl Spawner {
+    /// 以 [`Priority::Normal`] 提交任务, 返回的句柄可以等待任务的输出
+    pub fn spawn<F>(&self, future: F) -> Result<JoinHandle<F::Output>, SpawnError>
+    where
+        F: Future + Send + 'static,
+        F::Output: Send + 'static,
+    {
+        self.spawn_with_priority(future, Priority::Normal)
+    }
+
+    /// 以指定的优先级提交任务
+    pub fn spawn_with_priority<F>(
+        &self,
+        future: F,
+        priority: Priority,
+    ) -> Result<JoinHandle<F::Output>, SpawnError>
+    where
+        F: Future + Send + 'static,
+        F::Output: Send + 'static,
+    {
+        self.submit(None, future, priority)
+    }
+
+    /// 提交带名字的任务, 名字出现在任务表和跟踪输出中
+    pub fn spawn_named<F>(
+        &self,
+        name: &'static str,
+        future: F,
+        priority: Priority,
+    ) -> Result<JoinHandle<F::Output>, SpawnError>
+    where
+        F: Future + Send + 'static,
+        F::Output: Send + 'static,
+    {
+        self.submit(Some(name), future, priority)
+    }
+
+    fn submit<F>(
+        &self,
+        name: Option<&'static str>,
+        future: F,
+        priority: Priority,
+    ) -> Result<JoinHandle<F::Output>, SpawnError>
+    where
+        F: Future + Send + 'static,
+        F::Output: Send + 'static,
+    {
+        let spawned = self.spawned.upgrade().ok_or(SpawnError::NoExecutor)?;
+        reserve_slot(&self.task_count, self.max_tasks)?;
+        let (future, handle) = join::wrap(future);
+        spawned.push((Box::pin(future), name, priority));
+        self.waiter.wake();
+        Ok(handle)
+    }
+
+    /// 执行器中所有任务的统计值, 不包括还没有被执行器接收的任务
+    pub fn tasks(&self) -> Vec<TaskSnapshot> {
+        snapshot(&self.registry)
+    }
+}
+
+fn snapshot(registry: &Registry) -> Vec<TaskSnapshot> {
+    registry
+        .lock()
+        .values()
+        .map(|info| info.snapshot())
+        .collect()
+}
+
+/// 向最近一次开始运行的执行器提交任务
+///
+/// - 还没有执行器运行过, 或者它已经被丢弃时返回 [`SpawnError::NoExecutor`]
+pub fn spawn<F>(future: F) -> Result<JoinHandle<F::Output>, SpawnError>
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    let spawner = GLOBAL_SPAWNER.lock().clone();
+    spawner.ok_or(SpawnError::NoExecutor)?.spawn(future)
+}
+
+/// 最近一次开始运行的执行器中所有任务的统计值, 还没有执行器运行过时为空
+pub fn tasks() -> Vec<TaskSnapshot> {
+    let spawner = GLOBAL_SPAWNER.lock().clone();
+    spawner.map(|spawner| spawner.tasks()).unwrap_or_default()
+}
+
+/// 返回可以直接 `println!` 的任务表
+pub fn report() -> Report {
+    Report::new(tasks())
 }
 
 impl Default for Executor {
@@ -65,94 +270,270 @@ impl Default for Executor {
     }
 }
 
+/// 执行器被丢弃后 [`Spawner`] 可能还在, 不应再从它看到已经释放的任务;
+/// 还没有被接收的任务随提交队列一起释放
+impl Drop for Executor {
+    fn drop(&mut self) {
+        self.registry.lock().clear();
+    }
+}
+
 impl Executor {
     pub fn new() -> Self {
+        Self::with_max_tasks(DEFAULT_MAX_TASKS)
+    }
+
+    /// 最多容纳 `max_tasks` 个任务的执行器
+    pub fn with_max_tasks(max_tasks: usize) -> Self {
         Executor {
             tasks: BTreeMap::new(),
-            task_queue: Arc::new(ArrayQueue::new(100)),
+            task_queues: core::array::from_fn(|_| Arc::new(SegQueue::new())),
+            skipped: [0; Priority::COUNT],
             waker_cache: BTreeMap::new(),
+            spawned: Arc::new(SegQueue::new()),
+            registry: Arc::new(IrqSafeMutex::new(BTreeMap::new())),
+            task_count: Arc::new(AtomicUsize::new(0)),
+            max_tasks,
+            waiter: Arc::new(Waiter::new()),
+            tracing: false,
         }
     }
 
-    /// 生成任务加入队列中
-    pub fn spawn(&mut self, task: Task) {
+    /// 返回向这个执行器提交任务的句柄
+    pub fn spawner(&self) -> Spawner {
+        Spawner {
+            spawned: Arc::downgrade(&self.spawned),
+            registry: self.registry.clone(),
+            task_count: self.task_count.clone(),
+            max_tasks: self.max_tasks,
+            waiter: self.waiter.clone(),
+        }
+    }
+
+    /// 开启或关闭跟踪输出, 默认关闭
+    pub fn set_tracing(&mut self, enabled: bool) {
+        self.tracing = enabled;
+    }
+
+    /// 所有任务的统计值
+    pub fn tasks(&self) -> Vec<TaskSnapshot> {
+        snapshot(&self.registry)
+    }
+
+    /// 返回可以直接 `println!` 的任务表
+    pub fn report(&self) -> Report {
+        Report::new(self.tasks())
+    }
+
+    /// 把这个执行器设为全局 [`spawn`] 的目标
+    fn make_global(&self) {
+        *GLOBAL_SPAWNER.lock() = Some(self.spawner());
+    }
+
+    /// 接收通过 [`Spawner`] 提交的任务
+    fn accept_spawned(&mut self) {
+        while let Some((future, name, priority)) = self.spawned.pop() {
+            self.spawn_task(Task::build(name, future, priority));
+        }
+    }
+
+    /// 以 [`Priority::Normal`] 生成任务加入队列中, 返回的句柄可以等待任务的输出
+    pub fn spawn<F>(&mut self, future: F) -> Result<JoinHandle<F::Output>, SpawnError>
+    where
+        F: Future + 'static,
+        F::Output: 'static,
+    {
+        self.spawn_with_priority(future, Priority::Normal)
+    }
+
+    /// 以指定的优先级生成任务
+    pub fn spawn_with_priority<F>(
+        &mut self,
+        future: F,
+        priority: Priority,
+    ) -> Result<JoinHandle<F::Output>, SpawnError>
+    where
+        F: Future + 'static,
+        F::Output: 'static,
+    {
+        reserve_slot(&self.task_count, self.max_tasks)?;
+        let (future, handle) = join::wrap(future);
+        self.spawn_task(Task::with_priority(future, priority));
+        Ok(handle)
+    }
+
+    /// 生成带名字的任务, 名字出现在任务表和跟踪输出中
+    pub fn spawn_named<F>(
+        &mut self,
+        name: &'static str,
+        future: F,
+        priority: Priority,
+    ) -> Result<JoinHandle<F::Output>, SpawnError>
+    where
+        F: Future + 'static,
+        F::Output: 'static,
+    {
+        reserve_slot(&self.task_count, self.max_tasks)?;
+        let (future, handle) = join::wrap(future);
+        self.spawn_task(Task::named(name, future, priority));
+        Ok(handle)
+    }
+
+    /// 加入已经占用了名额的任务
+    fn spawn_task(&mut self, task: Task) {
         let task_id = task.id;
+        let queue = &self.task_queues[task.priority.index()];
+        self.registry.lock().insert(task_id, task.info.clone());
         if self.tasks.insert(task.id, task).is_some() {
             panic!("task with same ID already in tasks");
         }
         // 立即执行首次唤醒
-        self.task_queue.push(task_id).expect("queue full");
+        queue.push(task_id);
+    }
+
+    /// 取出下一个要轮询的任务
+    ///
+    /// - 先看是否有被跳过太多次的队列, 有多个时选优先级最低的
+    /// - 否则取优先级最高的非空队列, 同时给更低优先级的非空队列记一次跳过
+    fn next_ready(&mut self) -> Option<TaskId> {
+        for level in (0..Priority::COUNT).rev() {
+            if self.skipped[level] >= STARVATION_LIMIT {
+                self.skipped[level] = 0;
+                if let Some(task_id) = self.task_queues[level].pop() {
+                    return Some(task_id);
+                }
+            }
+        }
+        let level = (0..Priority::COUNT).find(|&level| !self.task_queues[level].is_empty())?;
+        for lower in level + 1..Priority::COUNT {
+            if !self.task_queues[lower].is_empty() {
+                self.skipped[lower] += 1;
+            }
+        }
+        self.skipped[level] = 0;
+        self.task_queues[level].pop()
     }
 
     /// 轮询队列中的任务
     ///
-    /// - 轮询task_queue中所有的task一遍, 这个队列只储存`id`
+    /// - 按优先级轮询就绪队列中的task, 队列只储存`id`
     /// - 因此pop掉之后不应先task和waker的存储
-    /// - 但这意味着一次轮询如果某个task在pending, 那么下次轮询就没有它了
-    /// - 也就是说所有任务执行器只会主动轮询一次
+    /// - 但这意味着一次轮询如果某个task在pending, 那么下次轮询就没有它了, 直到它被唤醒
+    /// - 一轮最多轮询 [`POLLS_PER_ROUND`] 次
     fn run_ready_tasks(&mut self) {
-        // pattern match
-        let Executor {
-            tasks,
-            task_queue,
-            waker_cache,
-        } = self;
+        self.accept_spawned();
+
+        for _ in 0..POLLS_PER_ROUND {
+            // 就绪队列都为空时退出循环
+            let Some(task_id) = self.next_ready() else {
+                break;
+            };
 
-        // task_queue为空时推出循环, 所以这里并非无限循环
-        while let Some(task_id) = task_queue.pop() {
-            // println!("{:?} {:?}", task_queue.is_empty(), task_id);
+            // pattern match
+            let Executor {
+                tasks,
+                task_queues,
+                waker_cache,
+                registry,
+                task_count,
+                waiter,
+                tracing,
+                ..
+            } = self;
 
             let task = match tasks.get_mut(&task_id) {
                 Some(task) => task,
                 None => continue, // 任务不存在
             };
 
-            let waker = waker_cache
-                .entry(task_id)
-                .or_insert_with(|| TaskWaker::new_waker(task_id, task_queue.clone()));
+            let task_queue = &task_queues[task.priority.index()];
+            let waker = waker_cache.entry(task_id).or_insert_with(|| {
+                TaskWaker::new_waker(
+                    task_id,
+                    task_queue.clone(),
+                    task.info.clone(),
+                    waiter.clone(),
+                )
+            });
 
             // 创建context准备poll一个task, 这里的context最关键的是包含task_queue的Arc引用
             // 也就是隐含了executor的信息, 因为task_queue是由执行器创建的, 也是执行器轮询的依据
             let mut context = Context::from_waker(waker);
             // 这里poll一个task也就是poll一个future, 并传入context包含执行器信息, 方便在pending的时候唤醒
-            match task.poll(&mut context) {
-                // 如果人物完成, 就把执行器中的所有相关信息删除, 包括tasks, task_queue, waker_cache
-                Poll::Ready(()) => {
-                    println!("remove once");
-
-                    tasks.remove(&task_id);
-                    waker_cache.remove(&task_id);
-                }
-                // 这里并不会在把还在pending的task id加回队列, 所以下次轮询不会再询问这个任务
-                Poll::Pending => {
-                    println!("poll only once");
-                }
+            let info = task.info.clone();
+            info.record_poll_start();
+            watchdog::poll_started(task_id.0);
+            let start = stats::rdtsc();
+            let result = coop::with_budget(|| task.poll(&mut context));
+            let cycles = stats::rdtsc().wrapping_sub(start);
+            watchdog::poll_finished();
+            info.record_poll(cycles);
+            if *tracing {
+                let outcome = match result {
+                    Poll::Ready(()) => "ready",
+                    Poll::Pending => "pending",
+                };
+                println!(
+                    "[executor] task {}: {} after {} cycles",
+                    TaskLabel(&info),
+                    outcome,
+                    cycles
+                );
+            }
+            // 如果人物完成, 就把执行器中的所有相关信息删除, 包括tasks, registry, waker_cache
+            // 还在pending的task id不会被加回队列, 被唤醒之前不会再询问这个任务
+            if result.is_ready() {
+                tasks.remove(&task_id);
+                waker_cache.remove(&task_id);
+                registry.lock().remove(&task_id);
+                task_count.fetch_sub(1, Ordering::AcqRel);
             }
         }
     }
 
+    /// 运行到没有就绪的任务和推迟的工作为止, 之后返回
+    ///
+    /// - 还在等待唤醒的任务留在执行器中, 下一次运行时继续
+    pub fn run_until_idle(&mut self) {
+        self.make_global();
+        while !self.is_idle() {
+            deferred::run_pending();
+            self.run_ready_tasks();
+        }
+    }
+
     /// 执行器运行暴露的接口
+    ///
+    /// - 每轮先执行中断推迟下来的工作, 它们可能会唤醒任务
     pub fn run(&mut self) -> ! {
+        self.make_global();
         loop {
+            deferred::run_pending();
             self.run_ready_tasks();
             self.sleep_if_idle();
         }
     }
 
+    /// 没有就绪的任务, 新提交的任务和推迟的工作
+    fn is_idle(&self) -> bool {
+        self.task_queues.iter().all(|queue| queue.is_empty())
+            && self.spawned.is_empty()
+            && !deferred::has_pending()
+    }
+
     /// 每次run_ready_tasks()只会轮询一遍
     ///
     /// - 之后如果所有任务都在pending, 那么大部分时间都会在执行这个idle函数
-    /// - 如果有其他的任务要执行, 比如说在其他线程, 可以在这里yield
+    /// - 执行器运行在线程中, 空闲时在 [`thread::park`] 中把 CPU 让给其他线程, 直到唤醒任务,
+    ///   提交任务或推迟中断工作的一方把它唤醒
+    /// - 先登记再检查是否空闲, 检查之后到来的唤醒会让 `park` 立即返回
     fn sleep_if_idle(&self) {
-        use x86_64::instructions::interrupts::{self, enable_and_hlt};
-
-        interrupts::disable();
-        // 當 task_queue 爲空時執行 [hlt 指令]。這個指令將 CPU 進入睡眠狀態，直到下一個中斷到來
-        // task_queue为空说明不需要轮询, 可以一直等待知道出现新的中断
-        if self.task_queue.is_empty() {
-            enable_and_hlt();
-        } else {
-            interrupts::enable();
+        self.waiter.register();
+        deferred::WAITER.register();
+        if self.is_idle() {
+            thread::park();
         }
+        self.waiter.cancel();
+        deferred::WAITER.cancel();
     }
 }
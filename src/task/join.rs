@@ -0,0 +1,177 @@
+//! 任务的输出, [`JoinHandle`] 和取消
+//!
+//! - 执行器把 `Future<Output = T>` 包装成输出为 `()` 的 [`Joinable`] 任务, 完成时把输出放进与句柄共享的状态
+//! - [`JoinHandle`] 本身也是 future, 等待任务完成并取出 `Result<T, JoinError>`
+//! - 丢弃句柄不影响任务运行(detach), 任务完成后输出随共享状态一起释放
+//! - [`JoinHandle::abort`] 或 [`CancellationToken::cancel`] 只做标记并唤醒任务, 执行器下一次轮询它时
+//!   丢弃内部的 future 并以 `Ready` 结束, 走和正常完成相同的清理路径; 等待者得到 [`JoinError::Cancelled`]
+//! - 任务没有完成就被丢弃(例如执行器本身被丢弃)时, 等待者同样得到 [`JoinError::Cancelled`]
+
+use alloc::sync::Arc;
+use core::future::Future;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::task::{Context, Poll};
+use futures_util::task::AtomicWaker;
+use spin::Mutex;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum JoinError {
+    /// 任务在完成之前被取消
+    Cancelled,
+}
+
+/// 取消请求, 与输出类型无关, 由句柄和 [`CancellationToken`] 共享
+struct CancelState {
+    cancelled: AtomicBool,
+    /// 任务最近一次被轮询时的 waker, 取消时用它把任务放回就绪队列
+    task_waker: AtomicWaker,
+}
+
+/// 任务和句柄共享的状态
+struct JoinState<T> {
+    cancel: Arc<CancelState>,
+    result: Mutex<Option<Result<T, JoinError>>>,
+    finished: AtomicBool,
+    /// 等待输出的句柄
+    waker: AtomicWaker,
+}
+
+impl<T> JoinState<T> {
+    fn complete(&self, result: Result<T, JoinError>) {
+        // 任务已经结束, 不再需要唤醒它
+        self.cancel.task_waker.take();
+        *self.result.lock() = Some(result);
+        self.finished.store(true, Ordering::Release);
+        self.waker.wake();
+    }
+}
+
+/// 把 `future` 包装成输出为 `()` 的任务, 完成时把输出交给返回的句柄
+pub(super) fn wrap<F>(future: F) -> (Joinable<F>, JoinHandle<F::Output>)
+where
+    F: Future + 'static,
+    F::Output: 'static,
+{
+    let state = Arc::new(JoinState {
+        cancel: Arc::new(CancelState {
+            cancelled: AtomicBool::new(false),
+            task_waker: AtomicWaker::new(),
+        }),
+        result: Mutex::new(None),
+        finished: AtomicBool::new(false),
+        waker: AtomicWaker::new(),
+    });
+    let handle = JoinHandle {
+        state: state.clone(),
+    };
+    let task = Joinable {
+        future: Some(future),
+        state,
+    };
+    (task, handle)
+}
+
+/// 执行器实际运行的任务: 转交输出, 响应取消
+pub(super) struct Joinable<F: Future> {
+    /// 完成或取消后立即丢弃
+    future: Option<F>,
+    state: Arc<JoinState<F::Output>>,
+}
+
+impl<F: Future> Future for Joinable<F> {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        // 不会移动 `future`, 只会在原地丢弃它
+        let this = unsafe { self.get_unchecked_mut() };
+        let cancel = &this.state.cancel;
+        cancel.task_waker.register(cx.waker());
+        if cancel.cancelled.load(Ordering::Acquire) {
+            this.future = None;
+            this.state.complete(Err(JoinError::Cancelled));
+            return Poll::Ready(());
+        }
+
+        let future = this.future.as_mut().expect("task polled after completion");
+        match unsafe { Pin::new_unchecked(future) }.poll(cx) {
+            Poll::Ready(output) => {
+                this.future = None;
+                this.state.complete(Ok(output));
+                Poll::Ready(())
+            }
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+impl<F: Future> Drop for Joinable<F> {
+    fn drop(&mut self) {
+        if self.future.take().is_some() {
+            self.state.complete(Err(JoinError::Cancelled));
+        }
+    }
+}
+
+/// 等待任务完成并取得它的输出
+pub struct JoinHandle<T> {
+    state: Arc<JoinState<T>>,
+}
+
+impl<T> JoinHandle<T> {
+    /// 任务是否已经完成, 被取消也算完成
+    pub fn is_finished(&self) -> bool {
+        self.state.finished.load(Ordering::Acquire)
+    }
+
+    /// 取消任务, 已经完成的任务不受影响
+    pub fn abort(&self) {
+        self.cancellation_token().cancel();
+    }
+
+    /// 返回可以在别处取消这个任务的令牌
+    pub fn cancellation_token(&self) -> CancellationToken {
+        CancellationToken {
+            state: self.state.cancel.clone(),
+        }
+    }
+}
+
+impl<T> Future for JoinHandle<T> {
+    type Output = Result<T, JoinError>;
+
+    /// - 任务完成后只能取一次输出, 之后再 poll 会 panic
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        // 先注册再检查, 避免错过两者之间的完成
+        self.state.waker.register(cx.waker());
+        if !self.is_finished() {
+            return Poll::Pending;
+        }
+        let result = self
+            .state
+            .result
+            .lock()
+            .take()
+            .expect("JoinHandle polled after completion");
+        Poll::Ready(result)
+    }
+}
+
+/// 取消一个任务的令牌, 可以克隆并交给其他任务, 线程或延迟工作
+#[derive(Clone)]
+pub struct CancellationToken {
+    state: Arc<CancelState>,
+}
+
+impl CancellationToken {
+    /// 请求取消, 任务在执行器下一次轮询它时结束
+    pub fn cancel(&self) {
+        if !self.state.cancelled.swap(true, Ordering::AcqRel) {
+            self.state.task_waker.wake();
+        }
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.state.cancelled.load(Ordering::Acquire)
+    }
+}
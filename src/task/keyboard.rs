@@ -1,20 +1,47 @@
 #![allow(dead_code)]
 
+use crate::interrupts::deferred::{self, Work};
+use crate::interrupts::{self, irq};
+use crate::task::coop;
 use crate::{print, println};
 use conquer_once::spin::OnceCell;
 use core::pin::Pin;
-use core::task::{Context, Poll};
+use core::task::{ready, Context, Poll};
 use crossbeam_queue::ArrayQueue;
 use futures_util::stream::{Stream, StreamExt};
 use futures_util::task::AtomicWaker;
 use pc_keyboard::{layouts, DecodedKey, HandleControl, Keyboard, ScancodeSet1};
+use x86_64::instructions::port::Port;
 
 static SCANCODE_QUEUE: OnceCell<ArrayQueue<u8>> = OnceCell::uninit();
 static WAKER: AtomicWaker = AtomicWaker::new();
 
-/// 由keyboard_interrupt_handler()调用
+/// 注册键盘中断处理函数
+pub fn init() {
+    interrupts::register_irq(irq::KEYBOARD, keyboard_interrupt_handler)
+        .expect("failed to register keyboard IRQ");
+}
+
+/// 键盘中断处理函数, 从PS/2数据端口读出扫描码
+///
+/// - 中断上下文中只读端口, 其余工作通过[`deferred`]推迟到普通上下文执行
+fn keyboard_interrupt_handler() {
+    let mut port = Port::new(0x60);
+    let scancode: u8 = unsafe { port.read() };
+
+    deferred::defer(
+        irq::KEYBOARD,
+        Work::new(add_scancode_work, usize::from(scancode)),
+    );
+}
+
+fn add_scancode_work(scancode: usize) {
+    add_scancode(scancode as u8);
+}
+
+/// 由延迟工作队列在普通上下文中调用
 ///
-/// - 这里是中断上下文, 所以must not block or allocate
+/// - 这里已经不是中断上下文, 可以安全地打印警告
 /// - 这里把scancode加入异步流[`Stream`]
 /// - 最后唤醒异步执行器尝试进行处理
 pub(crate) fn add_scancode(scancode: u8) {
@@ -96,6 +123,9 @@ impl Stream for ScancodeStream {
             .try_get()
             .expect("scancode queue not initialized");
 
+        // 一连串按键不应让这个任务一直占着执行器
+        ready!(coop::poll_budget(cx));
+
         if let Some(scancode) = queue.pop() {
             return Poll::Ready(Some(scancode));
         }
@@ -1,23 +1,79 @@
+pub mod coop;
 pub mod executor;
+mod join;
 pub mod keyboard;
+pub(crate) mod poll_state;
 pub mod simple_executor;
+pub mod stats;
+
+pub use coop::yield_now;
+pub use executor::{report, spawn, tasks, SpawnError, Spawner};
+pub use join::{CancellationToken, JoinError, JoinHandle};
 
 use alloc::boxed::Box;
+use alloc::sync::Arc;
 use core::fmt::Debug;
 use core::sync::atomic::{AtomicU64, Ordering};
 use core::task::{Context, Poll};
 use core::{future::Future, pin::Pin};
+use stats::TaskInfo;
+
+/// 任务的优先级, 就绪的高优先级任务先被轮询
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default)]
+pub enum Priority {
+    /// 中断驱动的输入输出, 如键盘
+    High,
+    #[default]
+    Normal,
+    /// 后台工作
+    Low,
+}
+
+impl Priority {
+    const COUNT: usize = 3;
+
+    fn index(self) -> usize {
+        self as usize
+    }
+}
 
 pub struct Task {
     id: TaskId,
+    priority: Priority,
     future: Pin<Box<dyn Future<Output = ()>>>,
+    /// 运行统计, 与任务的 waker 共享
+    info: Arc<TaskInfo>,
 }
 
 impl Task {
     pub fn new(future: impl Future<Output = ()> + 'static) -> Task {
+        Task::with_priority(future, Priority::Normal)
+    }
+
+    pub fn with_priority(future: impl Future<Output = ()> + 'static, priority: Priority) -> Task {
+        Task::build(None, future, priority)
+    }
+
+    /// 带名字的任务, 名字出现在任务表和跟踪输出中
+    pub fn named(
+        name: &'static str,
+        future: impl Future<Output = ()> + 'static,
+        priority: Priority,
+    ) -> Task {
+        Task::build(Some(name), future, priority)
+    }
+
+    fn build(
+        name: Option<&'static str>,
+        future: impl Future<Output = ()> + 'static,
+        priority: Priority,
+    ) -> Task {
+        let id = TaskId::new();
         Task {
-            id: TaskId::new(),
+            id,
+            priority,
             future: Box::pin(future),
+            info: Arc::new(TaskInfo::new(id, name, priority)),
         }
     }
 
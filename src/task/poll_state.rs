@@ -0,0 +1,59 @@
+//! 当前线程的轮询状态
+//!
+//! - 执行器运行在内核线程中, 可能有多个执行器线程互相抢占. 协作预算(见 [`coop`](super::coop))
+//!   和看门狗记录的正在轮询的任务(见 [`watchdog`](crate::watchdog))都属于正在轮询任务的线程,
+//!   不能是全局的
+//! - 正在运行的线程的状态放在 CPU 私有数据中, 访问时不需要加锁; 切换线程时调度器把它保存到
+//!   被换下的线程中, 再换入下一个线程的状态
+
+use core::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+
+/// 表示不受限制的预算
+pub(crate) const UNCONSTRAINED: u32 = u32::MAX;
+/// 表示没有在轮询任务
+pub(crate) const NO_TASK: u64 = u64::MAX;
+
+/// CPU 私有数据中当前线程的轮询状态
+pub struct PollState {
+    /// 这次轮询剩余的协作预算
+    pub(crate) budget: AtomicU32,
+    /// 正在轮询的任务号和开始轮询时的 tick
+    pub(crate) task: AtomicU64,
+    pub(crate) start: AtomicU64,
+}
+
+/// 线程被换下时保存的 [`PollState`]
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct SavedPoll {
+    budget: u32,
+    task: u64,
+    start: u64,
+}
+
+impl SavedPoll {
+    /// 没有在轮询任务的线程
+    pub(crate) const IDLE: SavedPoll = SavedPoll {
+        budget: UNCONSTRAINED,
+        task: NO_TASK,
+        start: 0,
+    };
+}
+
+impl PollState {
+    pub(crate) const fn new() -> Self {
+        PollState {
+            budget: AtomicU32::new(UNCONSTRAINED),
+            task: AtomicU64::new(NO_TASK),
+            start: AtomicU64::new(0),
+        }
+    }
+
+    /// 切换线程时调用: 返回被换下的线程的状态, 换入下一个线程的状态 `next`
+    pub(crate) fn switch(&self, next: SavedPoll) -> SavedPoll {
+        SavedPoll {
+            budget: self.budget.swap(next.budget, Ordering::Relaxed),
+            task: self.task.swap(next.task, Ordering::Relaxed),
+            start: self.start.swap(next.start, Ordering::Relaxed),
+        }
+    }
+}
@@ -0,0 +1,168 @@
+//! 任务的运行统计
+//!
+//! - 每个任务有一个 [`TaskInfo`], 由执行器和任务的 waker 共享: 执行器记录轮询次数和耗时,
+//!   waker 记录唤醒次数, 两者一起维护任务的 [`TaskState`]
+//! - 耗时以 TSC 周期为单位, 与 [`interrupts::stats`](crate::interrupts::stats) 一致
+//! - [`Executor::tasks`](super::executor::Executor::tasks) 和 [`tasks`](super::executor::tasks)
+//!   返回快照, [`Report`] 格式化为适合控制台输出的表格
+
+use super::{Priority, TaskId};
+use alloc::vec::Vec;
+use core::fmt;
+use core::sync::atomic::{AtomicU64, AtomicU8, Ordering};
+
+/// 任务的状态
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum TaskState {
+    /// 在就绪队列中等待轮询
+    Ready,
+    /// 正在被轮询
+    Running,
+    /// 返回了 `Pending`, 等待唤醒
+    Waiting,
+}
+
+impl TaskState {
+    fn from_u8(state: u8) -> TaskState {
+        match state {
+            s if s == TaskState::Ready as u8 => TaskState::Ready,
+            s if s == TaskState::Running as u8 => TaskState::Running,
+            _ => TaskState::Waiting,
+        }
+    }
+}
+
+/// 单个任务的计数器
+pub(super) struct TaskInfo {
+    id: TaskId,
+    name: Option<&'static str>,
+    priority: Priority,
+    state: AtomicU8,
+    polls: AtomicU64,
+    wakes: AtomicU64,
+    total_cycles: AtomicU64,
+    max_cycles: AtomicU64,
+}
+
+impl TaskInfo {
+    /// 新任务立即被放进就绪队列, 所以初始状态为 [`TaskState::Ready`]
+    pub(super) fn new(id: TaskId, name: Option<&'static str>, priority: Priority) -> Self {
+        TaskInfo {
+            id,
+            name,
+            priority,
+            state: AtomicU8::new(TaskState::Ready as u8),
+            polls: AtomicU64::new(0),
+            wakes: AtomicU64::new(0),
+            total_cycles: AtomicU64::new(0),
+            max_cycles: AtomicU64::new(0),
+        }
+    }
+
+    /// 由 waker 调用, 任务已经在就绪队列中时返回 `false`
+    pub(super) fn record_wake(&self) -> bool {
+        self.wakes.fetch_add(1, Ordering::Relaxed);
+        self.state.swap(TaskState::Ready as u8, Ordering::AcqRel) != TaskState::Ready as u8
+    }
+
+    pub(super) fn record_poll_start(&self) {
+        self.state
+            .store(TaskState::Running as u8, Ordering::Release);
+    }
+
+    /// 记录一次轮询的耗时
+    ///
+    /// - 轮询期间被唤醒(例如让出)的任务保持 [`TaskState::Ready`]
+    pub(super) fn record_poll(&self, cycles: u64) {
+        self.polls.fetch_add(1, Ordering::Relaxed);
+        self.total_cycles.fetch_add(cycles, Ordering::Relaxed);
+        self.max_cycles.fetch_max(cycles, Ordering::Relaxed);
+        let _ = self.state.compare_exchange(
+            TaskState::Running as u8,
+            TaskState::Waiting as u8,
+            Ordering::AcqRel,
+            Ordering::Relaxed,
+        );
+    }
+
+    pub(super) fn snapshot(&self) -> TaskSnapshot {
+        TaskSnapshot {
+            id: self.id.0,
+            name: self.name,
+            priority: self.priority,
+            state: TaskState::from_u8(self.state.load(Ordering::Acquire)),
+            polls: self.polls.load(Ordering::Relaxed),
+            wakes: self.wakes.load(Ordering::Relaxed),
+            total_cycles: self.total_cycles.load(Ordering::Relaxed),
+            max_cycles: self.max_cycles.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// 某个任务在某一时刻的统计值
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TaskSnapshot {
+    pub id: u64,
+    pub name: Option<&'static str>,
+    pub priority: Priority,
+    pub state: TaskState,
+    pub polls: u64,
+    pub wakes: u64,
+    pub total_cycles: u64,
+    pub max_cycles: u64,
+}
+
+impl TaskSnapshot {
+    /// 平均每次轮询耗费的周期数
+    pub fn average_cycles(&self) -> u64 {
+        self.total_cycles.checked_div(self.polls).unwrap_or(0)
+    }
+}
+
+/// 格式化后的任务表, 按任务号排序
+pub struct Report(Vec<TaskSnapshot>);
+
+impl Report {
+    pub(super) fn new(mut tasks: Vec<TaskSnapshot>) -> Self {
+        tasks.sort_by_key(|task| task.id);
+        Report(tasks)
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:>4}  {:<20} {:<6} {:<7} {:>8} {:>8} {:>10} {:>10}",
+            "ID", "NAME", "PRIO", "STATE", "POLLS", "WAKES", "AVG CYC", "MAX CYC"
+        )?;
+        for task in &self.0 {
+            write!(
+                f,
+                "\n{:>4}  {:<20} {:<6} {:<7} {:>8} {:>8} {:>10} {:>10}",
+                task.id,
+                task.name.unwrap_or("-"),
+                alloc::format!("{:?}", task.priority),
+                alloc::format!("{:?}", task.state),
+                task.polls,
+                task.wakes,
+                task.average_cycles(),
+                task.max_cycles
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// 跟踪输出中的任务, 形如 `3 (keyboard)`
+pub(super) struct TaskLabel<'a>(pub(super) &'a TaskInfo);
+
+impl fmt::Display for TaskLabel<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.0.name {
+            Some(name) => write!(f, "{} ({})", self.0.id.0, name),
+            None => write!(f, "{}", self.0.id.0),
+        }
+    }
+}
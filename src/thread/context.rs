@@ -0,0 +1,68 @@
+//! 线程上下文切换
+//!
+//! - 切换发生在普通的函数调用中, 调用者保存的寄存器已经由编译器处理,
+//!   这里只需要保存被调用者保存的寄存器(rbx, rbp, r12-r15)和栈指针
+//! - 中断标志不在这里保存, 调用者总是在关中断时切换, 恢复后由调用者自己恢复中断状态
+
+use core::arch::global_asm;
+use x86_64::VirtAddr;
+
+global_asm!(
+    ".global switch_context",
+    // rdi: 保存当前栈指针的位置, rsi: 新线程的栈指针
+    "switch_context:",
+    "push rbp",
+    "push rbx",
+    "push r12",
+    "push r13",
+    "push r14",
+    "push r15",
+    "mov [rdi], rsp",
+    "mov rsp, rsi",
+    "pop r15",
+    "pop r14",
+    "pop r13",
+    "pop r12",
+    "pop rbx",
+    "pop rbp",
+    "ret",
+    "",
+    // 新线程第一次被切换到时从这里开始执行, r12 中是入口参数
+    "thread_trampoline:",
+    "mov rdi, r12",
+    "xor ebp, ebp",
+    "call {start}",
+    "ud2",
+    start = sym super::thread_start,
+);
+
+extern "C" {
+    fn switch_context(save_rsp: *mut u64, new_rsp: u64);
+    fn thread_trampoline();
+}
+
+/// 保存当前上下文到 `save_rsp`, 切换到栈指针为 `new_rsp` 的上下文
+///
+/// # Safety
+/// 必须在关中断时调用, `new_rsp` 必须是之前由 [`switch`] 保存的或由 [`init_stack`] 构造的栈指针
+pub unsafe fn switch(save_rsp: *mut u64, new_rsp: u64) {
+    switch_context(save_rsp, new_rsp);
+}
+
+/// 在新栈顶构造初始上下文, 返回可以交给 [`switch`] 的栈指针
+///
+/// - 栈上依次是 r15, r14, r13, r12, rbx, rbp 和返回地址 `thread_trampoline`
+/// - `arg` 放在 r12 中, 由 `thread_trampoline` 传给 `thread_start`
+///
+/// # Safety
+/// `stack_top` 必须指向一段已映射且未被使用的栈的顶端
+pub unsafe fn init_stack(stack_top: VirtAddr, arg: u64) -> u64 {
+    let top = stack_top.align_down(16u64).as_u64();
+    let frame = [0, 0, 0, arg, 0, 0, thread_trampoline as usize as u64];
+    let rsp = top - (frame.len() * 8) as u64;
+    let ptr = rsp as *mut u64;
+    for (i, value) in frame.iter().enumerate() {
+        ptr.add(i).write(*value);
+    }
+    rsp
+}
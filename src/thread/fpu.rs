@@ -0,0 +1,122 @@
+//! 线程的 x87/SSE/AVX 寄存器状态
+//!
+//! - 每个线程有一块扩展状态保存区, 大小由 CPUID 给出; 启用了 XSAVE 时用 `XSAVE`/`XRSTOR`,
+//!   否则用 `FXSAVE`/`FXRSTOR`
+//! - 懒保存: 切换线程时只设置 CR0.TS, 新线程第一次使用这些寄存器时触发 #NM, 由 [`handle_device_not_available`]
+//!   把寄存器保存到上一个使用者的保存区, 再载入当前线程的状态. 不使用 SIMD 的线程(包括全部内核代码)
+//!   不产生任何保存开销
+//! - 寄存器中是哪个线程的状态记录在 CPU 私有数据中. 线程不在 CPU 之间迁移, 所以一个线程的状态
+//!   只会留在一个 CPU 的寄存器里
+
+use crate::cpu;
+use crate::percpu;
+use alloc::alloc::{alloc_zeroed, dealloc, Layout};
+use core::arch::asm;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use x86_64::registers::control::{Cr0, Cr0Flags};
+
+/// `FXSAVE` 保存区的大小, 也是 `XSAVE` 保存区中传统部分的大小
+const FXSAVE_AREA_SIZE: usize = 512;
+/// 保存区的对齐, `XSAVE` 要求 64 字节
+const AREA_ALIGN: usize = 64;
+
+/// 初始的 x87 控制字和 MXCSR: 屏蔽所有浮点异常, 就近舍入
+const INITIAL_FCW: u16 = 0x037f;
+const INITIAL_MXCSR: u32 = 0x1f80;
+
+static USE_XSAVE: AtomicBool = AtomicBool::new(false);
+static AREA_SIZE: AtomicUsize = AtomicUsize::new(FXSAVE_AREA_SIZE);
+
+/// 根据当前 CPU 启用的扩展确定保存方式和保存区大小, 在 [`cpu::init`] 之后, 创建线程之前调用
+pub(super) fn init() {
+    if cpu::xsave_enabled() {
+        USE_XSAVE.store(true, Ordering::Relaxed);
+        AREA_SIZE.store(cpu::xsave_area_size(), Ordering::Relaxed);
+    }
+}
+
+/// 一个线程的扩展状态保存区
+pub(super) struct FpuState {
+    area: *mut u8,
+}
+
+// 保存区只由所属线程所在的 CPU 在关中断时访问
+unsafe impl Send for FpuState {}
+
+impl FpuState {
+    /// 初始状态: 寄存器清零, 浮点异常全部屏蔽
+    pub(super) fn new() -> Self {
+        let area = unsafe { alloc_zeroed(layout()) };
+        assert!(!area.is_null(), "failed to allocate FPU state");
+        // 保存区清零后 XSAVE 头部表示所有组件都处于初始状态; 控制字和 MXCSR 总是从传统部分载入
+        unsafe {
+            area.cast::<u16>().write(INITIAL_FCW);
+            area.add(24).cast::<u32>().write(INITIAL_MXCSR);
+        }
+        FpuState { area }
+    }
+}
+
+impl Drop for FpuState {
+    fn drop(&mut self) {
+        // 寄存器中的状态属于这个线程时, 不再需要保存它
+        let owner = &percpu!(fpu_owner);
+        let _ = owner.compare_exchange(self.area as usize, 0, Ordering::Relaxed, Ordering::Relaxed);
+        unsafe { dealloc(self.area, layout()) };
+    }
+}
+
+fn layout() -> Layout {
+    Layout::from_size_align(AREA_SIZE.load(Ordering::Relaxed), AREA_ALIGN).unwrap()
+}
+
+/// 切换到保存区为 `next` 的线程时调用: 寄存器中已经是它的状态时清除 CR0.TS, 否则设置
+pub(super) fn switch_to(next: &FpuState) {
+    let percpu = percpu::current();
+    percpu
+        .fpu_current
+        .store(next.area as usize, Ordering::Relaxed);
+    let owned = percpu.fpu_owner.load(Ordering::Relaxed) == next.area as usize;
+    unsafe {
+        Cr0::update(|flags| flags.set(Cr0Flags::TASK_SWITCHED, !owned));
+    }
+}
+
+/// #NM 处理: 保存上一个使用者的寄存器, 载入当前线程的状态
+pub(crate) fn handle_device_not_available() {
+    let percpu = percpu::current();
+    let current = percpu.fpu_current.load(Ordering::Relaxed) as *mut u8;
+    assert!(
+        !current.is_null(),
+        "#NM before the scheduler was initialized"
+    );
+    unsafe {
+        asm!("clts", options(nomem, nostack, preserves_flags));
+        let owner = percpu.fpu_owner.load(Ordering::Relaxed) as *mut u8;
+        if owner != current {
+            if !owner.is_null() {
+                save(owner);
+            }
+            restore(current);
+            percpu.fpu_owner.store(current as usize, Ordering::Relaxed);
+        }
+    }
+}
+
+unsafe fn save(area: *mut u8) {
+    if USE_XSAVE.load(Ordering::Relaxed) {
+        asm!("xsave64 [{}]", in(reg) area, in("eax") u32::MAX, in("edx") u32::MAX,
+            options(nostack, preserves_flags));
+    } else {
+        asm!("fxsave64 [{}]", in(reg) area, options(nostack, preserves_flags));
+    }
+}
+
+unsafe fn restore(area: *const u8) {
+    if USE_XSAVE.load(Ordering::Relaxed) {
+        asm!("xrstor64 [{}]", in(reg) area, in("eax") u32::MAX, in("edx") u32::MAX,
+            options(nostack, preserves_flags));
+    } else {
+        asm!("fxrstor64 [{}]", in(reg) area, options(nostack, preserves_flags));
+    }
+}
@@ -0,0 +1,384 @@
+//! 抢占式内核线程
+//!
+//! - 每个线程有自己的内核栈和保存的上下文, 由时钟中断驱动轮转调度, 死循环的线程不会饿死其他线程
+//! - 调用 [`init`] 时正在运行的代码(`kernel_main`)成为 0 号线程, 之后可以用 [`spawn_thread`]
+//!   创建新线程, 用 [`yield_now`], [`sleep`], [`join`] 主动让出 CPU
+//! - [`park`] 和 [`unpark`] 是更底层的阻塞原语, 供进程等其他模块实现自己的等待;
+//!   [`Waiter`] 在它们之上记录等待者, 供中断处理函数等不知道等待者是谁的一方唤醒
+//! - 每个线程有自己的 x87/SSE/AVX 寄存器状态, 切换时懒保存, 见 [`fpu`]
+//! - 异步执行器可以整体运行在某一个线程中, 见 `main.rs`. 执行器空闲时在 [`park`] 中让出 CPU
+
+mod context;
+pub(crate) mod fpu;
+mod scheduler;
+
+use crate::interrupts::deferred;
+use crate::memory::{self, StackBounds};
+use crate::percpu;
+use crate::task::poll_state::SavedPoll;
+use crate::time;
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use fpu::FpuState;
+use scheduler::Scheduler;
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+use x86_64::registers::control::{Cr3, Cr3Flags};
+use x86_64::structures::paging::PhysFrame;
+
+/// 每个线程的内核栈页数
+const THREAD_STACK_PAGES: u64 = 8;
+
+static SCHEDULER: Mutex<Option<Scheduler>> = Mutex::new(None);
+
+/// 时钟中断置位, 在中断返回前检查, 为真时进行抢占
+static NEED_RESCHED: AtomicBool = AtomicBool::new(false);
+
+#[derive(Debug, Clone, Copy, PartialEq, PartialOrd, Eq, Ord, Hash)]
+pub struct ThreadId(u64);
+
+impl ThreadId {
+    fn new() -> Self {
+        static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+        ThreadId(NEXT_ID.fetch_add(1, Ordering::Relaxed))
+    }
+
+    pub fn as_u64(&self) -> u64 {
+        self.0
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ThreadState {
+    Running,
+    Ready,
+    /// 睡眠到指定的 tick
+    Sleeping(u64),
+    /// 等待其他线程结束
+    Blocked,
+    Finished,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum JoinError {
+    /// 线程不存在, 或者已经被其他线程 join 过
+    NoSuchThread,
+    /// 线程不能 join 自己
+    JoinSelf,
+}
+
+struct Thread {
+    id: ThreadId,
+    state: ThreadState,
+    /// 切换离开时保存的栈指针
+    saved_rsp: u64,
+    /// 0 号线程使用引导程序提供的栈, 没有自己分配的栈
+    stack: Option<StackBounds>,
+    /// 运行用户程序的线程使用的页表, 内核线程为 `None`, 使用内核页表
+    page_table: Option<PhysFrame>,
+    /// 等待这个线程结束的线程
+    joiners: Vec<ThreadId>,
+    /// 没有阻塞时收到的 [`unpark`], 下一次 [`park`] 直接返回
+    unparked: bool,
+    /// 扩展寄存器的保存区
+    fpu: FpuState,
+    /// 换下时保存的轮询状态
+    poll: SavedPoll,
+}
+
+impl Thread {
+    fn new(id: ThreadId, state: ThreadState, stack: Option<StackBounds>) -> Self {
+        Thread {
+            id,
+            state,
+            saved_rsp: 0,
+            stack,
+            page_table: None,
+            joiners: Vec::new(),
+            unparked: false,
+            fpu: FpuState::new(),
+            poll: SavedPoll::IDLE,
+        }
+    }
+}
+
+/// 初始化调度器, 把当前执行流作为 0 号线程, 并创建空闲线程
+///
+/// - 需要在堆和内核内存初始化之后, 开中断之前调用
+pub fn init() {
+    fpu::init();
+    let main = Thread::new(ThreadId::new(), ThreadState::Running, None);
+    let mut scheduler = Scheduler::new(main);
+
+    let idle = new_thread(&mut scheduler, Box::new(idle_loop));
+    scheduler.set_idle(idle);
+
+    fpu::switch_to(&scheduler.threads[&scheduler.current()].fpu);
+    *SCHEDULER.lock() = Some(scheduler);
+}
+
+/// 创建一个新线程并加入调度, 返回线程号
+pub fn spawn_thread<F>(f: F) -> ThreadId
+where
+    F: FnOnce() + Send + 'static,
+{
+    interrupts::without_interrupts(|| {
+        let mut scheduler = SCHEDULER.lock();
+        let scheduler = scheduler.as_mut().expect("thread::init not called");
+        scheduler.reap_stacks();
+        let thread = new_thread(scheduler, Box::new(f));
+        let id = thread.id;
+        scheduler.add(thread);
+        id
+    })
+}
+
+/// 分配栈并构造初始上下文, 线程第一次运行时从 [`thread_start`] 进入 `entry`
+fn new_thread(scheduler: &mut Scheduler, entry: Box<dyn FnOnce() + Send>) -> Thread {
+    let stack = scheduler.free_stacks.pop().unwrap_or_else(|| {
+        memory::alloc_kernel_stack(THREAD_STACK_PAGES).expect("failed to allocate thread stack")
+    });
+    // 胖指针再装箱一次, 变成可以放进寄存器的瘦指针
+    let entry = Box::into_raw(Box::new(entry));
+
+    let mut thread = Thread::new(ThreadId::new(), ThreadState::Ready, Some(stack));
+    thread.saved_rsp = unsafe { context::init_stack(stack.end(), entry as u64) };
+    thread
+}
+
+/// 新线程的第一个 Rust 函数, 由 `thread_trampoline` 调用
+extern "C" fn thread_start(entry: *mut Box<dyn FnOnce() + Send>) -> ! {
+    let entry = unsafe { Box::from_raw(entry) };
+    // 切换总是在关中断时发生, 新线程需要自己打开中断
+    interrupts::enable();
+    entry();
+    exit();
+}
+
+/// 空闲线程: 执行推迟的中断工作, 然后睡眠到下一个中断
+fn idle_loop() {
+    loop {
+        deferred::run_pending();
+        interrupts::disable();
+        if deferred::has_pending() {
+            interrupts::enable();
+        } else {
+            interrupts::enable_and_hlt();
+        }
+    }
+}
+
+/// 在关中断的状态下更新调度器状态, `update` 返回 `true` 时做一次调度
+fn schedule(update: impl FnOnce(&mut Scheduler) -> bool) {
+    interrupts::without_interrupts(|| {
+        let switch = {
+            let mut scheduler = SCHEDULER.lock();
+            let scheduler = scheduler.as_mut().expect("thread::init not called");
+            if !update(scheduler) {
+                return;
+            }
+            scheduler.reschedule()
+        };
+        // 必须先释放调度器的锁再切换, 切换回来时从这里继续执行
+        if let Some((save_rsp, new_rsp)) = switch {
+            unsafe { context::switch(save_rsp, new_rsp) };
+        }
+    });
+}
+
+/// 返回当前线程的线程号
+pub fn current() -> ThreadId {
+    ThreadId(percpu!(current_thread).load(Ordering::Relaxed))
+}
+
+/// 主动让出 CPU, 排到可运行队列的队尾
+pub fn yield_now() {
+    schedule(|_| true);
+}
+
+/// 睡眠至少 `ms` 毫秒
+///
+/// - 可能被 [`unpark`] 提前唤醒, 例如所在的进程被 kill; 和 [`park`] 一样,
+///   之前已经收到过 `unpark` 时立即返回
+pub fn sleep(ms: u64) {
+    let wake_at = time::ticks().saturating_add(time::ms_to_ticks(ms));
+    schedule(|scheduler| {
+        let thread = scheduler.current_mut();
+        if core::mem::take(&mut thread.unparked) {
+            return false;
+        }
+        thread.state = ThreadState::Sleeping(wake_at);
+        true
+    });
+}
+
+/// 等待线程 `id` 结束
+///
+/// - 线程结束后它的记录由 `join` 删除, 所以每个线程只能被成功 join 一次
+pub fn join(id: ThreadId) -> Result<(), JoinError> {
+    loop {
+        let mut finished = false;
+        let mut error = None;
+        schedule(|scheduler| {
+            let current = scheduler.current();
+            if id == current {
+                error = Some(JoinError::JoinSelf);
+                return false;
+            }
+            match scheduler.threads.get_mut(&id) {
+                None => {
+                    error = Some(JoinError::NoSuchThread);
+                    false
+                }
+                Some(thread) if thread.state == ThreadState::Finished => {
+                    finished = true;
+                    if let Some(stack) = thread.stack.take() {
+                        scheduler.free_stacks.push(stack);
+                    }
+                    scheduler.threads.remove(&id);
+                    false
+                }
+                Some(thread) => {
+                    thread.joiners.push(current);
+                    scheduler.current_mut().state = ThreadState::Blocked;
+                    true
+                }
+            }
+        });
+        if let Some(error) = error {
+            return Err(error);
+        }
+        if finished {
+            return Ok(());
+        }
+    }
+}
+
+/// 阻塞当前线程, 直到其他线程对它调用 [`unpark`]
+///
+/// - 之前已经收到过 `unpark` 时立即返回, 所以先登记等待条件再 `park` 不会丢失唤醒
+/// - 可能被其他原因唤醒, 调用者需要在返回后重新检查等待条件
+pub fn park() {
+    schedule(|scheduler| {
+        let thread = scheduler.current_mut();
+        if core::mem::take(&mut thread.unparked) {
+            return false;
+        }
+        thread.state = ThreadState::Blocked;
+        true
+    });
+}
+
+/// 唤醒在 [`park`] 中阻塞或在 [`sleep`] 中睡眠的线程 `id`; 它没有阻塞时让它的下一次 `park` 立即返回
+pub fn unpark(id: ThreadId) {
+    schedule(|scheduler| {
+        match scheduler.threads.get_mut(&id) {
+            Some(thread)
+                if matches!(
+                    thread.state,
+                    ThreadState::Blocked | ThreadState::Sleeping(_)
+                ) =>
+            {
+                scheduler.make_ready(id)
+            }
+            Some(thread) => thread.unparked = true,
+            None => {}
+        }
+        false
+    });
+}
+
+/// 没有线程在等待
+const NO_THREAD: u64 = u64::MAX;
+
+/// 记录在 [`park`] 中等待某个条件的线程, 让条件成立的一方(可以是中断处理函数)唤醒它
+///
+/// - 等待方先 [`register`](Waiter::register) 再检查条件, 条件不成立才 `park`, 醒来后
+///   [`cancel`](Waiter::cancel); 唤醒方先让条件成立再 [`wake`](Waiter::wake). 所以要么等待方
+///   看到条件成立, 要么唤醒方看到登记的线程, 唤醒不会丢失
+/// - 只记录一个线程, 后登记的覆盖先登记的
+pub struct Waiter {
+    thread: AtomicU64,
+}
+
+impl Waiter {
+    pub const fn new() -> Self {
+        Waiter {
+            thread: AtomicU64::new(NO_THREAD),
+        }
+    }
+
+    /// 登记当前线程
+    pub fn register(&self) {
+        self.thread.store(current().0, Ordering::SeqCst);
+    }
+
+    /// 取消当前线程的登记, 其他线程的登记不受影响
+    pub fn cancel(&self) {
+        let _ = self.thread.compare_exchange(
+            current().0,
+            NO_THREAD,
+            Ordering::SeqCst,
+            Ordering::Relaxed,
+        );
+    }
+
+    /// 唤醒并取消登记的线程, 没有线程登记时什么也不做
+    pub fn wake(&self) {
+        let id = self.thread.swap(NO_THREAD, Ordering::SeqCst);
+        if id != NO_THREAD {
+            unpark(ThreadId(id));
+        }
+    }
+}
+
+impl Default for Waiter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// 结束当前线程, 唤醒所有等待它的线程
+pub fn exit() -> ! {
+    schedule(|scheduler| {
+        let thread = scheduler.current_mut();
+        thread.state = ThreadState::Finished;
+        let joiners = core::mem::take(&mut thread.joiners);
+        for joiner in joiners {
+            scheduler.make_ready(joiner);
+        }
+        true
+    });
+    unreachable!("finished thread was scheduled again");
+}
+
+/// 让当前线程改用位于 `page_table` 的页表, 之后每次切换到这个线程时都会加载它
+///
+/// # Safety
+/// `page_table` 必须是包含内核映射的有效 4 级页表, 见 [`AddressSpace`](crate::address_space::AddressSpace)
+pub unsafe fn set_page_table(page_table: PhysFrame) {
+    interrupts::without_interrupts(|| {
+        let mut scheduler = SCHEDULER.lock();
+        let scheduler = scheduler.as_mut().expect("thread::init not called");
+        scheduler.current_mut().page_table = Some(page_table);
+        Cr3::write(page_table, Cr3Flags::empty());
+    });
+}
+
+/// 由时钟中断处理函数调用: 唤醒到期的睡眠线程, 并请求在中断返回前抢占
+pub(crate) fn timer_tick() {
+    if let Some(scheduler) = SCHEDULER.lock().as_mut() {
+        scheduler.wake_sleepers(time::ticks());
+        NEED_RESCHED.store(true, Ordering::Relaxed);
+    }
+}
+
+/// 在 IRQ 处理完成(已发送 EOI)后调用, 如果需要则切换到下一个线程
+///
+/// - 被抢占的线程之后从这里返回, 继续沿中断返回路径执行 `iretq`
+pub(crate) fn preempt_on_irq_exit() {
+    if NEED_RESCHED.swap(false, Ordering::Relaxed) {
+        schedule(|_| true);
+    }
+}
@@ -0,0 +1,169 @@
+//! 轮转(round-robin)调度器
+//!
+//! - 所有可运行的线程排在当前 CPU 的可运行队列(见 [`percpu`](crate::percpu))中, 每次调度取队首,
+//!   被换下的线程排到队尾. 当前线程的编号也记录在 CPU 私有数据中
+//! - 没有可运行的线程时切换到空闲线程, 空闲线程从不进入可运行队列
+//! - 调度器只在关中断时访问, 时钟中断中的抢占也会用到它, 所以抢占路径上不能分配内存:
+//!   可运行队列的容量在创建线程时预留好, 保证入队不会扩容
+
+use super::{fpu, Thread, ThreadId, ThreadState};
+use crate::gdt;
+use crate::memory::{self, StackBounds};
+use crate::percpu;
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+use core::sync::atomic::Ordering;
+use x86_64::registers::control::{Cr3, Cr3Flags};
+
+pub(super) struct Scheduler {
+    /// 用 `Box` 保存线程, 保证 `saved_rsp` 的地址不会随着 map 的调整而变化
+    pub(super) threads: BTreeMap<ThreadId, Box<Thread>>,
+    idle: Option<ThreadId>,
+    /// 已结束线程留下的栈, 新线程优先复用
+    pub(super) free_stacks: Vec<StackBounds>,
+    /// 0 号线程使用的引导栈, 由内核初始化时记录在 CPU 私有数据中
+    boot_stack: Option<StackBounds>,
+}
+
+/// 一次切换需要的两个栈指针: 保存旧线程栈指针的位置, 以及新线程的栈指针
+pub(super) type Switch = (*mut u64, u64);
+
+impl Scheduler {
+    pub(super) fn new(current: Thread) -> Self {
+        let current_id = current.id;
+        let mut threads = BTreeMap::new();
+        threads.insert(current_id, Box::new(current));
+        set_current(current_id);
+        Scheduler {
+            threads,
+            idle: None,
+            free_stacks: Vec::new(),
+            boot_stack: percpu::current().stack(),
+        }
+    }
+
+    /// 当前 CPU 上正在运行的线程
+    pub(super) fn current(&self) -> ThreadId {
+        ThreadId(percpu!(current_thread).load(Ordering::Relaxed))
+    }
+
+    pub(super) fn set_idle(&mut self, idle: Thread) {
+        let id = idle.id;
+        self.threads.insert(id, Box::new(idle));
+        self.idle = Some(id);
+    }
+
+    /// 加入一个新的可运行线程
+    pub(super) fn add(&mut self, thread: Thread) {
+        let id = thread.id;
+        self.threads.insert(id, Box::new(thread));
+        let mut run_queue = percpu!(run_queue).lock();
+        run_queue.push_back(id);
+        // 每个线程至多在队列中出现一次, 预留足够的容量后抢占路径上的入队就不会分配内存
+        let len = run_queue.len();
+        run_queue.reserve(self.threads.len().saturating_sub(len));
+    }
+
+    pub(super) fn current_mut(&mut self) -> &mut Thread {
+        let current = self.current();
+        self.threads
+            .get_mut(&current)
+            .expect("current thread missing")
+    }
+
+    /// 把线程标记为可运行并加入队尾
+    ///
+    /// - 已经可运行或正在运行的线程不重复入队, 所以多余的唤醒是无害的
+    pub(super) fn make_ready(&mut self, id: ThreadId) {
+        if let Some(thread) = self.threads.get_mut(&id) {
+            if matches!(thread.state, ThreadState::Ready | ThreadState::Running) {
+                return;
+            }
+            thread.state = ThreadState::Ready;
+            percpu!(run_queue).lock().push_back(id);
+        }
+    }
+
+    /// 唤醒所有到期的睡眠线程
+    pub(super) fn wake_sleepers(&mut self, now: u64) {
+        let mut run_queue = percpu!(run_queue).lock();
+        for (id, thread) in self.threads.iter_mut() {
+            if let ThreadState::Sleeping(wake_at) = thread.state {
+                if wake_at <= now {
+                    thread.state = ThreadState::Ready;
+                    run_queue.push_back(*id);
+                }
+            }
+        }
+    }
+
+    /// 回收已结束线程的栈
+    ///
+    /// - 只有切换离开之后栈才不再被使用, 所以当前线程的栈不回收
+    pub(super) fn reap_stacks(&mut self) {
+        let current = self.current();
+        for thread in self.threads.values_mut() {
+            if thread.state == ThreadState::Finished && thread.id != current {
+                if let Some(stack) = thread.stack.take() {
+                    self.free_stacks.push(stack);
+                }
+            }
+        }
+    }
+
+    /// 选出下一个要运行的线程
+    ///
+    /// - 当前线程如果仍处于 `Running` 状态, 说明只是让出 CPU, 重新排到队尾
+    /// - 其他状态(睡眠, 阻塞, 结束)由调用者在调用前设置好
+    /// - 不需要切换时返回 `None`
+    pub(super) fn reschedule(&mut self) -> Option<Switch> {
+        let current = self.current();
+        let idle = self.idle.expect("scheduler has no idle thread");
+        let still_runnable = self.threads[&current].state == ThreadState::Running;
+
+        let next = percpu!(run_queue).lock().pop_front();
+        let next = match next {
+            Some(next) => next,
+            None if still_runnable => return None,
+            None => idle,
+        };
+        if next == current {
+            return None;
+        }
+
+        if still_runnable && current != idle {
+            self.make_ready(current);
+        } else if still_runnable {
+            self.current_mut().state = ThreadState::Ready;
+        }
+
+        let percpu = percpu::current();
+        let next_poll = self.threads[&next].poll;
+        self.current_mut().poll = percpu.poll.switch(next_poll);
+
+        let new_rsp = {
+            let next = self.threads.get_mut(&next).expect("next thread missing");
+            next.state = ThreadState::Running;
+            // 0 号线程没有自己分配的栈, 它不会进入用户态, 不需要内核栈
+            if let Some(stack) = next.stack {
+                gdt::set_kernel_stack(stack.end());
+            }
+            percpu.set_stack(next.stack.or(self.boot_stack));
+            // 内核映射在所有页表中都相同, 所以可以在切换栈之前切换页表
+            let page_table = next.page_table.unwrap_or_else(memory::kernel_page_table);
+            if Cr3::read().0 != page_table {
+                unsafe { Cr3::write(page_table, Cr3Flags::empty()) };
+            }
+            fpu::switch_to(&next.fpu);
+            next.saved_rsp
+        };
+        let save_rsp = &mut self.current_mut().saved_rsp as *mut u64;
+        set_current(next);
+        Some((save_rsp, new_rsp))
+    }
+}
+
+fn set_current(id: ThreadId) {
+    percpu!(current_thread).store(id.0, Ordering::Relaxed);
+}
@@ -0,0 +1,51 @@
+//! 系统时钟
+//!
+//! - 把 PIT(8253/8254) 的通道 0 设置为 [`TIMER_HZ`] 的周期中断, 即 IRQ0
+//! - 每次时钟中断调用 [`tick`], 内核以 tick 为单位计时
+
+use core::sync::atomic::{AtomicU64, Ordering};
+use x86_64::instructions::port::Port;
+
+/// 时钟中断频率
+pub const TIMER_HZ: u64 = 100;
+
+/// PIT 的输入时钟频率
+const PIT_FREQUENCY: u64 = 1_193_182;
+const PIT_CHANNEL_0: u16 = 0x40;
+const PIT_COMMAND: u16 = 0x43;
+/// 通道 0, 先低字节后高字节, 模式 3(方波), 二进制计数
+const PIT_MODE_SQUARE_WAVE: u8 = 0b0011_0110;
+
+static TICKS: AtomicU64 = AtomicU64::new(0);
+
+/// 设置 PIT 的分频, 需要在开中断之前调用
+pub fn init() {
+    let divisor = (PIT_FREQUENCY / TIMER_HZ) as u16;
+    let mut command: Port<u8> = Port::new(PIT_COMMAND);
+    let mut channel_0: Port<u8> = Port::new(PIT_CHANNEL_0);
+    unsafe {
+        command.write(PIT_MODE_SQUARE_WAVE);
+        channel_0.write(divisor as u8);
+        channel_0.write((divisor >> 8) as u8);
+    }
+}
+
+/// 由时钟中断处理函数调用
+pub(crate) fn tick() {
+    TICKS.fetch_add(1, Ordering::Relaxed);
+}
+
+/// 开机以来的 tick 数
+pub fn ticks() -> u64 {
+    TICKS.load(Ordering::Relaxed)
+}
+
+/// 开机以来的毫秒数
+pub fn uptime_ms() -> u64 {
+    ticks() * 1000 / TIMER_HZ
+}
+
+/// 把毫秒数换算成 tick 数, 向上取整, 溢出时取最大值
+pub fn ms_to_ticks(ms: u64) -> u64 {
+    ms.saturating_mul(TIMER_HZ).div_ceil(1000)
+}
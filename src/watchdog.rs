@@ -0,0 +1,317 @@
+//! 看门狗
+//!
+//! - 执行器每次轮询任务前后调用 [`poll_started`]/[`poll_finished`], 时钟中断检查被打断的线程
+//!   的这次轮询是否已经超过超时时间, 用来发现不返回 `Pending` 的任务. 轮询状态属于执行器所在的线程
+//!   (见 [`poll_state`](crate::task::poll_state)), 多个执行器线程互相抢占时不会认错任务.
+//!   计时用的是墙上时间, 执行器线程被其他线程抢占的时间也算在内
+//! - 关中断卡死时时钟中断不会再到来. 有多个 CPU 时第一个上线的 AP 充当监视者:
+//!   它观察 BSP 的 tick 计数, 超时未增加就向被监视的 CPU 发送 NMI, 在 NMI 中打印现场.
+//!   只有一个 CPU 时只能发现前一种情况
+//! - 默认不启用, 需要调用 [`enable`]. 调试器暂停或宿主机很慢时也可能触发, 所以调试时最好用
+//!   [`Action::Panic`]
+//! - 触发后直接写串口(不经过 `SERIAL1`, 持锁的可能正是卡住的代码)打印原因, 任务号,
+//!   中断状态和栈回溯, 再按 [`Action`] 重启, 退出 QEMU 或 panic
+//! - 监视者在看门狗启用期间由自己的 local APIC 定时器每个 tick 唤醒一次, 其余时间停在 `hlt`;
+//!   未启用时定时器停止, 由 [`enable`] 用 NMI 唤醒
+
+use crate::backtrace::Backtrace;
+use crate::interrupts::trap::TrapFrame;
+use crate::smp::{self, lapic};
+use crate::task::poll_state::NO_TASK;
+use crate::{percpu, serial, time};
+use core::arch::x86_64::_rdtsc;
+use core::fmt;
+use core::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, AtomicU8, Ordering};
+use x86_64::instructions::port::Port;
+use x86_64::registers::rflags::RFlags;
+
+/// 看门狗触发后的处理方式
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Action {
+    /// 通过键盘控制器复位, 失败时三重错误
+    Reboot,
+    /// 以失败码退出 QEMU
+    ExitQemu,
+    /// panic, 由 panic 处理函数决定后续, 主要用于测试
+    Panic,
+}
+
+const NO_APIC: u32 = u32::MAX;
+
+/// 超时的 tick 数, 0 表示未启用
+static TIMEOUT_TICKS: AtomicU64 = AtomicU64::new(0);
+static ACTION: AtomicU8 = AtomicU8::new(Action::Reboot as u8);
+/// 已经触发, 之后不再检查
+static FIRED: AtomicBool = AtomicBool::new(false);
+
+/// 被监视的 CPU 和监视者的 local APIC ID
+static WATCHED_APIC: AtomicU32 = AtomicU32::new(NO_APIC);
+static MONITOR_APIC: AtomicU32 = AtomicU32::new(NO_APIC);
+/// 监视者正在监视, 用来确认 [`enable`] 的唤醒已经送达
+static MONITOR_ACTIVE: AtomicBool = AtomicBool::new(false);
+/// 发给监视者的 NMI 是唤醒, 发给被监视 CPU 的 NMI 是要求打印现场
+static WAKE_REQUESTED: AtomicBool = AtomicBool::new(false);
+static DUMP_REQUESTED: AtomicBool = AtomicBool::new(false);
+/// 一个 tick 内的 TSC 周期数, 由 [`enable`] 测量
+static TSC_PER_TICK: AtomicU64 = AtomicU64::new(0);
+
+/// 以 `timeout_ms` 毫秒为超时时间启用看门狗, 监视调用者所在的 CPU(即执行器和时钟中断所在的 BSP)
+///
+/// - 需要开中断, 要用几个 tick 测量 TSC 频率
+pub fn enable(timeout_ms: u64, action: Action) {
+    assert!(
+        x86_64::instructions::interrupts::are_enabled(),
+        "watchdog::enable requires interrupts to be enabled"
+    );
+    TSC_PER_TICK.store(measure_tsc_per_tick(), Ordering::Relaxed);
+    ACTION.store(action as u8, Ordering::Relaxed);
+    FIRED.store(false, Ordering::Relaxed);
+    if smp::cpu_count() > 1 {
+        WATCHED_APIC.store(u32::from(lapic::id()), Ordering::Relaxed);
+    }
+    TIMEOUT_TICKS.store(time::ms_to_ticks(timeout_ms).max(1), Ordering::Release);
+    wake_monitor();
+}
+
+/// 停用看门狗, 监视者回到 `hlt`
+pub fn disable() {
+    TIMEOUT_TICKS.store(0, Ordering::Release);
+}
+
+pub fn is_enabled() -> bool {
+    TIMEOUT_TICKS.load(Ordering::Relaxed) != 0
+}
+
+/// 当前线程中的执行器开始轮询任务 `task`
+pub fn poll_started(task: u64) {
+    let poll = percpu!(poll);
+    poll.start.store(time::ticks(), Ordering::Relaxed);
+    poll.task.store(task, Ordering::Relaxed);
+}
+
+/// 当前线程中的执行器的这次轮询已经返回
+pub fn poll_finished() {
+    percpu!(poll).task.store(NO_TASK, Ordering::Relaxed);
+}
+
+/// 时钟中断中调用, `frame` 是被打断的现场
+pub(crate) fn check(frame: &TrapFrame) {
+    let timeout = TIMEOUT_TICKS.load(Ordering::Relaxed);
+    let poll = percpu!(poll);
+    let task = poll.task.load(Ordering::Relaxed);
+    if timeout == 0 || task == NO_TASK || FIRED.load(Ordering::Relaxed) {
+        return;
+    }
+    let elapsed = time::ticks().saturating_sub(poll.start.load(Ordering::Relaxed));
+    if elapsed >= timeout {
+        fire(
+            format_args!(
+                "task {} has been polled for {} ms without returning",
+                task,
+                elapsed * 1000 / time::TIMER_HZ
+            ),
+            frame,
+        );
+    }
+}
+
+/// NMI 处理: 看门狗发出的 NMI 返回 `true`, 其他来源的 NMI 返回 `false`
+pub(crate) fn handle_nmi(frame: &TrapFrame) -> bool {
+    let apic = lapic::is_initialized().then(|| u32::from(lapic::id()));
+    if apic.is_some() && apic == Some(MONITOR_APIC.load(Ordering::Relaxed)) {
+        return WAKE_REQUESTED.swap(false, Ordering::AcqRel);
+    }
+    if !DUMP_REQUESTED.swap(false, Ordering::AcqRel) {
+        return false;
+    }
+    let timeout = TIMEOUT_TICKS.load(Ordering::Relaxed);
+    fire(
+        format_args!(
+            "no timer interrupt for more than {} ms, interrupts are probably disabled",
+            timeout * 1000 / time::TIMER_HZ
+        ),
+        frame,
+    );
+    true
+}
+
+/// AP 的空闲循环: 第一个进入的 AP 成为监视者, 其余的 AP 停在 `hlt`
+pub(crate) fn ap_idle() -> ! {
+    let apic = u32::from(lapic::id());
+    let is_monitor = MONITOR_APIC
+        .compare_exchange(NO_APIC, apic, Ordering::AcqRel, Ordering::Acquire)
+        .is_ok();
+    if !is_monitor {
+        crate::hlt_loop();
+    }
+    loop {
+        if is_enabled() && !FIRED.load(Ordering::Relaxed) {
+            monitor();
+        }
+        MONITOR_ACTIVE.store(false, Ordering::Release);
+        x86_64::instructions::hlt();
+    }
+}
+
+/// 看门狗启用期间观察 BSP 的 tick 计数
+fn monitor() {
+    MONITOR_ACTIVE.store(true, Ordering::Release);
+    let tsc_per_tick = TSC_PER_TICK.load(Ordering::Relaxed);
+    lapic::start_timer(measure_lapic_timer_per_tick(tsc_per_tick), true);
+    let mut last_tick = time::ticks();
+    let mut last_change = unsafe { _rdtsc() };
+    while is_enabled() && !FIRED.load(Ordering::Relaxed) {
+        let tick = time::ticks();
+        let now = unsafe { _rdtsc() };
+        if tick != last_tick {
+            last_tick = tick;
+            last_change = now;
+        } else if now - last_change > TIMEOUT_TICKS.load(Ordering::Relaxed) * tsc_per_tick {
+            let watched = WATCHED_APIC.load(Ordering::Relaxed);
+            if watched == NO_APIC {
+                break;
+            }
+            DUMP_REQUESTED.store(true, Ordering::Release);
+            lapic::send_nmi(watched as u8);
+            // 等被监视的 CPU 处理完; NMI 没有被处理时不重复发送
+            last_change = now;
+        }
+        // 下一次定时器中断时再检查
+        x86_64::instructions::hlt();
+    }
+    lapic::stop_timer();
+}
+
+/// 测量一个 tick 内 local APIC 定时器的计数, 用 TSC 计时, 只忙等一个 tick
+///
+/// - BSP 的时钟中断可能正是要监视的对象, 所以不用 tick 计数计时
+fn measure_lapic_timer_per_tick(tsc_per_tick: u64) -> u32 {
+    lapic::start_timer(u32::MAX, false);
+    let start = unsafe { _rdtsc() };
+    while unsafe { _rdtsc() } - start < tsc_per_tick {
+        core::hint::spin_loop();
+    }
+    let elapsed = u32::MAX - lapic::timer_current();
+    lapic::stop_timer();
+    elapsed.max(1)
+}
+
+/// 用 NMI 把停在 `hlt` 中的监视者唤醒, 每个 tick 重发一次直到它开始监视
+///
+/// - 监视者可能在检查启用状态之后, 执行 `hlt` 之前收到 NMI, 这时 NMI 被错过
+fn wake_monitor() {
+    let monitor = MONITOR_APIC.load(Ordering::Acquire);
+    if monitor == NO_APIC {
+        return;
+    }
+    for _ in 0..10 {
+        if MONITOR_ACTIVE.load(Ordering::Acquire) {
+            return;
+        }
+        WAKE_REQUESTED.store(true, Ordering::Release);
+        lapic::send_nmi(monitor as u8);
+        let tick = time::ticks();
+        while time::ticks() == tick && !MONITOR_ACTIVE.load(Ordering::Acquire) {
+            x86_64::instructions::hlt();
+        }
+    }
+}
+
+/// 测量一个 tick 内的 TSC 周期数
+fn measure_tsc_per_tick() -> u64 {
+    const TICKS: u64 = 2;
+
+    // 先对齐到 tick 的边界
+    let tick = time::ticks();
+    while time::ticks() == tick {
+        x86_64::instructions::hlt();
+    }
+    let start_tick = time::ticks();
+    let start = unsafe { _rdtsc() };
+    while time::ticks() < start_tick + TICKS {
+        x86_64::instructions::hlt();
+    }
+    (unsafe { _rdtsc() } - start) / TICKS
+}
+
+/// 打印现场并按 [`Action`] 处理, 只会执行一次
+fn fire(reason: fmt::Arguments, frame: &TrapFrame) {
+    if FIRED.swap(true, Ordering::AcqRel) {
+        return;
+    }
+    let percpu = percpu::current();
+    let rflags = RFlags::from_bits_truncate(frame.stack_frame.cpu_flags);
+    let task = percpu.poll.task.load(Ordering::Relaxed);
+    serial::_print_unlocked(format_args!("\nwatchdog: {}\n", reason));
+    serial::_print_unlocked(format_args!(
+        "  cpu {}, thread {}, task {}, interrupts {}, irq depth {}\n",
+        percpu.cpu_id.load(Ordering::Relaxed),
+        percpu.current_thread.load(Ordering::Relaxed),
+        TaskDisplay(task),
+        if rflags.contains(RFlags::INTERRUPT_FLAG) {
+            "enabled"
+        } else {
+            "disabled"
+        },
+        // 不包括看门狗自己所在的中断
+        percpu.irq_depth.load(Ordering::Relaxed).saturating_sub(1),
+    ));
+    serial::_print_unlocked(format_args!(
+        "{}\n",
+        Backtrace::from_frame(
+            frame.stack_frame.instruction_pointer.as_u64(),
+            frame.registers.rbp
+        )
+    ));
+
+    match ACTION.load(Ordering::Relaxed) {
+        action if action == Action::ExitQemu as u8 => {
+            crate::exit_qemu(crate::QemuExitCode::Failed);
+            crate::hlt_loop();
+        }
+        action if action == Action::Panic as u8 => panic!("watchdog: {}", reason),
+        _ => reboot(),
+    }
+}
+
+struct TaskDisplay(u64);
+
+impl fmt::Display for TaskDisplay {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.0 {
+            NO_TASK => f.write_str("none"),
+            task => write!(f, "{}", task),
+        }
+    }
+}
+
+/// 通过 8042 键盘控制器复位 CPU, 不成功时加载空 IDT 触发三重错误
+fn reboot() -> ! {
+    const KBC_COMMAND: u16 = 0x64;
+    const KBC_PULSE_RESET: u8 = 0xfe;
+
+    unsafe { Port::<u8>::new(KBC_COMMAND).write(KBC_PULSE_RESET) };
+    unsafe {
+        let idt = x86_64::structures::DescriptorTablePointer {
+            limit: 0,
+            base: x86_64::VirtAddr::zero(),
+        };
+        x86_64::instructions::tables::lidt(&idt);
+        core::arch::asm!("int3", options(noreturn));
+    }
+}
+
+#[test_case]
+fn test_polling_task_belongs_to_thread() {
+    use crate::thread;
+
+    let task = || percpu!(poll).task.load(Ordering::Relaxed);
+    poll_started(7);
+    // 其他线程看不到这个线程正在轮询的任务
+    let other = thread::spawn_thread(move || assert_eq!(task(), NO_TASK));
+    thread::join(other).expect("failed to join thread");
+    assert_eq!(task(), 7);
+    poll_finished();
+    assert_eq!(task(), NO_TASK);
+}
@@ -0,0 +1,110 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::vec::Vec;
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use rust_os::elf::{Elf, ElfError};
+use rust_os::loader::{self, LoadError};
+use rust_os::thread;
+use x86_64::structures::paging::PageTableFlags;
+use x86_64::VirtAddr;
+
+/// 检查初始栈内容的用户程序, 源码见 `user/programs/args.rs`
+static ARGS: &[u8] = include_bytes!(concat!(env!("OUT_DIR"), "/user/args"));
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    // 内核初始化
+    rust_os::init(boot_info);
+
+    test_main();
+    rust_os::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rust_os::test_panic_handler(info)
+}
+
+#[test_case]
+fn rejects_invalid_images() {
+    assert_eq!(Elf::parse(b"\x7fELF").err(), Some(ElfError::Truncated));
+    assert_eq!(Elf::parse(&[0; 64]).err(), Some(ElfError::BadMagic));
+
+    let mut image = Vec::from(ARGS);
+    image[18] = 3; // EM_386
+    assert_eq!(Elf::parse(&image).err(), Some(ElfError::UnsupportedMachine));
+
+    let mut image = Vec::from(ARGS);
+    image[56..58].copy_from_slice(&u16::MAX.to_le_bytes()); // 程序头个数超出文件
+    assert_eq!(Elf::parse(&image).err(), Some(ElfError::BadProgramHeaders));
+
+    let mut image = Vec::from(ARGS);
+    image[24..32].copy_from_slice(&0u64.to_le_bytes()); // 入口不在任何段中
+    assert!(matches!(
+        loader::load(&image, &[], &[]),
+        Err(LoadError::BadEntry)
+    ));
+}
+
+#[test_case]
+fn maps_segments_with_permissions() {
+    let elf = Elf::parse(ARGS).expect("invalid ELF");
+    let program = loader::load(ARGS, &["args"], &[]).expect("load failed");
+    assert_eq!(program.entry.as_u64(), elf.entry());
+
+    for segment in elf.program_headers().filter(|s| s.is_load()) {
+        let flags = program
+            .address_space
+            .page_flags(VirtAddr::new(segment.vaddr))
+            .expect("segment not mapped");
+        assert!(flags.contains(PageTableFlags::USER_ACCESSIBLE));
+        assert_eq!(
+            flags.contains(PageTableFlags::WRITABLE),
+            segment.is_writable()
+        );
+        assert_eq!(
+            flags.contains(PageTableFlags::NO_EXECUTE),
+            !segment.is_executable()
+        );
+    }
+}
+
+#[test_case]
+fn stack_holds_arguments() {
+    let program = loader::load(ARGS, &["args", "hello"], &["KEY=value"]).expect("load failed");
+    let sp = program.stack_pointer;
+    assert_eq!(sp.as_u64() % 16, 0);
+
+    let read_u64 = |addr: VirtAddr| {
+        let mut buf = [0; 8];
+        program
+            .address_space
+            .read(addr, &mut buf)
+            .expect("not mapped");
+        u64::from_le_bytes(buf)
+    };
+    assert_eq!(read_u64(sp), 2);
+    let argv1 = VirtAddr::new(read_u64(sp + 16u64));
+    let mut buf = [0; 6];
+    program
+        .address_space
+        .read(argv1, &mut buf)
+        .expect("not mapped");
+    assert_eq!(&buf, b"hello\0");
+    assert_eq!(read_u64(sp + 24u64), 0);
+}
+
+#[test_case]
+fn runs_program_in_ring3() {
+    let program = loader::load(ARGS, &["args", "hello"], &["KEY=value"]).expect("load failed");
+    let user = thread::spawn_thread(move || loader::exec(program));
+    thread::join(user).expect("join failed");
+}
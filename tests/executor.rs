@@ -0,0 +1,343 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::rc::Rc;
+use alloc::string::ToString;
+use alloc::vec::Vec;
+use bootloader::{entry_point, BootInfo};
+use core::cell::{Cell, RefCell};
+use core::future::{self, Future};
+use core::panic::PanicInfo;
+use core::pin::pin;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use core::task::{Context, Poll};
+use futures_util::task::noop_waker_ref;
+use rust_os::interrupts::deferred::{self, Work};
+use rust_os::interrupts::irq;
+use rust_os::task::stats::TaskState;
+use rust_os::task::{self, coop, executor::Executor, JoinError, Priority, SpawnError};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    // 内核初始化
+    rust_os::init(boot_info);
+
+    test_main();
+    rust_os::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rust_os::test_panic_handler(info)
+}
+
+/// 在执行器之外 poll 一次
+fn poll_once<F: Future>(future: F) -> Poll<F::Output> {
+    let mut context = Context::from_waker(noop_waker_ref());
+    pin!(future).poll(&mut context)
+}
+
+#[test_case]
+fn join_handle_returns_output() {
+    let mut executor = Executor::new();
+    let handle = executor.spawn(async { 6 * 7 }).unwrap();
+    assert!(!handle.is_finished());
+    executor.run_until_idle();
+    assert!(handle.is_finished());
+    assert_eq!(poll_once(handle), Poll::Ready(Ok(42)));
+}
+
+#[test_case]
+fn task_awaits_another_task() {
+    let mut executor = Executor::new();
+    let inner = executor.spawn(async { "inner" }).unwrap();
+    let outer = executor
+        .spawn(async move { inner.await.map(str::len) })
+        .unwrap();
+    executor.run_until_idle();
+    assert_eq!(poll_once(outer), Poll::Ready(Ok(Ok(5))));
+}
+
+#[test_case]
+fn dropped_handle_detaches_task() {
+    let ran = Rc::new(Cell::new(false));
+    let mut executor = Executor::new();
+    let flag = ran.clone();
+    executor.spawn(async move { flag.set(true) }).unwrap();
+    executor.run_until_idle();
+    assert!(ran.get());
+    // 任务完成后共享状态随之释放
+    assert_eq!(Rc::strong_count(&ran), 1);
+}
+
+#[test_case]
+fn running_task_spawns_tasks() {
+    let mut executor = Executor::new();
+    let spawner = executor.spawner();
+    let outer = executor
+        .spawn(async move {
+            let by_spawner = spawner.clone().spawn(async { 1 }).unwrap();
+            let global = task::spawn(async { 2 }).unwrap();
+            Ok::<_, JoinError>(by_spawner.await? + global.await?)
+        })
+        .unwrap();
+    executor.run_until_idle();
+    assert_eq!(poll_once(outer), Poll::Ready(Ok(Ok(3))));
+}
+
+#[test_case]
+fn deferred_work_spawns_task() {
+    static RAN: AtomicUsize = AtomicUsize::new(0);
+
+    fn work(arg: usize) {
+        task::spawn(async move {
+            RAN.fetch_add(arg, Ordering::SeqCst);
+        })
+        .unwrap();
+    }
+
+    let mut executor = Executor::new();
+    // 让执行器成为全局 spawn 的目标
+    executor.run_until_idle();
+    deferred::defer(irq::LPT2, Work::new(work, 7));
+    executor.run_until_idle();
+    assert_eq!(RAN.load(Ordering::SeqCst), 7);
+}
+
+/// 被丢弃时计数, 用来确认任务的 future 已经释放
+struct DropCounter(Rc<Cell<usize>>);
+
+impl Drop for DropCounter {
+    fn drop(&mut self) {
+        self.0.set(self.0.get() + 1);
+    }
+}
+
+#[test_case]
+fn aborted_task_is_dropped() {
+    let drops = Rc::new(Cell::new(0));
+    let mut executor = Executor::new();
+    let counter = DropCounter(drops.clone());
+    let handle = executor
+        .spawn(async move {
+            let _counter = counter;
+            future::pending::<()>().await;
+        })
+        .unwrap();
+    executor.run_until_idle();
+    assert!(!handle.is_finished());
+    assert_eq!(drops.get(), 0);
+
+    handle.abort();
+    executor.run_until_idle();
+    assert_eq!(drops.get(), 1);
+    assert!(handle.is_finished());
+    assert_eq!(poll_once(handle), Poll::Ready(Err(JoinError::Cancelled)));
+}
+
+#[test_case]
+fn token_cancels_from_another_task() {
+    let mut executor = Executor::new();
+    let victim = executor.spawn(future::pending::<u32>()).unwrap();
+    let token = victim.cancellation_token();
+    let canceller = executor
+        .spawn(async move {
+            token.cancel();
+            token.is_cancelled()
+        })
+        .unwrap();
+    executor.run_until_idle();
+    assert_eq!(poll_once(canceller), Poll::Ready(Ok(true)));
+    assert_eq!(poll_once(victim), Poll::Ready(Err(JoinError::Cancelled)));
+}
+
+#[test_case]
+fn abort_after_completion_keeps_output() {
+    let mut executor = Executor::new();
+    let handle = executor.spawn(async { 1 }).unwrap();
+    executor.run_until_idle();
+    handle.abort();
+    executor.run_until_idle();
+    assert_eq!(poll_once(handle), Poll::Ready(Ok(1)));
+}
+
+#[test_case]
+fn higher_priority_runs_first() {
+    let order = Rc::new(RefCell::new(Vec::new()));
+    let mut executor = Executor::new();
+    for (name, priority) in [
+        ("low", Priority::Low),
+        ("normal", Priority::Normal),
+        ("high", Priority::High),
+    ] {
+        let order = order.clone();
+        executor
+            .spawn_with_priority(async move { order.borrow_mut().push(name) }, priority)
+            .unwrap();
+    }
+    executor.run_until_idle();
+    assert_eq!(*order.borrow(), ["high", "normal", "low"]);
+}
+
+#[test_case]
+fn yield_now_interleaves_tasks() {
+    let order = Rc::new(RefCell::new(Vec::new()));
+    let mut executor = Executor::new();
+    for name in ["a", "b"] {
+        let order = order.clone();
+        executor
+            .spawn(async move {
+                for _ in 0..3 {
+                    order.borrow_mut().push(name);
+                    task::yield_now().await;
+                }
+            })
+            .unwrap();
+    }
+    executor.run_until_idle();
+    assert_eq!(*order.borrow(), ["a", "b", "a", "b", "a", "b"]);
+}
+
+#[test_case]
+fn exhausted_budget_yields() {
+    let consumed = Rc::new(Cell::new(0));
+    let mut executor = Executor::new();
+    let counter = consumed.clone();
+    executor
+        .spawn(async move {
+            for _ in 0..coop::BUDGET * 2 {
+                coop::consume_budget().await;
+                counter.set(counter.get() + 1);
+            }
+        })
+        .unwrap();
+    let observer = consumed.clone();
+    let seen = executor.spawn(async move { observer.get() }).unwrap();
+    executor.run_until_idle();
+    assert_eq!(poll_once(seen), Poll::Ready(Ok(coop::BUDGET)));
+    assert_eq!(consumed.get(), coop::BUDGET * 2);
+}
+
+#[test_case]
+fn low_priority_is_not_starved() {
+    let done = Rc::new(Cell::new(false));
+    let mut executor = Executor::new();
+    for _ in 0..2 {
+        let done = done.clone();
+        // 低优先级的任务不运行, 这两个任务就永远不会结束
+        executor
+            .spawn_with_priority(
+                async move {
+                    while !done.get() {
+                        task::yield_now().await;
+                    }
+                },
+                Priority::High,
+            )
+            .unwrap();
+    }
+    let flag = done.clone();
+    executor
+        .spawn_with_priority(async move { flag.set(true) }, Priority::Low)
+        .unwrap();
+    executor.run_until_idle();
+    assert!(done.get());
+}
+
+#[test_case]
+fn task_stats_are_recorded() {
+    let mut executor = Executor::new();
+    executor
+        .spawn_named(
+            "yielder",
+            async {
+                task::yield_now().await;
+                task::yield_now().await;
+                future::pending::<()>().await;
+            },
+            Priority::Low,
+        )
+        .unwrap();
+    executor.spawn(async {}).unwrap();
+    executor.run_until_idle();
+
+    // 完成的任务不再出现
+    let tasks = executor.tasks();
+    assert_eq!(tasks.len(), 1);
+    let stats = tasks[0];
+    assert_eq!(stats.name, Some("yielder"));
+    assert_eq!(stats.priority, Priority::Low);
+    assert_eq!(stats.state, TaskState::Waiting);
+    assert_eq!(stats.polls, 3);
+    assert_eq!(stats.wakes, 2);
+    assert!(stats.max_cycles > 0);
+    assert!(stats.total_cycles >= stats.max_cycles);
+
+    let report = executor.report().to_string();
+    assert!(report.starts_with("  ID  NAME"));
+    assert!(report.contains("yielder"));
+    // 全局接口看到的是最近一次运行的执行器
+    assert_eq!(task::tasks(), tasks);
+}
+
+#[test_case]
+fn spawn_fails_at_task_limit() {
+    let mut executor = Executor::with_max_tasks(2);
+    let first = executor.spawn(future::pending::<()>()).unwrap();
+    let spawner = executor.spawner();
+    spawner.spawn(future::pending::<()>()).unwrap();
+    assert_eq!(
+        executor.spawn(async {}).err(),
+        Some(SpawnError::TooManyTasks)
+    );
+    assert_eq!(
+        spawner.spawn(async {}).err(),
+        Some(SpawnError::TooManyTasks)
+    );
+
+    // 任务结束后名额被释放
+    first.abort();
+    executor.run_until_idle();
+    assert!(executor.spawn(async {}).is_ok());
+}
+
+#[test_case]
+fn spawn_fails_after_executor_is_dropped() {
+    let mut executor = Executor::new();
+    let spawner = executor.spawner();
+    executor.run_until_idle();
+    assert!(task::spawn(async {}).is_ok());
+
+    drop(executor);
+    assert_eq!(spawner.spawn(async {}).err(), Some(SpawnError::NoExecutor));
+    assert_eq!(task::spawn(async {}).err(), Some(SpawnError::NoExecutor));
+    assert!(task::tasks().is_empty());
+}
+
+#[test_case]
+fn repeated_wakes_are_coalesced() {
+    let polls = Rc::new(Cell::new(0));
+    let mut executor = Executor::new();
+    let counter = polls.clone();
+    // 只在第一次轮询时连续唤醒自己, 之后一直等待
+    executor
+        .spawn(future::poll_fn(move |cx| {
+            counter.set(counter.get() + 1);
+            if counter.get() == 1 {
+                for _ in 0..1000 {
+                    cx.waker().wake_by_ref();
+                }
+            }
+            Poll::<()>::Pending
+        }))
+        .unwrap();
+    executor.run_until_idle();
+    assert_eq!(polls.get(), 2);
+    assert_eq!(executor.tasks()[0].wakes, 1000);
+}
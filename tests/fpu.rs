@@ -0,0 +1,74 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{entry_point, BootInfo};
+use core::arch::asm;
+use core::panic::PanicInfo;
+use rust_os::process::{self, programs, ExitStatus};
+use rust_os::thread;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    // 内核初始化
+    rust_os::init(boot_info);
+
+    test_main();
+    rust_os::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rust_os::test_panic_handler(info)
+}
+
+/// 内核以软浮点编译, 不会生成使用 xmm0 的代码, 所以可以跨越函数调用保存值
+fn write_xmm0(value: u64) {
+    unsafe { asm!("movq xmm0, {}", in(reg) value, options(nomem, nostack)) };
+}
+
+fn read_xmm0() -> u64 {
+    let value;
+    unsafe { asm!("movq {}, xmm0", out(reg) value, options(nomem, nostack)) };
+    value
+}
+
+#[test_case]
+fn threads_keep_their_own_registers() {
+    fn check(value: u64) {
+        write_xmm0(value);
+        for _ in 0..100 {
+            thread::yield_now();
+            assert_eq!(read_xmm0(), value);
+        }
+    }
+
+    let a = thread::spawn_thread(|| check(0x1111_2222_3333_4444));
+    let b = thread::spawn_thread(|| check(0x5555_6666_7777_8888));
+    check(0x9999_aaaa_bbbb_cccc);
+    thread::join(a).expect("join failed");
+    thread::join(b).expect("join failed");
+}
+
+#[test_case]
+fn new_thread_starts_with_clean_registers() {
+    write_xmm0(u64::MAX);
+    let id = thread::spawn_thread(|| assert_eq!(read_xmm0(), 0));
+    thread::join(id).expect("join failed");
+    assert_eq!(read_xmm0(), u64::MAX);
+}
+
+#[test_case]
+fn processes_do_fp_math_concurrently() {
+    let image = programs::find("fpu").expect("program missing");
+    let pids = [
+        process::spawn(image, &["fpu"], &[]).expect("spawn failed"),
+        process::spawn(image, &["fpu"], &[]).expect("spawn failed"),
+    ];
+    for pid in pids {
+        assert_eq!(process::wait(Some(pid)), Ok((pid, ExitStatus::Exited(0))));
+    }
+}
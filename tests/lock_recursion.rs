@@ -0,0 +1,27 @@
+#![no_std]
+#![no_main]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use rust_os::sync::IrqSafeMutex;
+use rust_os::{exit_qemu, serial_print, serial_println, QemuExitCode};
+
+entry_point!(main);
+
+static LOCK: IrqSafeMutex<u32> = IrqSafeMutex::new(0);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    rust_os::init(boot_info);
+
+    serial_print!("lock_recursion::recursive_lock_panics...\t");
+    let _outer = LOCK.lock();
+    let _inner = LOCK.lock();
+    serial_println!("[test did not panic]");
+    exit_qemu(QemuExitCode::Failed);
+    rust_os::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rust_os::test_expect_panic(info, "lockdep: recursive acquisition")
+}
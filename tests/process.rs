@@ -0,0 +1,103 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use rust_os::process::{self, programs, ExitStatus, ProcessError, ProcessState};
+use rust_os::{memory, thread};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    // 内核初始化
+    rust_os::init(boot_info);
+
+    test_main();
+    rust_os::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rust_os::test_panic_handler(info)
+}
+
+fn spawn(name: &str) -> process::Pid {
+    let image = programs::find(name).expect("program missing");
+    process::spawn(image, &[name], &[]).expect("spawn failed")
+}
+
+#[test_case]
+fn wait_without_children() {
+    assert_eq!(process::wait(None), Err(ProcessError::NoChild));
+}
+
+#[test_case]
+fn exit_status_is_reported() {
+    let pid = spawn("exit_code");
+    let info = process::info(pid).expect("process missing");
+    assert_eq!(info.parent, None);
+    assert_eq!(process::wait(Some(pid)), Ok((pid, ExitStatus::Exited(42))));
+    assert_eq!(process::info(pid), None);
+}
+
+#[test_case]
+fn kill_running_process() {
+    let pid = spawn("spin");
+    thread::sleep(20);
+    assert_eq!(
+        process::info(pid).map(|info| info.state),
+        Some(ProcessState::Running)
+    );
+    process::kill(pid).expect("kill failed");
+    assert_eq!(process::wait(None), Ok((pid, ExitStatus::Killed)));
+    assert_eq!(process::kill(pid), Err(ProcessError::NoSuchProcess));
+}
+
+#[test_case]
+fn kill_sleeping_process() {
+    let pid = spawn("sleeper");
+    thread::sleep(20);
+    process::kill(pid).expect("kill failed");
+    // 不用等到睡眠结束
+    assert_eq!(process::wait(Some(pid)), Ok((pid, ExitStatus::Killed)));
+}
+
+#[test_case]
+fn faulting_process_is_killed() {
+    // 参数个数不符合预期时 `args` 执行 ud2
+    let pid = spawn("args");
+    assert_eq!(process::wait(Some(pid)), Ok((pid, ExitStatus::Killed)));
+}
+
+#[test_case]
+fn process_spawns_and_waits_children() {
+    let pid = spawn("spawner");
+    assert_eq!(process::wait(Some(pid)), Ok((pid, ExitStatus::Exited(0))));
+    assert!(process::children(pid).is_empty());
+}
+
+#[test_case]
+fn syscall_surface() {
+    let pid = spawn("syscalls");
+    assert_eq!(process::wait(Some(pid)), Ok((pid, ExitStatus::Exited(0))));
+}
+
+#[test_case]
+fn reaped_processes_free_their_memory() {
+    // 第一轮可能为线程分配新的内核栈, 之后的线程复用它
+    let pid = spawn("syscalls");
+    assert_eq!(process::wait(Some(pid)), Ok((pid, ExitStatus::Exited(0))));
+
+    let free = memory::free_frames();
+    for _ in 0..10 {
+        // `syscalls` 还会 mmap 不释放的页
+        for name in ["exit_code", "syscalls"] {
+            let pid = spawn(name);
+            assert!(process::wait(Some(pid)).is_ok());
+        }
+    }
+    assert_eq!(memory::free_frames(), free);
+}
@@ -0,0 +1,38 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use rust_os::smp;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    // 内核初始化, 其中会启动所有 AP
+    rust_os::init(boot_info);
+
+    test_main();
+    rust_os::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rust_os::test_panic_handler(info)
+}
+
+/// QEMU 以 `-smp 4` 启动, 见 Cargo.toml 中的 test-args
+#[test_case]
+fn all_cpus_online() {
+    assert_eq!(smp::cpu_count(), 4);
+    for cpu in 0..smp::cpu_count() {
+        assert!(smp::is_online(cpu), "cpu {} did not reach Rust code", cpu);
+    }
+}
+
+#[test_case]
+fn bsp_is_cpu_zero() {
+    assert_eq!(smp::current_cpu(), 0);
+}
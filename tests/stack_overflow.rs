@@ -2,27 +2,106 @@
 #![no_main]
 #![feature(abi_x86_interrupt)]
 
+use bootloader::{entry_point, BootInfo};
+use core::arch::asm;
 use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicU64, Ordering};
 use lazy_static::lazy_static;
-use rust_os::{exit_qemu, serial_print, serial_println, QemuExitCode};
+use rust_os::gdt::{DOUBLE_FAULT_IST_INDEX, MACHINE_CHECK_IST_INDEX, NMI_IST_INDEX};
+use rust_os::{exit_qemu, gdt, memory, percpu, serial_print, serial_println, QemuExitCode};
 use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame};
+use x86_64::structures::paging::Translate;
+use x86_64::VirtAddr;
 
-#[no_mangle]
-pub extern "C" fn _start() -> ! {
-    serial_print!("stack_overflow::stack_overflow...\t");
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    unsafe { memory::init_kernel_memory(phys_mem_offset, &boot_info.memory_map) };
 
-    rust_os::gdt::init();
+    percpu::init(0);
+    gdt::init();
     init_test_idt();
 
+    serial_print!("stack_overflow::ist_guard_pages...\t");
+    ist_guard_pages();
+    serial_println!("[ok]");
+
+    serial_print!("stack_overflow::nmi_ist_stack...\t");
+    unsafe { asm!("int 2") };
+    assert_on_ist_stack(NMI_IST_INDEX, NMI_RSP.load(Ordering::SeqCst));
+    serial_println!("[ok]");
+
+    serial_print!("stack_overflow::machine_check_ist_stack...\t");
+    unsafe { asm!("int 18") };
+    assert_on_ist_stack(
+        MACHINE_CHECK_IST_INDEX,
+        MACHINE_CHECK_RSP.load(Ordering::SeqCst),
+    );
+    serial_println!("[ok]");
+
+    serial_print!("stack_overflow::stack_overflow...\t");
     stack_overflow();
 
     panic!("Execution continued after stack overflow");
 }
 
+/// 每个 IST 栈都是独立映射的, 且下方的保护页没有映射
+fn ist_guard_pages() {
+    let indexes = [
+        DOUBLE_FAULT_IST_INDEX,
+        NMI_IST_INDEX,
+        MACHINE_CHECK_IST_INDEX,
+    ];
+    memory::with_kernel_memory(|mapper, _| {
+        for index in indexes {
+            let stack = gdt::ist_stack(index).expect("missing IST stack");
+            assert!(mapper.translate_addr(stack.start()).is_some());
+            assert!(mapper.translate_addr(stack.end() - 1u64).is_some());
+            assert!(mapper
+                .translate_addr(stack.guard_page().start_address())
+                .is_none());
+
+            for other in indexes.iter().filter(|&&other| other != index) {
+                let other = gdt::ist_stack(*other).unwrap();
+                assert!(!other.contains(stack.start()));
+            }
+        }
+    });
+}
+
+fn assert_on_ist_stack(index: u16, rsp: u64) {
+    let stack = gdt::ist_stack(index).expect("missing IST stack");
+    assert!(
+        stack.contains(VirtAddr::new(rsp)),
+        "rsp {:#x} not on IST stack {:?}",
+        rsp,
+        stack
+    );
+}
+
+fn current_rsp() -> u64 {
+    let rsp: u64;
+    unsafe { asm!("mov {}, rsp", out(reg) rsp, options(nomem, nostack, preserves_flags)) };
+    rsp
+}
+
+static NMI_RSP: AtomicU64 = AtomicU64::new(0);
+static MACHINE_CHECK_RSP: AtomicU64 = AtomicU64::new(0);
+
+extern "x86-interrupt" fn test_nmi_handler(_stack_frame: InterruptStackFrame) {
+    NMI_RSP.store(current_rsp(), Ordering::SeqCst);
+}
+
+extern "x86-interrupt" fn test_machine_check_handler(_stack_frame: InterruptStackFrame) {
+    MACHINE_CHECK_RSP.store(current_rsp(), Ordering::SeqCst);
+}
+
 extern "x86-interrupt" fn test_double_fault_handler(
     _stack_frame: InterruptStackFrame,
     _error_code: u64,
 ) -> ! {
+    assert_on_ist_stack(DOUBLE_FAULT_IST_INDEX, current_rsp());
     serial_println!("[ok]");
     exit_qemu(QemuExitCode::Success);
     rust_os::hlt_loop();
@@ -45,7 +124,15 @@ lazy_static! {
         unsafe {
             idt.double_fault
                 .set_handler_fn(test_double_fault_handler)
-                .set_stack_index(rust_os::gdt::DOUBLE_FAULT_IST_INDEX);
+                .set_stack_index(DOUBLE_FAULT_IST_INDEX);
+            idt.non_maskable_interrupt
+                .set_handler_fn(test_nmi_handler)
+                .set_stack_index(NMI_IST_INDEX);
+            // 真正的 machine check 不可恢复, 所以处理函数类型是发散的;
+            // 这里只用 `int 18` 软件触发, 需要能返回
+            idt.machine_check
+                .set_handler_addr(VirtAddr::new(test_machine_check_handler as usize as u64))
+                .set_stack_index(MACHINE_CHECK_IST_INDEX);
         }
         idt
     };
@@ -0,0 +1,109 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{entry_point, BootInfo};
+use core::arch::global_asm;
+use core::panic::PanicInfo;
+use rust_os::{memory, syscall, thread};
+use x86_64::structures::paging::{FrameAllocator, Mapper, Page, PageTableFlags, Size4KiB};
+use x86_64::VirtAddr;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    // 内核初始化
+    rust_os::init(boot_info);
+
+    test_main();
+    rust_os::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rust_os::test_panic_handler(info)
+}
+
+/// 用户代码和用户栈的位置, 独占一个 4 级页表项, 中间各级页表都带有用户可访问标志
+const USER_CODE: u64 = 0x1000_0000_0000;
+const USER_STACK: u64 = USER_CODE + 0x1000;
+/// 用户代码执行到最后时写在用户栈底的值
+const MARKER: u64 = 0x600d;
+
+global_asm!(
+    ".global user_program_start, user_program_end",
+    "user_program_start:",
+    // 确认运行在 ring 3
+    "movw %cs, %ax",
+    "andw $3, %ax",
+    "cmpw $3, %ax",
+    "jne 2f",
+    // rbx 在系统调用前后保持不变
+    "movq $3, %rbx",
+    "1:",
+    "movq ${sys_yield}, %rax",
+    "syscall",
+    "testq %rax, %rax",
+    "jnz 2f",
+    "decq %rbx",
+    "jnz 1b",
+    // 不存在的系统调用返回 -ENOSYS
+    "movq $1000, %rax",
+    "syscall",
+    "cmpq $-38, %rax",
+    "jne 2f",
+    "movq ${marker}, %rax",
+    "movabsq %rax, {stack}",
+    "movq ${sys_exit}, %rax",
+    "xorl %edi, %edi",
+    "syscall",
+    "2:",
+    "ud2",
+    "user_program_end:",
+    sys_yield = const syscall::SYS_YIELD,
+    sys_exit = const syscall::SYS_EXIT,
+    marker = const MARKER,
+    stack = const USER_STACK,
+    options(att_syntax)
+);
+
+extern "C" {
+    static user_program_start: u8;
+    static user_program_end: u8;
+}
+
+fn map_user_page(addr: u64) {
+    let page = Page::<Size4KiB>::containing_address(VirtAddr::new(addr));
+    let flags =
+        PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE;
+    memory::with_kernel_memory(|mapper, frame_allocator| {
+        let frame = frame_allocator.allocate_frame().expect("out of frames");
+        unsafe {
+            mapper
+                .map_to(page, frame, flags, frame_allocator)
+                .expect("map_to failed")
+                .flush()
+        };
+    });
+}
+
+#[test_case]
+fn user_program_makes_syscalls() {
+    map_user_page(USER_CODE);
+    map_user_page(USER_STACK);
+
+    let start = core::ptr::addr_of!(user_program_start);
+    let len = core::ptr::addr_of!(user_program_end) as usize - start as usize;
+    unsafe { core::ptr::copy_nonoverlapping(start, USER_CODE as *mut u8, len) };
+
+    let user = thread::spawn_thread(|| unsafe {
+        syscall::enter_user_mode(VirtAddr::new(USER_CODE), VirtAddr::new(USER_STACK + 0x1000))
+    });
+    thread::join(user).expect("join failed");
+    assert_eq!(
+        unsafe { (USER_STACK as *const u64).read_volatile() },
+        MARKER
+    );
+}
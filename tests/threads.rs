@@ -0,0 +1,138 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use rust_os::{thread, time};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    // 内核初始化
+    rust_os::init(boot_info);
+
+    test_main();
+    rust_os::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rust_os::test_panic_handler(info)
+}
+
+#[test_case]
+fn join_returns_after_thread_exits() {
+    static DONE: AtomicBool = AtomicBool::new(false);
+
+    let id = thread::spawn_thread(|| DONE.store(true, Ordering::SeqCst));
+    thread::join(id).expect("join failed");
+    assert!(DONE.load(Ordering::SeqCst));
+    assert_eq!(thread::join(id), Err(thread::JoinError::NoSuchThread));
+}
+
+#[test_case]
+fn busy_thread_is_preempted() {
+    static FLAG: AtomicBool = AtomicBool::new(false);
+
+    // 不主动让出 CPU 的死循环, 只有被抢占后另一个线程才能设置 FLAG
+    let spinner = thread::spawn_thread(|| {
+        while !FLAG.load(Ordering::SeqCst) {
+            core::hint::spin_loop();
+        }
+    });
+    let setter = thread::spawn_thread(|| FLAG.store(true, Ordering::SeqCst));
+    thread::join(spinner).expect("join spinner failed");
+    thread::join(setter).expect("join setter failed");
+}
+
+#[test_case]
+fn sleep_waits_for_ticks() {
+    let start = time::ticks();
+    thread::sleep(50);
+    assert!(time::ticks() >= start + time::ms_to_ticks(50));
+}
+
+#[test_case]
+fn threads_interleave_with_yield() {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+
+    let workers: [thread::ThreadId; 4] = core::array::from_fn(|_| {
+        thread::spawn_thread(|| {
+            for _ in 0..100 {
+                COUNTER.fetch_add(1, Ordering::SeqCst);
+                thread::yield_now();
+            }
+        })
+    });
+    for worker in workers {
+        thread::join(worker).expect("join failed");
+    }
+    assert_eq!(COUNTER.load(Ordering::SeqCst), 400);
+}
+
+#[test_case]
+fn idle_executor_thread_yields_to_busy_thread() {
+    use rust_os::sync::IrqSafeMutex;
+    use rust_os::task::executor::Executor;
+    use rust_os::task::Spawner;
+
+    static SPAWNER: IrqSafeMutex<Option<Spawner>> = IrqSafeMutex::new(None);
+    static STOP: AtomicBool = AtomicBool::new(false);
+    static TICKS_SEEN: AtomicU64 = AtomicU64::new(0);
+    static DONE: AtomicBool = AtomicBool::new(false);
+
+    thread::spawn_thread(|| {
+        let mut executor = Executor::new();
+        *SPAWNER.lock() = Some(executor.spawner());
+        executor.run();
+    });
+    // 数自己运行时经过了几个 tick, 不主动让出 CPU
+    let busy = thread::spawn_thread(|| {
+        let mut last = time::ticks();
+        while !STOP.load(Ordering::SeqCst) {
+            let now = time::ticks();
+            if now != last {
+                last = now;
+                TICKS_SEEN.fetch_add(1, Ordering::SeqCst);
+            }
+        }
+    });
+    let spawner = loop {
+        if let Some(spawner) = SPAWNER.lock().clone() {
+            break spawner;
+        }
+        thread::yield_now();
+    };
+
+    // 执行器空闲时不占用 CPU, 忙碌的线程几乎能看到每一个 tick
+    let window = time::ms_to_ticks(200);
+    thread::sleep(10);
+    let seen = TICKS_SEEN.load(Ordering::SeqCst);
+    thread::sleep(200);
+    let seen = TICKS_SEEN.load(Ordering::SeqCst) - seen;
+    assert!(
+        seen * 4 >= window * 3,
+        "busy thread saw {} of {} ticks",
+        seen,
+        window
+    );
+
+    // 提交任务会唤醒空闲的执行器
+    spawner
+        .spawn(async { DONE.store(true, Ordering::SeqCst) })
+        .expect("failed to spawn task");
+    for _ in 0..100 {
+        if DONE.load(Ordering::SeqCst) {
+            break;
+        }
+        thread::sleep(10);
+    }
+    assert!(DONE.load(Ordering::SeqCst), "idle executor was not woken");
+
+    STOP.store(true, Ordering::SeqCst);
+    thread::join(busy).expect("join busy thread failed");
+}
@@ -0,0 +1,32 @@
+#![no_std]
+#![no_main]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use rust_os::serial_print;
+use rust_os::task::executor::Executor;
+use rust_os::watchdog::{self, Action};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    rust_os::init(boot_info);
+
+    serial_print!("watchdog::hung_task_is_reported...\t");
+    watchdog::enable(200, Action::Panic);
+    let mut executor = Executor::new();
+    executor.spawn(hung_task()).unwrap();
+    executor.run();
+}
+
+/// 从不返回 `Pending` 的任务
+async fn hung_task() {
+    loop {
+        core::hint::spin_loop();
+    }
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rust_os::test_expect_panic(info, "watchdog: task")
+}
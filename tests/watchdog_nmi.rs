@@ -0,0 +1,27 @@
+#![no_std]
+#![no_main]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use rust_os::serial_print;
+use rust_os::watchdog::{self, Action};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    rust_os::init(boot_info);
+
+    serial_print!("watchdog_nmi::interrupts_disabled_is_reported...\t");
+    // 测试以 -smp 4 运行, 由 AP 发现 BSP 的时钟中断停止
+    assert!(rust_os::smp::cpu_count() > 1);
+    watchdog::enable(200, Action::Panic);
+    x86_64::instructions::interrupts::disable();
+    loop {
+        core::hint::spin_loop();
+    }
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rust_os::test_expect_panic(info, "watchdog: no timer interrupt")
+}
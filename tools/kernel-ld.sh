@@ -0,0 +1,60 @@
+#!/bin/sh
+# 内核的链接器: 链接后把代码段符号表嵌入内核, 供 `src/backtrace.rs` 解析返回地址
+#
+# - 先以空符号表链接一次, 用 `llvm-nm` 从产物中取出符号, 再带着符号表重新链接
+# - 符号表是 `.data` 中的一段文本, 位于代码段之后, 它的大小不影响代码地址;
+#   仍然重复链接直到符号表不再变化, 保证嵌入的地址和最终产物一致
+# - rustc 调用链接器时会把工具链的 `bin` 目录加入 PATH, 这里用到的 `rust-lld`,
+#   `llvm-nm` 和 `llvm-objcopy` 都在其中(`llvm-tools-preview` 组件)
+
+set -eu
+
+out=
+prev=
+for arg in "$@"; do
+    if [ "$prev" = "-o" ]; then
+        out=$arg
+    fi
+    prev=$arg
+done
+if [ -z "$out" ]; then
+    echo "kernel-ld: no output file given" >&2
+    exit 1
+fi
+
+dir="$out.symbols"
+mkdir -p "$dir"
+: > "$dir/ksyms"
+
+# 生成 `_binary_ksyms_start` 和 `_binary_ksyms_end` 之间的符号表对象
+make_object() {
+    (cd "$dir" && llvm-objcopy -I binary -O elf64-x86-64 \
+        --rename-section .data=.data.ksyms ksyms ksyms.o)
+}
+
+# 每行一个代码段符号: 起始地址 大小 名字, 按地址升序, 同一地址只保留第一个;
+# 汇编中定义的符号没有大小, 记为 0. 名字去掉 Rust 符号末尾的哈希
+list_symbols() {
+    llvm-nm -n -S -C --defined-only "$out" | awk '
+        $2 ~ /^[tTwW]$/ { name = $0; sub(/^[^ ]+ [^ ]+ /, "", name); size = "0" }
+        $3 ~ /^[tTwW]$/ { name = $0; sub(/^[^ ]+ [^ ]+ [^ ]+ /, "", name); size = $2 }
+        $2 ~ /^[tTwW]$/ || $3 ~ /^[tTwW]$/ {
+            sub(/::h[0-9a-f]+$/, "", name)
+            if (!seen[$1]++) print $1, size, name
+        }
+    '
+}
+
+for _ in 1 2 3 4; do
+    make_object
+    rust-lld -flavor gnu "$@" "$dir/ksyms.o"
+    list_symbols > "$dir/ksyms.new"
+    if cmp -s "$dir/ksyms.new" "$dir/ksyms"; then
+        rm -f "$dir/ksyms.new"
+        exit 0
+    fi
+    mv "$dir/ksyms.new" "$dir/ksyms"
+done
+
+echo "kernel-ld: symbol table did not converge" >&2
+exit 1
@@ -0,0 +1,87 @@
+//! 检查内核为新程序准备的初始栈
+//!
+//! - 期望以参数 `args hello` 和环境变量 `KEY=value` 启动
+//! - 检查失败时执行 `ud2`, 在内核中表现为致命的 #UD 异常; 全部通过时调用 exit
+
+#![no_std]
+#![no_main]
+
+use core::arch::{asm, global_asm};
+
+const SYS_EXIT: u64 = 0;
+
+const AT_NULL: u64 = 0;
+const AT_PAGESZ: u64 = 6;
+const AT_ENTRY: u64 = 9;
+
+/// 检查 `.data` 的初始值和 `.bss` 的清零
+static mut DATA: u64 = 0x1234;
+static mut BSS: [u64; 64] = [0; 64];
+
+// 进入时 rsp 指向 argc, 16 字节对齐; 按调用约定把它作为参数传给 Rust 函数
+global_asm!(
+    ".section .text._start",
+    ".global _start",
+    "_start:",
+    "mov rdi, rsp",
+    "call {main}",
+    "ud2",
+    main = sym main,
+);
+
+fn check(condition: bool) {
+    if !condition {
+        unsafe { asm!("ud2", options(noreturn)) };
+    }
+}
+
+unsafe fn c_str(ptr: *const u8) -> &'static [u8] {
+    let mut len = 0;
+    while *ptr.add(len) != 0 {
+        len += 1;
+    }
+    core::slice::from_raw_parts(ptr, len)
+}
+
+unsafe extern "C" fn main(sp: *const u64) -> ! {
+    let argc = *sp as usize;
+    let argv = sp.add(1) as *const *const u8;
+    check(argc == 2);
+    check(c_str(*argv) == b"args");
+    check(c_str(*argv.add(1)) == b"hello");
+    check((*argv.add(2)).is_null());
+
+    let envp = argv.add(argc + 1);
+    check(c_str(*envp) == b"KEY=value");
+    check((*envp.add(1)).is_null());
+
+    let mut auxv = envp.add(2) as *const u64;
+    let (mut page_size, mut entry) = (0, 0);
+    while *auxv != AT_NULL {
+        match *auxv {
+            AT_PAGESZ => page_size = *auxv.add(1),
+            AT_ENTRY => entry = *auxv.add(1),
+            _ => {}
+        }
+        auxv = auxv.add(2);
+    }
+    extern "C" {
+        fn _start();
+    }
+    check(page_size == 4096);
+    check(entry == _start as usize as u64);
+
+    let data = core::ptr::addr_of_mut!(DATA);
+    check(data.read_volatile() == 0x1234);
+    data.write_volatile(0x5678);
+    check(data.read_volatile() == 0x5678);
+    let bss = core::ptr::addr_of_mut!(BSS);
+    check((*bss).iter().all(|&x| x == 0));
+
+    asm!("syscall", in("rax") SYS_EXIT, in("rdi") 0, options(noreturn));
+}
+
+#[panic_handler]
+fn panic(_info: &core::panic::PanicInfo) -> ! {
+    unsafe { asm!("ud2", options(noreturn)) };
+}
@@ -0,0 +1,11 @@
+//! 以退出码 42 结束, 用于检查进程的退出状态
+
+#![no_std]
+#![no_main]
+
+extern crate rt;
+
+#[no_mangle]
+fn main() -> i32 {
+    42
+}
@@ -0,0 +1,59 @@
+//! 在让出 CPU 前后检查 SSE 寄存器和浮点计算结果, 用于检查线程切换保存了扩展寄存器状态
+//!
+//! - 每个进程以自己的进程号作为寄存器中的值, 多个实例同时运行时互相之间不能看到对方的值
+//! - 寄存器不一致时以退出码 1 结束, 浮点结果不一致时以 2 结束
+
+#![no_std]
+#![no_main]
+
+extern crate rt;
+
+use core::arch::asm;
+
+const ROUNDS: u64 = 200;
+
+#[no_mangle]
+fn main() -> i32 {
+    let seed = rt::getpid();
+    for round in 0..ROUNDS {
+        let value = seed << 32 | round;
+        let (low, high): (u64, u64);
+        // 写寄存器, 让出 CPU 和读回放在同一段汇编中, 中间编译器不会使用这些寄存器
+        unsafe {
+            asm!(
+                "movq xmm0, {value}",
+                "movq xmm15, {value}",
+                "syscall",
+                "movq {low}, xmm0",
+                "movq {high}, xmm15",
+                value = in(reg) value,
+                low = lateout(reg) low,
+                high = lateout(reg) high,
+                inout("rax") rt::SYS_YIELD => _,
+                out("rcx") _,
+                out("r11") _,
+                out("xmm0") _,
+                out("xmm15") _,
+            );
+        }
+        if low != value || high != value {
+            return 1;
+        }
+    }
+
+    // 累加过程跨越多次抢占, 结果只取决于本进程的输入
+    let step = 1.0 / (seed as f64 + 1.0);
+    let mut sum = 0.0f64;
+    for i in 0..100_000u32 {
+        sum += step * f64::from(i % 7);
+        if i % 10_000 == 0 {
+            rt::yield_now();
+        }
+    }
+    let expected = step * 299_995.0;
+    let tolerance = expected * 1e-9;
+    if sum - expected > tolerance || expected - sum > tolerance {
+        return 2;
+    }
+    0
+}
@@ -0,0 +1,10 @@
+//! 以最大的毫秒数睡眠, 用于检查毫秒数不会溢出, 以及 kill 能结束正在睡眠的进程
+
+#![no_std]
+#![no_main]
+
+#[no_mangle]
+fn main() -> i32 {
+    rt::sleep(u64::MAX);
+    0
+}
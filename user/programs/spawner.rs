@@ -0,0 +1,23 @@
+//! 通过系统调用创建和回收子进程
+//!
+//! - 启动 `exit_code` 并等待它, 检查进程号和退出状态; 启动 `spin` 后 kill 并回收它
+//! - 检查失败时 panic, 以退出码 101 结束; 全部通过时以退出码 0 结束
+
+#![no_std]
+#![no_main]
+
+use rt::Errno;
+
+#[no_mangle]
+fn main() -> i32 {
+    let child = rt::spawn("exit_code").expect("spawn exit_code");
+    assert_eq!(rt::wait(child), Ok((child, 42 << 8)));
+
+    let child = rt::spawn("spin").expect("spawn spin");
+    rt::kill(child).expect("kill spin");
+    assert_eq!(rt::wait(0), Ok((child, 9)));
+
+    assert_eq!(rt::spawn("missing"), Err(Errno::ENOENT));
+    assert_eq!(rt::wait(0), Err(Errno::ECHILD));
+    0
+}
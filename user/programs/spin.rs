@@ -0,0 +1,13 @@
+//! 在用户态死循环, 用于检查 kill 能结束没有系统调用的进程
+
+#![no_std]
+#![no_main]
+
+extern crate rt;
+
+#[no_mangle]
+fn main() -> i32 {
+    loop {
+        core::hint::spin_loop();
+    }
+}
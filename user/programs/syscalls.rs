@@ -0,0 +1,119 @@
+//! 检查系统调用的基本行为和用户指针检查
+//!
+//! - 检查失败时 panic, 以退出码 101 结束; 全部通过时以退出码 0 结束
+
+#![no_std]
+#![no_main]
+
+use rt::{Errno, PROT_READ, PROT_WRITE};
+
+const PAGE_SIZE: usize = 4096;
+
+/// 内核地址和没有映射的用户地址
+const KERNEL_ADDR: u64 = 0xffff_8000_0000_0000;
+const UNMAPPED_ADDR: u64 = 0x0000_3000_0000_0000;
+
+static MESSAGE: &str = "hello from user space\n";
+
+unsafe fn raw(number: u64, args: [u64; 4]) -> Result<u64, Errno> {
+    match rt::syscall(number, args) {
+        ret if ret < 0 => Err(Errno(-ret)),
+        ret => Ok(ret as u64),
+    }
+}
+
+fn check_pointers() {
+    let write = |addr, len| unsafe { raw(rt::SYS_WRITE, [1, addr, len, 0]) };
+    assert_eq!(write(KERNEL_ADDR, 8), Err(Errno::EFAULT));
+    assert_eq!(write(UNMAPPED_ADDR, 8), Err(Errno::EFAULT));
+    // 跨越用户地址范围末尾
+    assert_eq!(write(0x0000_3fff_ffff_fffc, 8), Err(Errno::EFAULT));
+    assert_eq!(write(u64::MAX - 3, 8), Err(Errno::EFAULT));
+
+    // 只读数据不能作为 read 的缓冲区
+    let null = rt::open("/dev/null").expect("open /dev/null");
+    let ro = MESSAGE.as_ptr() as u64;
+    let read = |fd: usize, addr, len| unsafe { raw(rt::SYS_READ, [fd as u64, addr, len, 0]) };
+    assert_eq!(read(null, ro, 4), Err(Errno::EFAULT));
+    assert_eq!(read(null, KERNEL_ADDR, 4), Err(Errno::EFAULT));
+    rt::close(null).expect("close /dev/null");
+
+    let wait = unsafe { raw(rt::SYS_WAIT, [0, KERNEL_ADDR, 0, 0]) };
+    assert_eq!(wait, Err(Errno::EFAULT));
+    let open = unsafe { raw(rt::SYS_OPEN, [KERNEL_ADDR, 4, 0, 0]) };
+    assert_eq!(open, Err(Errno::EFAULT));
+    let open = unsafe { raw(rt::SYS_OPEN, [ro, 4096, 0, 0]) };
+    assert_eq!(open, Err(Errno::ENAMETOOLONG));
+}
+
+fn check_files() {
+    assert_eq!(rt::write(rt::STDOUT, MESSAGE.as_bytes()), Ok(MESSAGE.len()));
+    assert_eq!(rt::write(7, b"x"), Err(Errno::EBADF));
+    assert_eq!(rt::open("/no/such/file"), Err(Errno::ENOENT));
+
+    // 新的描述符使用最小的空闲下标
+    let fd = rt::open("/bin/exit_code").expect("open /bin/exit_code");
+    assert_eq!(fd, 3);
+    let mut magic = [0; 4];
+    assert_eq!(rt::read(fd, &mut magic), Ok(4));
+    assert_eq!(&magic, b"\x7fELF");
+    assert_eq!(rt::write(fd, b"x"), Err(Errno::EBADF));
+    assert_eq!(rt::close(fd), Ok(()));
+    assert_eq!(rt::close(fd), Err(Errno::EBADF));
+}
+
+fn check_memory() {
+    let len = 3 * PAGE_SIZE;
+    let ptr = rt::mmap(0, len, PROT_READ | PROT_WRITE).expect("mmap");
+    assert_eq!(ptr as usize % PAGE_SIZE, 0);
+    let memory = unsafe { core::slice::from_raw_parts_mut(ptr, len) };
+    assert!(memory.iter().all(|&byte| byte == 0));
+    memory.fill(0xa5);
+
+    // 新映射不与已有映射重叠
+    let other = rt::mmap(ptr as u64, PAGE_SIZE, PROT_READ).expect("mmap");
+    assert!(other as usize >= ptr as usize + len || (other as usize) + PAGE_SIZE <= ptr as usize);
+    assert_eq!(rt::mmap(0, 0, PROT_READ), Err(Errno::EINVAL));
+    // 超过单次映射的上限
+    assert_eq!(rt::mmap(0, 1 << 40, PROT_READ), Err(Errno::ENOMEM));
+
+    // 只读映射不能作为 read 的缓冲区, 解除映射后不能再作为 write 的数据
+    let null = rt::open("/dev/null").expect("open /dev/null");
+    let read = unsafe { raw(rt::SYS_READ, [null as u64, other as u64, 1, 0]) };
+    assert_eq!(read, Err(Errno::EFAULT));
+    unsafe { rt::munmap(ptr, len) }.expect("munmap");
+    let write = unsafe { raw(rt::SYS_WRITE, [null as u64, ptr as u64, 1, 0]) };
+    assert_eq!(write, Err(Errno::EFAULT));
+    rt::close(null).expect("close /dev/null");
+
+    // 解除映射的范围可以再次映射, 内容重新清零
+    let again = rt::mmap(ptr as u64, PAGE_SIZE, PROT_READ | PROT_WRITE).expect("mmap");
+    assert_eq!(again, ptr);
+    assert_eq!(unsafe { *again }, 0);
+}
+
+fn check_sleep() {
+    rt::sleep(10);
+    // 毫秒数换算成 tick 时不能溢出; 睡眠中的子进程可以被 kill
+    let child = rt::spawn("sleeper").expect("spawn sleeper");
+    rt::yield_now();
+    rt::kill(child).expect("kill sleeper");
+    assert_eq!(rt::wait(child), Ok((child, 9)));
+}
+
+#[no_mangle]
+fn main() -> i32 {
+    let pid = rt::getpid();
+    assert!(pid > 0);
+    assert_eq!(rt::args().next(), Some("syscalls"));
+    rt::println!("syscalls: pid {}", pid);
+
+    check_pointers();
+    check_files();
+    check_memory();
+    check_sleep();
+
+    rt::yield_now();
+    assert_eq!(unsafe { raw(1000, [0; 4]) }, Err(Errno::ENOSYS));
+    0
+}
@@ -0,0 +1,295 @@
+//! 用户程序的运行时
+//!
+//! - 提供 `_start`: 保存初始栈指针后调用程序定义的 `#[no_mangle] fn main() -> i32`,
+//!   以它的返回值调用 [`exit`]
+//! - 系统调用的封装, 调用约定和编号见内核的 `syscall` 模块; 出错时返回 [`Errno`]
+//! - [`print!`], [`println!`] 写到标准输出, panic 时把信息写到标准错误并以退出码 101 结束
+//! - 以宿主的 x86_64 目标编译, `core` 中的代码会调用 `memcpy` 等函数, 这里用字符串指令实现
+
+#![no_std]
+
+use core::arch::{asm, global_asm};
+use core::fmt;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+pub const SYS_EXIT: u64 = 0;
+pub const SYS_YIELD: u64 = 1;
+pub const SYS_SPAWN: u64 = 2;
+pub const SYS_WAIT: u64 = 3;
+pub const SYS_KILL: u64 = 4;
+pub const SYS_WRITE: u64 = 5;
+pub const SYS_READ: u64 = 6;
+pub const SYS_GETPID: u64 = 7;
+pub const SYS_SLEEP: u64 = 8;
+pub const SYS_MMAP: u64 = 9;
+pub const SYS_MUNMAP: u64 = 10;
+pub const SYS_OPEN: u64 = 11;
+pub const SYS_CLOSE: u64 = 12;
+
+pub const PROT_READ: u64 = 1 << 0;
+pub const PROT_WRITE: u64 = 1 << 1;
+pub const PROT_EXEC: u64 = 1 << 2;
+
+pub const STDIN: usize = 0;
+pub const STDOUT: usize = 1;
+pub const STDERR: usize = 2;
+
+/// 系统调用返回的错误码
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Errno(pub i64);
+
+impl Errno {
+    pub const ENOENT: Errno = Errno(2);
+    pub const ESRCH: Errno = Errno(3);
+    pub const EINTR: Errno = Errno(4);
+    pub const EBADF: Errno = Errno(9);
+    pub const ECHILD: Errno = Errno(10);
+    pub const ENOMEM: Errno = Errno(12);
+    pub const EFAULT: Errno = Errno(14);
+    pub const EINVAL: Errno = Errno(22);
+    pub const ENAMETOOLONG: Errno = Errno(36);
+    pub const ENOSYS: Errno = Errno(38);
+}
+
+pub type Result<T> = core::result::Result<T, Errno>;
+
+/// 执行系统调用 `number`, 返回原始的返回值
+///
+/// # Safety
+/// 参数必须符合对应系统调用的要求, 例如指针指向的内存在调用期间有效
+pub unsafe fn syscall(number: u64, args: [u64; 4]) -> i64 {
+    let ret: i64;
+    asm!(
+        "syscall",
+        inlateout("rax") number as i64 => ret,
+        in("rdi") args[0],
+        in("rsi") args[1],
+        in("rdx") args[2],
+        in("r10") args[3],
+        lateout("rcx") _,
+        lateout("r11") _,
+        options(nostack),
+    );
+    ret
+}
+
+fn check(ret: i64) -> Result<u64> {
+    if ret < 0 {
+        Err(Errno(-ret))
+    } else {
+        Ok(ret as u64)
+    }
+}
+
+pub fn exit(code: i32) -> ! {
+    unsafe { syscall(SYS_EXIT, [code as u64, 0, 0, 0]) };
+    unreachable!("exit returned");
+}
+
+pub fn yield_now() {
+    unsafe { syscall(SYS_YIELD, [0; 4]) };
+}
+
+/// 启动内核中名为 `name` 的程序, 返回子进程号
+pub fn spawn(name: &str) -> Result<u64> {
+    check(unsafe { syscall(SYS_SPAWN, [name.as_ptr() as u64, name.len() as u64, 0, 0]) })
+}
+
+/// 等待子进程 `pid` (为 0 时任意子进程) 结束, 返回子进程号和 wait 状态
+pub fn wait(pid: u64) -> Result<(u64, i32)> {
+    let mut status = 0i32;
+    let pid = check(unsafe { syscall(SYS_WAIT, [pid, &mut status as *mut i32 as u64, 0, 0]) })?;
+    Ok((pid, status))
+}
+
+pub fn kill(pid: u64) -> Result<()> {
+    check(unsafe { syscall(SYS_KILL, [pid, 0, 0, 0]) }).map(drop)
+}
+
+pub fn write(fd: usize, buf: &[u8]) -> Result<usize> {
+    check(unsafe {
+        syscall(
+            SYS_WRITE,
+            [fd as u64, buf.as_ptr() as u64, buf.len() as u64, 0],
+        )
+    })
+    .map(|len| len as usize)
+}
+
+pub fn read(fd: usize, buf: &mut [u8]) -> Result<usize> {
+    let args = [fd as u64, buf.as_mut_ptr() as u64, buf.len() as u64, 0];
+    check(unsafe { syscall(SYS_READ, args) }).map(|len| len as usize)
+}
+
+pub fn getpid() -> u64 {
+    unsafe { syscall(SYS_GETPID, [0; 4]) as u64 }
+}
+
+pub fn sleep(ms: u64) {
+    unsafe { syscall(SYS_SLEEP, [ms, 0, 0, 0]) };
+}
+
+/// 映射 `len` 字节的清零匿名内存, 返回起始地址
+pub fn mmap(hint: u64, len: usize, prot: u64) -> Result<*mut u8> {
+    check(unsafe { syscall(SYS_MMAP, [hint, len as u64, prot, 0]) }).map(|addr| addr as *mut u8)
+}
+
+/// # Safety
+/// 调用者之后不能再访问被解除映射的内存
+pub unsafe fn munmap(addr: *mut u8, len: usize) -> Result<()> {
+    check(syscall(SYS_MUNMAP, [addr as u64, len as u64, 0, 0])).map(drop)
+}
+
+pub fn open(path: &str) -> Result<usize> {
+    check(unsafe { syscall(SYS_OPEN, [path.as_ptr() as u64, path.len() as u64, 0, 0]) })
+        .map(|fd| fd as usize)
+}
+
+pub fn close(fd: usize) -> Result<()> {
+    check(unsafe { syscall(SYS_CLOSE, [fd as u64, 0, 0, 0]) }).map(drop)
+}
+
+/// 写到文件描述符的 [`fmt::Write`], 忽略写入错误
+pub struct FdWriter(pub usize);
+
+impl fmt::Write for FdWriter {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let mut bytes = s.as_bytes();
+        while !bytes.is_empty() {
+            match write(self.0, bytes) {
+                Ok(0) | Err(_) => return Err(fmt::Error),
+                Ok(len) => bytes = &bytes[len..],
+            }
+        }
+        Ok(())
+    }
+}
+
+#[doc(hidden)]
+pub fn _print(fd: usize, args: fmt::Arguments) {
+    let _ = fmt::Write::write_fmt(&mut FdWriter(fd), args);
+}
+
+#[macro_export]
+macro_rules! print {
+    ($($arg:tt)*) => ($crate::_print($crate::STDOUT, format_args!($($arg)*)));
+}
+
+#[macro_export]
+macro_rules! println {
+    () => ($crate::print!("\n"));
+    ($($arg:tt)*) => ($crate::print!("{}\n", format_args!($($arg)*)));
+}
+
+#[macro_export]
+macro_rules! eprintln {
+    ($($arg:tt)*) => ($crate::_print($crate::STDERR, format_args!("{}\n", format_args!($($arg)*))));
+}
+
+/// `_start` 时的栈指针, 指向 argc
+static INITIAL_SP: AtomicUsize = AtomicUsize::new(0);
+
+/// 程序的参数
+pub fn args() -> impl Iterator<Item = &'static str> {
+    let sp = INITIAL_SP.load(Ordering::Relaxed) as *const usize;
+    let argc = unsafe { *sp };
+    let argv = unsafe { sp.add(1) as *const *const u8 };
+    (0..argc).map(move |i| unsafe { c_str(*argv.add(i)) })
+}
+
+/// 程序的环境变量, 每一项形如 `KEY=value`
+pub fn env() -> impl Iterator<Item = &'static str> {
+    let sp = INITIAL_SP.load(Ordering::Relaxed) as *const usize;
+    let argc = unsafe { *sp };
+    let envp = unsafe { sp.add(argc + 2) as *const *const u8 };
+    (0..)
+        .map(move |i| unsafe { *envp.add(i) })
+        .take_while(|ptr| !ptr.is_null())
+        .map(|ptr| unsafe { c_str(ptr) })
+}
+
+/// 内核放在栈上的字符串都是 UTF-8
+unsafe fn c_str(ptr: *const u8) -> &'static str {
+    let mut len = 0;
+    while *ptr.add(len) != 0 {
+        len += 1;
+    }
+    core::str::from_utf8_unchecked(core::slice::from_raw_parts(ptr, len))
+}
+
+// 进入时 rsp 指向 argc, 16 字节对齐; 按调用约定把它作为参数传给 Rust 函数
+global_asm!(
+    ".section .text._start",
+    ".global _start",
+    "_start:",
+    "mov rdi, rsp",
+    "call {start}",
+    "ud2",
+    start = sym rt_start,
+);
+
+extern "Rust" {
+    fn main() -> i32;
+}
+
+unsafe extern "C" fn rt_start(sp: usize) -> ! {
+    INITIAL_SP.store(sp, Ordering::Relaxed);
+    exit(main());
+}
+
+#[panic_handler]
+fn panic(info: &core::panic::PanicInfo) -> ! {
+    eprintln!("{}", info);
+    exit(101);
+}
+
+/// 预编译的 `core` 以 panic=unwind 构建, 会引用这个符号; 程序以 panic=abort 编译, 不会真正调用它
+#[no_mangle]
+extern "C" fn rust_eh_personality() {}
+
+#[no_mangle]
+unsafe extern "C" fn memcpy(dest: *mut u8, src: *const u8, n: usize) -> *mut u8 {
+    asm!("rep movsb", inout("rdi") dest => _, inout("rsi") src => _, inout("rcx") n => _,
+        options(nostack, preserves_flags));
+    dest
+}
+
+#[no_mangle]
+unsafe extern "C" fn memmove(dest: *mut u8, src: *const u8, n: usize) -> *mut u8 {
+    if (dest as usize) <= (src as usize) || (dest as usize) >= (src as usize) + n {
+        return memcpy(dest, src, n);
+    }
+    // 目标在源之后且有重叠, 从后往前复制
+    asm!("std", "rep movsb", "cld",
+        inout("rdi") dest.add(n - 1) => _, inout("rsi") src.add(n - 1) => _,
+        inout("rcx") n => _, options(nostack));
+    dest
+}
+
+#[no_mangle]
+unsafe extern "C" fn memset(dest: *mut u8, value: i32, n: usize) -> *mut u8 {
+    asm!("rep stosb", inout("rdi") dest => _, inout("rcx") n => _, in("al") value as u8,
+        options(nostack, preserves_flags));
+    dest
+}
+
+#[no_mangle]
+unsafe extern "C" fn memcmp(a: *const u8, b: *const u8, n: usize) -> i32 {
+    let mut i = 0;
+    while i < n {
+        let (x, y) = (
+            core::ptr::read_volatile(a.add(i)),
+            core::ptr::read_volatile(b.add(i)),
+        );
+        if x != y {
+            return i32::from(x) - i32::from(y);
+        }
+        i += 1;
+    }
+    0
+}
+
+#[no_mangle]
+unsafe extern "C" fn bcmp(a: *const u8, b: *const u8, n: usize) -> i32 {
+    memcmp(a, b, n)
+}